@@ -0,0 +1,243 @@
+use serde::{Serialize, Deserialize};
+use std::{collections::BTreeMap, fmt, net::IpAddr, str::FromStr, sync::{Arc, Mutex}};
+use once_cell::sync::Lazy;
+
+use crate::process::{process_context, ProcessContext};
+use crate::utils::timestamp;
+
+/// The severity of an alert raised by one of the detection sources.
+///
+/// Severities are ordered, so `Severity::High > Severity::Medium` holds and a policy
+/// can simply compare against a threshold.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let name = match self {
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s.to_lowercase().as_str() {
+            "low" => Ok(Severity::Low),
+            "medium" => Ok(Severity::Medium),
+            "high" => Ok(Severity::High),
+            "critical" => Ok(Severity::Critical),
+            _ => Err(format!("unknown severity \"{s}\"")),
+        }
+    }
+}
+
+/// Represents a detection raised by one of the sources (canary files, ...).
+///
+/// An alert always names the machine it is about (`target`), which is the machine that
+/// will be isolated if the response policy decides so.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Alert {
+    /// The detection source that raised the alert (e.g. "canary").
+    pub source: String,
+    /// How bad the detection is.
+    pub severity: Severity,
+    /// The IP address of the machine the alert is about.
+    pub target: IpAddr,
+    /// A human readable description of what happened.
+    pub message: String,
+    /// The time the alert was raised, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// The forensic context of the process responsible for the detection, if it could be identified.
+    pub process: Option<ProcessContext>,
+    /// Source specific details about the detection (e.g. old and new hashes of a file).
+    #[serde(default)]
+    pub details: BTreeMap<String, String>,
+}
+
+impl Alert {
+    /// Creates a new alert, timestamped now, with no process attached.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The detection source raising the alert.
+    /// * `severity` - The severity of the alert.
+    /// * `target` - The IP address of the machine the alert is about.
+    /// * `message` - A human readable description of the detection.
+    pub fn new(source: &str, severity: Severity, target: IpAddr, message: String) -> Self
+    {
+        Alert {
+            source: source.to_string(),
+            severity,
+            target,
+            message,
+            timestamp: timestamp(),
+            process: None,
+            details: BTreeMap::new(),
+        }
+    }
+
+    /// Attaches the forensic context of the process responsible for the detection.
+    ///
+    /// The name and PID of the process are appended to the message. If the process is
+    /// already gone, the alert is returned unchanged.
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - The PID of the responsible process.
+    pub fn with_process(mut self, pid: u32) -> Self
+    {
+        if let Some(context) = process_context(pid) {
+            self.message = format!("{} by {} (PID {pid})", self.message, context.name);
+            self.process = Some(context);
+        }
+        self
+    }
+}
+
+/// The maximum number of alerts kept in `ALERTS`, the oldest ones being dropped first.
+const MAX_ALERTS: usize = 1000;
+
+/// The latest alerts raised, oldest first, up to `MAX_ALERTS`, as shown by the dashboard.
+pub static ALERTS: Lazy<Arc<Mutex<Vec<Alert>>>> = Lazy::new(|| {Arc::new(Mutex::new(Vec::new()))});
+
+/// Stores an alert in `ALERTS`, dropping the oldest one if the list is full.
+///
+/// # Arguments
+///
+/// * `alert` - The alert to store.
+pub fn record_alert(alert: Alert)
+{
+    let mut alerts = ALERTS.lock().unwrap();
+    if alerts.len() >= MAX_ALERTS {
+        alerts.remove(0);
+    }
+    alerts.push(alert);
+}
+
+/// Retrieves the alerts stored, oldest first.
+///
+/// # Arguments
+///
+/// * `target` - If set, only the alerts about this IP address are returned.
+///
+/// # Returns
+///
+/// * `Vec<Alert>` - The alerts stored.
+pub fn get_alerts(target: Option<IpAddr>) -> Vec<Alert>
+{
+    let alerts = ALERTS.lock().unwrap();
+    alerts
+        .iter()
+        .filter(|alert| target.is_none_or(|ip| alert.target == ip))
+        .cloned()
+        .collect()
+}
+
+/// A type alias for a callback function that is executed when an alert is raised.
+///
+/// Like the other callbacks of the application, it is wrapped in an `Arc` for shared
+/// ownership and a `Mutex` for safe concurrent access across threads.
+pub type AlertCallback = Arc<Mutex<Box<dyn Fn(Alert) + Send + 'static>>>;
+
+/// The action to take in response to an alert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    /// Isolate the target machine and broadcast it to the other machines.
+    Isolate,
+    /// Only log the alert.
+    Log,
+}
+
+/// Maps the severity of an alert to the response to run.
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    /// The lowest severity that leads to an isolation.
+    pub isolate_from: Severity,
+}
+
+impl Default for Policy {
+    fn default() -> Self
+    {
+        Policy { isolate_from: Severity::High }
+    }
+}
+
+impl Policy {
+    /// Returns the response to run for an alert of the given severity.
+    ///
+    /// # Arguments
+    ///
+    /// * `severity` - The severity of the alert.
+    pub fn response_for(&self, severity: Severity) -> Response
+    {
+        if severity >= self.isolate_from {
+            Response::Isolate
+        } else {
+            Response::Log
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_severity_ordering() {
+        assert!(Severity::Critical > Severity::High);
+        assert!(Severity::High > Severity::Medium);
+        assert!(Severity::Medium > Severity::Low);
+    }
+
+    #[test]
+    fn test_severity_from_str() {
+        assert_eq!("high".parse::<Severity>(), Ok(Severity::High));
+        assert_eq!("Critical".parse::<Severity>(), Ok(Severity::Critical));
+        assert!("urgent".parse::<Severity>().is_err());
+    }
+
+    #[test]
+    fn test_with_process_attaches_context() {
+        let alert = Alert::new("canary", Severity::High, "127.0.0.1".parse().unwrap(), "Canary read".to_string())
+            .with_process(std::process::id());
+
+        assert_eq!(alert.process.as_ref().map(|p| p.pid), Some(std::process::id()));
+        assert!(alert.message.ends_with(&format!("(PID {})", std::process::id())));
+    }
+
+    #[test]
+    fn test_record_and_get_alerts() {
+        let target: IpAddr = "10.1.2.3".parse().unwrap();
+        record_alert(Alert::new("fim", Severity::High, target, "File /etc/passwd modified".to_string()));
+        record_alert(Alert::new("fim", Severity::Low, "10.1.2.4".parse().unwrap(), "Other".to_string()));
+
+        let alerts = get_alerts(Some(target));
+        assert!(!alerts.is_empty());
+        assert!(alerts.iter().all(|alert| alert.target == target));
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = Policy::default();
+
+        assert_eq!(policy.response_for(Severity::Low), Response::Log);
+        assert_eq!(policy.response_for(Severity::Medium), Response::Log);
+        assert_eq!(policy.response_for(Severity::High), Response::Isolate);
+        assert_eq!(policy.response_for(Severity::Critical), Response::Isolate);
+    }
+}
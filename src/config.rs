@@ -0,0 +1,608 @@
+use serde::{Serialize, Deserialize};
+use std::{fs, io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, path::Path};
+
+use crate::alert::Severity;
+use crate::control::DEFAULT_CONTROL_SOCKET;
+use crate::events::DEFAULT_EVENT_LOG;
+use crate::flood::ListenerSettings;
+use crate::identity::DEFAULT_KEY_FILE;
+use crate::incident::DEFAULT_INCIDENT_WINDOW;
+use crate::inventory::is_mac;
+use crate::iptables::DEFAULT_MAX_LOCKED;
+use crate::network::MAX_HOPS;
+use crate::state::DEFAULT_CLOCK_FILE;
+use crate::transport::TransportKind;
+use crate::trust::DEFAULT_TRUST_STORE;
+
+/// The default path of the configuration file.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/wormsec/config.toml";
+
+/// The environment variable overriding the path of the configuration file.
+pub const CONFIG_PATH_VAR: &str = "WORMSEC_CONFIG";
+
+/// The prefix of the environment variables overriding configuration values.
+///
+/// `WORMSEC_<SECTION>_<KEY>` overrides the `key` of the `[section]` table, e.g.
+/// `WORMSEC_NETWORK_PORT=21336` or `WORMSEC_SOURCES_AUTH_LOG=/var/log/secure`.
+const OVERRIDE_PREFIX: &str = "WORMSEC_";
+
+/// The port used to exchange alerts with the peers and to serve the dashboard.
+pub const DEFAULT_PORT: u16 = 21335; // 21335 => b"WS"
+
+/// The configuration of the agent, read from a TOML file.
+///
+/// Every section and key is optional and falls back to the values the agent always used,
+/// so an empty file (or no file at all) keeps the previous behaviour.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub inventory: InventoryConfig,
+    pub sources: SourcesConfig,
+    pub response: ResponseConfig,
+    pub firewall: FirewallConfig,
+    pub events: EventsConfig,
+    pub control: ControlConfig,
+    pub ui: UiConfig,
+    pub discovery: DiscoveryConfig,
+    pub identity: IdentityConfig,
+}
+
+/// The `[network]` section: where alerts are exchanged with the peers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// The address the peer listener binds to. `::` listens on IPv4 and IPv6 both.
+    pub bind: IpAddr,
+    /// The port alerts are received on and sent to.
+    pub port: u16,
+    /// How the messages are exchanged with the peers (UDP or TCP).
+    pub transport: TransportKind,
+    /// How often the state (statuses of the machines, incidents) is sent to the peers, in
+    /// seconds. `0` disables the synchronization.
+    pub sync_interval: u64,
+    /// The file the Lamport clock stamping the statuses of the machines is saved to, so
+    /// that the changes made after a restart win over the older ones.
+    pub clock_file: String,
+    /// The number of times the isolation requests are relayed from peer to peer, to reach
+    /// the machines the agent cannot reach directly. `0` disables the relaying.
+    pub gossip_ttl: u8,
+    /// The number of messages per second accepted from each source, `0` for no limit.
+    pub rate_limit: u32,
+    /// The number of messages waiting to be processed, the next ones being dropped.
+    pub queue_size: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self
+    {
+        NetworkConfig {
+            bind: Ipv6Addr::UNSPECIFIED.into(),
+            port: DEFAULT_PORT,
+            transport: TransportKind::Udp,
+            sync_interval: 10,
+            clock_file: DEFAULT_CLOCK_FILE.to_string(),
+            gossip_ttl: 3,
+            rate_limit: ListenerSettings::default().rate_limit,
+            queue_size: ListenerSettings::default().queue_size,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// Returns the address the peer listener binds to.
+    pub fn bind_addr(&self) -> SocketAddr
+    {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    /// Returns the settings protecting the peer listener from floods.
+    pub fn listener_settings(&self) -> ListenerSettings
+    {
+        ListenerSettings { rate_limit: self.rate_limit, queue_size: self.queue_size }
+    }
+}
+
+/// The `[inventory]` section: the machines of the network.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InventoryConfig {
+    /// The inventory file, listing the machines one per line (see `inventory::parse_inventory`).
+    pub ips_file: String,
+    /// Additional peers, on top of the ones listed in `ips_file`.
+    pub peers: Vec<IpAddr>,
+    /// The MAC address shown for the machines whose MAC address is neither in the inventory
+    /// nor discovered yet. Empty by default: the MAC address is unknown.
+    pub mac: String,
+    /// How often the MAC addresses and hostnames of the machines are discovered, in seconds.
+    /// `0` disables the discovery.
+    pub discovery_interval: u64,
+}
+
+impl Default for InventoryConfig {
+    fn default() -> Self
+    {
+        InventoryConfig {
+            ips_file: "./ips.txt".to_string(),
+            peers: Vec::new(),
+            mac: String::new(),
+            discovery_interval: 30,
+        }
+    }
+}
+
+/// The `[sources]` section: the detection sources to run.
+///
+/// An empty path disables the corresponding source.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourcesConfig {
+    /// The log file whose accesses raise an alert about the local machine.
+    pub auth_log: String,
+    /// The file listing the canaries.
+    pub canaries: String,
+    /// The file listing the paths checked for integrity.
+    pub fim: String,
+    /// The file the integrity baseline is stored in.
+    pub fim_baseline: String,
+    /// The file listing the honeyports.
+    pub honeyports: String,
+    /// The auditd log followed.
+    pub audit_log: String,
+    /// The file holding the settings of the syslog receiver.
+    pub syslog: String,
+    /// Whether outbound connection fan-out spikes are detected.
+    pub fanout: bool,
+}
+
+impl Default for SourcesConfig {
+    fn default() -> Self
+    {
+        SourcesConfig {
+            auth_log: "/var/log/auth.log".to_string(),
+            canaries: "./canaries.txt".to_string(),
+            fim: "./fim.txt".to_string(),
+            fim_baseline: "./fim-baseline.json".to_string(),
+            honeyports: "./honeyports.txt".to_string(),
+            audit_log: "/var/log/audit/audit.log".to_string(),
+            syslog: "./syslog.txt".to_string(),
+            fanout: true,
+        }
+    }
+}
+
+/// The `[response]` section: how alerts are responded to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseConfig {
+    /// The lowest severity that leads to an isolation.
+    pub isolate_from: Severity,
+    /// The time, in seconds, after which a quiet incident is closed.
+    pub incident_window: u64,
+    /// The number of distinct nodes that must ask for the isolation of a machine, or for its
+    /// release, before it takes effect. The releases an operator issued need no quorum.
+    pub quorum: usize,
+    /// How long, in seconds, the votes of the nodes count.
+    pub quorum_window: u64,
+    /// The lowest severity a peer-requested isolation takes effect at on a single vote.
+    pub immediate_from: Severity,
+}
+
+impl Default for ResponseConfig {
+    fn default() -> Self
+    {
+        ResponseConfig {
+            isolate_from: Severity::High,
+            incident_window: DEFAULT_INCIDENT_WINDOW,
+            quorum: 1,
+            quorum_window: 300,
+            immediate_from: Severity::Critical,
+        }
+    }
+}
+
+/// The firewall used to lock isolated machines out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallBackend {
+    /// Rules are added with `iptables` and `ip6tables`.
+    #[default]
+    Iptables,
+    /// No rule is added, machines are only marked as isolated.
+    None,
+}
+
+/// The `[firewall]` section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FirewallConfig {
+    /// The firewall used to lock isolated machines out.
+    pub backend: FirewallBackend,
+    /// The maximum number of machines locked out at the same time, so that a flood of
+    /// isolations cannot exhaust the firewall with rules.
+    pub max_locked: usize,
+}
+
+impl Default for FirewallConfig {
+    fn default() -> Self
+    {
+        FirewallConfig { backend: FirewallBackend::default(), max_locked: DEFAULT_MAX_LOCKED }
+    }
+}
+
+/// The `[events]` section: the event log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// The file the events are appended to.
+    pub path: String,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self
+    {
+        EventsConfig { path: DEFAULT_EVENT_LOG.to_string() }
+    }
+}
+
+/// The `[control]` section: the local socket administrative actions go through.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// The path of the control socket. An empty path disables it.
+    pub socket: String,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self
+    {
+        ControlConfig { socket: DEFAULT_CONTROL_SOCKET.to_string() }
+    }
+}
+
+/// The `[discovery]` section: the automatic discovery of the other agents of the segment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Whether the agent announces itself and adds the agents it hears from as pending peers.
+    pub enabled: bool,
+    /// How often the agent announces itself, in seconds.
+    pub interval: u64,
+    /// The address the announcements are sent to, on the port of `[network]`.
+    pub address: IpAddr,
+    /// The pre-shared enrolment key. Peers whose announcements are signed with it are
+    /// approved right away, the others wait for an operator. The machines of the inventory
+    /// whose hellos are signed with it are trusted as well, even with the discovery disabled.
+    /// Empty by default.
+    pub enrolment_key: String,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self
+    {
+        DiscoveryConfig {
+            enabled: false,
+            interval: 30,
+            address: IpAddr::V4(Ipv4Addr::BROADCAST),
+            enrolment_key: String::new(),
+        }
+    }
+}
+
+/// The `[identity]` section: the key the agent signs its messages with, and the nodes it trusts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// The file holding the identity key of the agent, generated on first run.
+    pub key_file: String,
+    /// The file holding the node keys the agent trusts.
+    pub trust_store: String,
+    /// Whether the key of a machine of the inventory is trusted the first time it says hello,
+    /// rather than waiting for an operator to pin it. Disabled by default: the first machine
+    /// to speak for an address would be trusted.
+    pub trust_on_first_use: bool,
+}
+
+impl Default for IdentityConfig {
+    fn default() -> Self
+    {
+        IdentityConfig {
+            key_file: DEFAULT_KEY_FILE.to_string(),
+            trust_store: DEFAULT_TRUST_STORE.to_string(),
+            trust_on_first_use: false,
+        }
+    }
+}
+
+/// The `[ui]` section: the web server serving the API and the dashboard.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    /// The address the web server binds to.
+    pub bind: IpAddr,
+    /// The TCP port of the web server.
+    pub port: u16,
+    /// The directory holding the build of the dashboard.
+    pub path: String,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self
+    {
+        UiConfig {
+            bind: Ipv4Addr::UNSPECIFIED.into(),
+            port: DEFAULT_PORT,
+            path: "./ui/build".to_string(),
+        }
+    }
+}
+
+impl UiConfig {
+    /// Returns the address the web server binds to.
+    pub fn bind_addr(&self) -> SocketAddr
+    {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
+
+impl Config {
+    /// Checks the values that parse correctly but make no sense.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if the configuration is valid.
+    /// * `Err(String)` naming the first invalid key and why it is invalid.
+    pub fn validate(&self) -> Result<(), String>
+    {
+        if self.network.port == 0 {
+            return Err("network.port: must not be 0".to_string());
+        }
+        if usize::from(self.network.gossip_ttl) > MAX_HOPS {
+            return Err(format!("network.gossip_ttl: must be at most {MAX_HOPS}"));
+        }
+        if self.network.queue_size == 0 {
+            return Err("network.queue_size: must be greater than 0".to_string());
+        }
+        if self.network.clock_file.is_empty() {
+            return Err("network.clock_file: must not be empty".to_string());
+        }
+        if self.firewall.max_locked == 0 {
+            return Err("firewall.max_locked: must be greater than 0".to_string());
+        }
+        if self.ui.port == 0 {
+            return Err("ui.port: must not be 0".to_string());
+        }
+        if self.inventory.ips_file.is_empty() {
+            return Err("inventory.ips_file: must not be empty".to_string());
+        }
+        if !self.inventory.mac.is_empty() && !is_mac(&self.inventory.mac) {
+            return Err(format!("inventory.mac: \"{}\" is not a MAC address", self.inventory.mac));
+        }
+        if self.discovery.enabled && self.discovery.interval == 0 {
+            return Err("discovery.interval: must be greater than 0".to_string());
+        }
+        if self.identity.key_file.is_empty() {
+            return Err("identity.key_file: must not be empty".to_string());
+        }
+        if self.identity.trust_store.is_empty() {
+            return Err("identity.trust_store: must not be empty".to_string());
+        }
+        if self.response.incident_window == 0 {
+            return Err("response.incident_window: must be greater than 0".to_string());
+        }
+        if self.response.quorum == 0 {
+            return Err("response.quorum: must be greater than 0".to_string());
+        }
+        if self.response.quorum_window == 0 {
+            return Err("response.quorum_window: must be greater than 0".to_string());
+        }
+        if self.events.path.is_empty() {
+            return Err("events.path: must not be empty".to_string());
+        }
+        if self.ui.path.is_empty() {
+            return Err("ui.path: must not be empty".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the files the agent needs to run exist.
+    ///
+    /// Only the agent (`run`) and `check-config` need them, the client commands talking to
+    /// a running agent work without.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if the files exist.
+    /// * `Err(String)` naming the key of the first missing file.
+    pub fn check_files(&self) -> Result<(), String>
+    {
+        if !Path::new(&self.inventory.ips_file).is_file() {
+            return Err(format!("inventory.ips_file: \"{}\" does not exist", self.inventory.ips_file));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses the value of an environment override as a TOML value.
+///
+/// Values that are not valid TOML (e.g. unquoted paths) are taken as strings.
+fn parse_override(value: &str) -> toml::Value
+{
+    toml::from_str::<toml::Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// Applies the environment overrides to a parsed configuration file.
+///
+/// # Arguments
+///
+/// * `table` - The parsed configuration file.
+/// * `vars` - The environment variables, as `(name, value)` pairs. Only the ones starting
+///   with `WORMSEC_` are considered, and the ones naming no configuration key are ignored
+///   with a warning.
+///
+/// # Returns
+///
+/// * `Ok(())` if the overrides were applied.
+/// * `Err(String)` naming the variable overriding a section that is not a table.
+pub fn apply_overrides(table: &mut toml::Table, vars: impl Iterator<Item = (String, String)>) -> Result<(), String>
+{
+    let known = toml::Table::try_from(Config::default()).expect("the default configuration serializes");
+
+    for (name, value) in vars {
+        let Some(path) = name.strip_prefix(OVERRIDE_PREFIX) else {
+            continue;
+        };
+        if name == CONFIG_PATH_VAR {
+            continue;
+        }
+
+        let path = path.to_lowercase();
+        let is_known = |(section, key): &(&str, &str)| {
+            known.get(*section).and_then(toml::Value::as_table).is_some_and(|keys| keys.contains_key(*key))
+        };
+        let Some((section, key)) = path.split_once('_').filter(is_known) else {
+            eprintln!("Ignored {name}: does not name a configuration key (expected {OVERRIDE_PREFIX}<SECTION>_<KEY>)");
+            continue;
+        };
+
+        let section = table
+            .entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        let Some(section) = section.as_table_mut() else {
+            return Err(format!("{name}: [{}] is not a table", path.split_once('_').unwrap().0));
+        };
+        section.insert(key.to_string(), parse_override(&value));
+    }
+
+    Ok(())
+}
+
+/// Parses and validates a configuration, applying the environment overrides.
+///
+/// # Arguments
+///
+/// * `content` - The content of the configuration file.
+/// * `vars` - The environment variables, as `(name, value)` pairs.
+///
+/// # Returns
+///
+/// * `Ok(Config)` - The configuration.
+/// * `Err(String)` - A message pointing at the invalid line or key.
+pub fn parse_config(content: &str, vars: impl Iterator<Item = (String, String)>) -> Result<Config, String>
+{
+    // Parsing the file alone first reports errors with their line and column.
+    toml::from_str::<Config>(content).map_err(|e| e.to_string().trim_end().to_string())?;
+
+    let mut table: toml::Table = toml::from_str(content).map_err(|e| e.to_string())?;
+    apply_overrides(&mut table, vars)?;
+
+    let config: Config = toml::Value::Table(table)
+        .try_into()
+        .map_err(|e: toml::de::Error| format!("environment override: {}", e.to_string().trim_end()))?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+/// Loads the configuration of the agent.
+///
+/// The file is read from `$WORMSEC_CONFIG`, or `/etc/wormsec/config.toml` by default. A
+/// missing default file is not an error, the default configuration being used instead.
+///
+/// # Returns
+///
+/// * `Ok((Config, String))` - The configuration and the path it was read from.
+/// * `Err(String)` - A message prefixed with the path of the file and pointing at the
+///   invalid line or key.
+pub fn load_config() -> Result<(Config, String), String>
+{
+    let path = std::env::var(CONFIG_PATH_VAR).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && path == DEFAULT_CONFIG_PATH => String::new(),
+        Err(e) => return Err(format!("{path}: {e}")),
+    };
+
+    let config = parse_config(&content, std::env::vars()).map_err(|e| format!("{path}: {e}"))?;
+    Ok((config, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn ips_file() -> String {
+        let path = std::env::temp_dir().join("test_config_ips.txt");
+        fs::write(&path, "127.0.0.1\n").unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let content = format!(
+            "[network]\nport = 4000\n\n[inventory]\nips_file = \"{}\"\npeers = [\"10.0.0.9\"]\n\n\
+             [response]\nisolate_from = \"critical\"\n\n[firewall]\nbackend = \"none\"\n",
+            ips_file(),
+        );
+
+        let config = parse_config(&content, vars(&[])).unwrap();
+        assert_eq!(config.network.port, 4000);
+        assert_eq!(config.inventory.peers, vec!["10.0.0.9".parse::<IpAddr>().unwrap()]);
+        assert_eq!(config.response.isolate_from, Severity::Critical);
+        assert_eq!(config.firewall.backend, FirewallBackend::None);
+        assert_eq!(config.ui, UiConfig::default());
+    }
+
+    #[test]
+    fn test_parse_config_errors() {
+        let err = parse_config("[network]\nport = \"x\"\n", vars(&[])).unwrap_err();
+        assert!(err.contains("line 2"), "{err}");
+
+        let err = parse_config("[network]\nprot = 1\n", vars(&[])).unwrap_err();
+        assert!(err.contains("unknown field `prot`"), "{err}");
+
+        let content = format!("[inventory]\nips_file = \"{}\"\nmac = \"nope\"\n", ips_file());
+        let err = parse_config(&content, vars(&[])).unwrap_err();
+        assert_eq!(err, "inventory.mac: \"nope\" is not a MAC address");
+
+        let config = parse_config("[inventory]\nips_file = \"/nonexistent/ips.txt\"\n", vars(&[])).unwrap();
+        let err = config.check_files().unwrap_err();
+        assert_eq!(err, "inventory.ips_file: \"/nonexistent/ips.txt\" does not exist");
+    }
+
+    #[test]
+    fn test_environment_overrides() {
+        let content = format!("[inventory]\nips_file = \"{}\"\n", ips_file());
+        let config = parse_config(&content, vars(&[
+            ("WORMSEC_NETWORK_PORT", "4001"),
+            ("WORMSEC_SOURCES_AUTH_LOG", "/var/log/secure"),
+            ("WORMSEC_SOURCES_FANOUT", "false"),
+            ("WORMSEC_CONFIG", "/tmp/ignored.toml"),
+            ("HOME", "/root"),
+        ])).unwrap();
+
+        assert_eq!(config.network.port, 4001);
+        assert_eq!(config.sources.auth_log, "/var/log/secure");
+        assert!(!config.sources.fanout);
+
+        let err = parse_config(&content, vars(&[("WORMSEC_NETWORK_PORT", "high")])).unwrap_err();
+        assert!(err.starts_with("environment override:"), "{err}");
+
+        let config = parse_config(&content, vars(&[("WORMSEC_NOPE", "1"), ("WORMSEC_NETWORK_PROT", "1")])).unwrap();
+        assert_eq!(config.network.port, DEFAULT_PORT);
+    }
+}
@@ -0,0 +1,399 @@
+use serde::{Serialize, Deserialize};
+use std::{fs, io::{self, BufRead, BufReader, Write}, mem, net::IpAddr, os::unix::{fs::{FileTypeExt, PermissionsExt}, io::AsRawFd, net::{UnixListener, UnixStream}}, path::Path, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use crate::alert::{Alert, AlertCallback, Severity};
+use crate::events::{record_event, Event, EventKind};
+use crate::response::Responder;
+use crate::state::{self, Release};
+use crate::trust;
+use crate::web_server::{agent_status, Status};
+
+/// The default path of the control socket.
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/wormsec/control.sock";
+
+/// How long a release waits for the peers to confirm it before answering.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A type alias for a callback function that reloads the inventory.
+///
+/// Like the other callbacks of the application, it is wrapped in an `Arc` for shared
+/// ownership and a `Mutex` for safe concurrent access across threads. It takes who or
+/// what triggered the reload, for the event log, and returns a description of what was
+/// reloaded, or why the reload failed.
+pub type ReloadCallback = Arc<Mutex<Box<dyn Fn(&str) -> Result<String, String> + Send + 'static>>>;
+
+/// A request sent to the control socket, as a JSON line.
+///
+/// For example `{"command":"isolate","ip":"10.0.0.2"}` or `{"command":"status"}`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Returns a summary of the state of the agent.
+    Status,
+    /// Isolates a machine and broadcasts it.
+    Isolate { ip: IpAddr },
+    /// Releases an isolated machine.
+    Release { ip: IpAddr },
+    /// Reloads the inventory.
+    Reload,
+    /// Raises a test alert about the local machine.
+    TestAlert { severity: Severity },
+    /// Approves a pending peer, which is then trusted to send isolation orders.
+    Approve { ip: IpAddr },
+    /// Rejects a pending peer.
+    Reject { ip: IpAddr },
+    /// Pins the node key of a machine in the trust store.
+    Trust { ip: IpAddr, key: String },
+    /// Revokes a node, by its key or its IP address: its messages are ignored from then on.
+    Revoke { node: String },
+}
+
+/// The response to a control request, as a JSON line.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ControlResponse {
+    /// Whether the request succeeded.
+    pub ok: bool,
+    /// A human readable description of what was done, or of the error.
+    pub message: String,
+    /// The state of the agent, for `status` requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+}
+
+impl ControlResponse {
+    /// Creates a successful response.
+    fn ok(message: String) -> Self
+    {
+        ControlResponse { ok: true, message, status: None }
+    }
+
+    /// Creates an error response.
+    fn error(message: String) -> Self
+    {
+        ControlResponse { ok: false, message, status: None }
+    }
+}
+
+/// What the control socket needs to run the requests.
+#[derive(Clone)]
+pub struct ControlContext {
+    /// Runs the isolations and releases.
+    pub responder: Responder,
+    /// The callback test alerts are sent to, like the alerts of the detection sources.
+    pub alert_callback: AlertCallback,
+    /// The callback reloading the inventory.
+    pub reload: ReloadCallback,
+    /// The time the agent started, in seconds since the UNIX epoch.
+    pub started: u64,
+}
+
+/// Returns the credentials (`SO_PEERCRED`) of the process on the other end of a Unix socket.
+///
+/// # Returns
+///
+/// * `Ok(libc::ucred)` - The PID, UID and GID of the peer.
+/// * `Err(io::Error)` - An error if the credentials could not be read.
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<libc::ucred>
+{
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    // SAFETY: `cred` and `len` are valid for writes and `len` holds the size of `cred`.
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret == 0 {
+        Ok(cred)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Checks whether a user may administer the agent.
+///
+/// Only root, and the user the agent runs as, are allowed.
+fn is_authorized(uid: u32) -> bool
+{
+    // SAFETY: `geteuid` has no preconditions and cannot fail.
+    uid == 0 || uid == unsafe { libc::geteuid() }
+}
+
+/// Waits for the peers to confirm a release, at most `RELEASE_TIMEOUT`.
+///
+/// # Returns
+///
+/// * `Release` - The release, with the confirmations received.
+fn wait_for_confirmations(release: Release) -> Release
+{
+    let deadline = Instant::now() + RELEASE_TIMEOUT;
+    let mut release = release;
+
+    while !release.is_confirmed() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+        release = state::get_release(&release.id).unwrap_or(release);
+    }
+    release
+}
+
+/// Describes a release and the peers that confirmed it.
+fn release_message(release: &Release) -> String
+{
+    let missing: Vec<String> = release
+        .sent_to
+        .iter()
+        .filter(|ip| !release.confirmed_by.contains(ip))
+        .map(|ip| ip.to_string())
+        .collect();
+
+    let mut message = format!("{} released, confirmed by {} of {} peers", release.ip, release.confirmed_by.len(), release.sent_to.len());
+    if !missing.is_empty() {
+        message.push_str(&format!(" (not confirmed by {})", missing.join(", ")));
+    }
+    message
+}
+
+/// Runs a control request.
+///
+/// # Arguments
+///
+/// * `request` - The request to run.
+/// * `context` - What the requests need to run.
+/// * `actor` - Who sent the request, for the event log.
+///
+/// # Returns
+///
+/// * `ControlResponse` - The response to send back.
+pub fn handle_request(request: ControlRequest, context: &ControlContext, actor: &str) -> ControlResponse
+{
+    match request {
+        ControlRequest::Status => ControlResponse {
+            status: Some(agent_status(context.responder.my_ip, context.started)),
+            ..ControlResponse::ok("Running".to_string())
+        },
+        ControlRequest::Isolate { ip } => {
+            record_event(Event::new(EventKind::Operator, Some(ip), actor, format!("Asked for the isolation of {ip}")));
+            if !context.responder.isolate(ip, true, None) {
+                return ControlResponse::error(format!("{ip} could not be locked out (see the event log), the isolation was broadcast"));
+            }
+            ControlResponse::ok(format!("{ip} isolated"))
+        },
+        ControlRequest::Release { ip } => {
+            let Some(release) = context.responder.release(ip, actor, true) else {
+                return ControlResponse::ok(format!("{ip} released locally, the release could not be broadcast"));
+            };
+            ControlResponse::ok(release_message(&wait_for_confirmations(release)))
+        },
+        ControlRequest::Reload => {
+            let reload = context.reload.lock().unwrap();
+            match reload(actor) {
+                Ok(message) => ControlResponse::ok(message),
+                Err(message) => ControlResponse::error(message),
+            }
+        },
+        ControlRequest::TestAlert { severity } => {
+            let alert = Alert::new("test", severity, context.responder.my_ip, format!("Test alert sent by {actor}"));
+            let cb = context.alert_callback.lock().unwrap();
+            cb(alert);
+            ControlResponse::ok(format!("Test alert ({severity}) sent"))
+        },
+        ControlRequest::Approve { ip } => {
+            let Some(peer) = state::find_machine(&ip.to_string()).filter(|m| m.pending) else {
+                return ControlResponse::error(format!("{ip} is not a pending peer"));
+            };
+            if let Some(key) = &peer.key {
+                if let Err(e) = trust::trust_node(key, ip, peer.hostname.as_deref().unwrap_or_default(), actor) {
+                    return ControlResponse::error(format!("Cannot approve {ip}: {e}"));
+                }
+            }
+            state::approve_peer(&ip.to_string());
+            record_event(Event::new(EventKind::Discovery, Some(ip), actor, format!("Approved peer {ip}")));
+            ControlResponse::ok(format!("{ip} approved"))
+        },
+        ControlRequest::Reject { ip } => {
+            if !state::reject_peer(&ip.to_string()) {
+                return ControlResponse::error(format!("{ip} is not a pending peer"));
+            }
+            record_event(Event::new(EventKind::Discovery, Some(ip), actor, format!("Rejected peer {ip}")));
+            ControlResponse::ok(format!("{ip} rejected"))
+        },
+        ControlRequest::Trust { ip, key } => {
+            let hostname = state::find_machine(&ip.to_string()).and_then(|m| m.hostname).unwrap_or_default();
+            match trust::trust_node(&key, ip, &hostname, actor) {
+                Ok(true) => ControlResponse::ok(format!("Node {} trusted for {ip}", trust::short_key(&key))),
+                Ok(false) => ControlResponse::ok(format!("Node {} is already trusted", trust::short_key(&key))),
+                Err(e) => ControlResponse::error(format!("Cannot trust the node: {e}")),
+            }
+        },
+        ControlRequest::Revoke { node } => {
+            let revoked = trust::revoke_node(&node, actor);
+            if revoked.is_empty() {
+                return ControlResponse::error(format!("No trusted node matches {node}"));
+            }
+            let nodes: Vec<String> = revoked.iter().map(|n| format!("{} ({})", trust::short_key(&n.key), n.ip)).collect();
+            ControlResponse::ok(format!("Revoked node {}", nodes.join(", ")))
+        },
+    }
+}
+
+/// Serves the requests of one client of the control socket, one JSON line each.
+fn serve_client(stream: UnixStream, context: ControlContext) -> io::Result<()>
+{
+    let mut writer = stream.try_clone()?;
+    let cred = peer_credentials(&stream)?;
+
+    if !is_authorized(cred.uid) {
+        println!("Rejected control connection from UID {} (PID {})", cred.uid, cred.pid);
+        let response = ControlResponse::error("Permission denied: the control socket is reserved to root".to_string());
+        return writeln!(writer, "{}", serde_json::to_string(&response)?);
+    }
+
+    let actor = format!("operator (UID {}, PID {})", cred.uid, cred.pid);
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handle_request(request, &context, &actor),
+            Err(e) => ControlResponse::error(format!("Invalid request: {e}")),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+
+    Ok(())
+}
+
+/// Starts the control socket, through which the administrative actions are run.
+///
+/// The socket is only accessible to root (mode `0600`), and the credentials of each client
+/// are checked with `SO_PEERCRED`, so that administrative actions never need the network
+/// exposed HTTP port. A stale socket left by a previous run is replaced.
+///
+/// The socket is served in a separate thread, each client in its own thread.
+///
+/// # Arguments
+///
+/// * `path` - The path of the socket.
+/// * `context` - What the requests need to run.
+///
+/// # Returns
+///
+/// * `Ok(())` if the socket is listening.
+/// * `Err(io::Error)` if the socket could not be created.
+pub fn start_control_socket(path: &str, context: ControlContext) -> io::Result<()>
+{
+    let path = Path::new(path);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+    println!("Control socket listening at {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let context = context.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve_client(stream, context) {
+                            println!("Control client error: {e}");
+                        }
+                    });
+                },
+                Err(e) => println!("Control socket error: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Sends a request to the control socket of the running agent.
+///
+/// # Arguments
+///
+/// * `path` - The path of the socket.
+/// * `request` - The request to send.
+///
+/// # Returns
+///
+/// * `Ok(ControlResponse)` - The response of the agent, which may be an error response.
+/// * `Err(String)` - A message if the agent could not be reached.
+pub fn send_request(path: &str, request: &ControlRequest) -> Result<ControlResponse, String>
+{
+    let mut stream = UnixStream::connect(path).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => format!("Cannot open the control socket {path}: run as root"),
+        _ => format!("Cannot reach the agent at {path} (is it running?): {e}"),
+    })?;
+    stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+
+    let request = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    writeln!(stream, "{request}").map_err(|e| e.to_string())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|e| e.to_string())?;
+    serde_json::from_str(&line).map_err(|e| format!("Unexpected response from the agent: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FirewallBackend;
+
+    fn context() -> ControlContext {
+        ControlContext {
+            responder: Responder { my_ip: "10.40.0.1".parse().unwrap(), firewall: FirewallBackend::None, port: 21335, gossip_ttl: 3, max_locked: 256 },
+            alert_callback: Arc::new(Mutex::new(Box::new(|_| {}))),
+            reload: Arc::new(Mutex::new(Box::new(|_| Err("nothing to reload".to_string())))),
+            started: 1000,
+        }
+    }
+
+    #[test]
+    fn test_request_format() {
+        let request: ControlRequest = serde_json::from_str(r#"{"command":"isolate","ip":"10.0.0.2"}"#).unwrap();
+        assert_eq!(request, ControlRequest::Isolate { ip: "10.0.0.2".parse().unwrap() });
+
+        let request: ControlRequest = serde_json::from_str(r#"{"command":"test_alert","severity":"high"}"#).unwrap();
+        assert_eq!(request, ControlRequest::TestAlert { severity: Severity::High });
+
+        let request: ControlRequest = serde_json::from_str(r#"{"command":"revoke","node":"10.0.0.3"}"#).unwrap();
+        assert_eq!(request, ControlRequest::Revoke { node: "10.0.0.3".to_string() });
+
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"command":"shutdown"}"#).is_err());
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let path = std::env::temp_dir().join("test_wormsec_control.sock");
+        let path = path.to_str().unwrap();
+        start_control_socket(path, context()).unwrap();
+
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let response = send_request(path, &ControlRequest::Status).unwrap();
+        assert!(response.ok);
+        assert_eq!(response.status.unwrap().ip, "10.40.0.1".parse::<IpAddr>().unwrap());
+
+        let response = send_request(path, &ControlRequest::Reload).unwrap();
+        assert!(!response.ok);
+        assert_eq!(response.message, "nothing to reload");
+    }
+}
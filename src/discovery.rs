@@ -0,0 +1,420 @@
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use std::{net::{IpAddr, SocketAddr, UdpSocket}, sync::{Arc, Mutex}, thread, time::Duration};
+
+use crate::events::{record_event, Event, EventKind};
+use crate::identity::from_hex;
+use crate::network::{MessageCallback, Payload, PeerMessage};
+use crate::state;
+use crate::trust;
+use crate::utils::{hostname, local_addresses};
+
+/// The announcement an agent sends on the segment to be discovered by the other agents.
+///
+/// It is sent as the payload of a `PeerMessage`, so it is signed by the node key of the agent.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    /// The IP address of the agent.
+    pub ip: IpAddr,
+    /// The hostname of the agent.
+    pub hostname: String,
+    /// The port the agent listens on for the messages of its peers.
+    pub port: u16,
+    /// The HMAC-SHA256 of the announcement and of the node key with the enrolment key, in
+    /// hexadecimal, if the agent has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// What was done with an announcement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discovery {
+    /// The announcement of the local agent, heard back.
+    Own,
+    /// The announcement of a machine already known.
+    Known,
+    /// A peer pending approval, newly added.
+    Pending,
+    /// A peer approved with the enrolment key, newly added or previously pending.
+    Approved,
+}
+
+impl Announcement {
+    /// Creates an announcement of the local agent, signed with the enrolment key if there is one.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address of the agent.
+    /// * `port` - The port the agent listens on.
+    /// * `node` - The node key of the agent.
+    /// * `key` - The enrolment key, empty if there is none.
+    pub fn new(ip: IpAddr, port: u16, node: &str, key: &str) -> Self
+    {
+        let mut announcement = Announcement { ip, hostname: hostname(), port, signature: None };
+        if !key.is_empty() {
+            announcement.signature = Some(announcement.sign(node, key));
+        }
+        announcement
+    }
+
+    /// Returns the HMAC of the announcement sent by `node`, keyed with `key`.
+    fn mac(&self, node: &str, key: &str) -> Hmac<Sha256>
+    {
+        enrolment_mac(&format!("{}|{}|{}|{node}", self.ip, self.hostname, self.port), key)
+    }
+
+    /// Signs the announcement sent by `node` with the enrolment key.
+    ///
+    /// The node key is part of the signature, so that the proof of enrolment cannot be
+    /// replayed by another node.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The signature, in hexadecimal.
+    pub fn sign(&self, node: &str, key: &str) -> String
+    {
+        format!("{:x}", self.mac(node, key).finalize().into_bytes())
+    }
+
+    /// Checks the signature of the announcement sent by `node` against the enrolment key, in
+    /// constant time.
+    ///
+    /// # Returns
+    ///
+    /// * `true` if the announcement is signed with `key`.
+    pub fn verify(&self, node: &str, key: &str) -> bool
+    {
+        let Some(signature) = self.signature.as_deref().and_then(from_hex::<32>) else {
+            return false;
+        };
+        self.mac(node, key).verify_slice(&signature).is_ok()
+    }
+}
+
+/// Returns the HMAC of `data`, keyed with the enrolment key.
+fn enrolment_mac(data: &str, key: &str) -> Hmac<Sha256>
+{
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac
+}
+
+/// Signs the hello sent by `node` to the machine with the address `to` with the enrolment key.
+///
+/// The node key and the address of the receiver are part of the signature, so that the proof
+/// of enrolment can neither be replayed by another node nor to another machine.
+///
+/// # Returns
+///
+/// * `String` - The signature, in hexadecimal.
+pub fn sign_hello(node: &str, to: IpAddr, key: &str) -> String
+{
+    format!("{:x}", enrolment_mac(&format!("hello|{to}|{node}"), key).finalize().into_bytes())
+}
+
+/// Handles the enrolment proof of a hello, which works without the discovery.
+///
+/// If the local agent has an enrolment key, a hello signed with it by a machine of the
+/// inventory pins the node key of its sender in the trust store, so that the agents listed in
+/// each other's inventory join the cluster with a shared key only. Hellos with a wrong
+/// signature are rejected, and the nodes revoked from an address are never trusted again.
+///
+/// # Arguments
+///
+/// * `signature` - The signature of the hello, if any.
+/// * `src` - The address the hello was received from.
+/// * `node` - The node key the hello was signed with.
+/// * `local` - The IP addresses of the local machine, one of which the hello was sent to.
+/// * `key` - The enrolment key, empty if there is none.
+///
+/// # Returns
+///
+/// * `Ok(bool)` - `true` if the node was trusted with the enrolment key, `false` if the hello
+///   carries no proof or the node was trusted already.
+/// * `Err(String)` - Why the proof was rejected.
+pub fn handle_hello(signature: Option<&str>, src: SocketAddr, node: &str, local: &[IpAddr], key: &str) -> Result<bool, String>
+{
+    let Some(signature) = signature.filter(|_| !key.is_empty()).and_then(from_hex::<32>) else {
+        return Ok(false);
+    };
+    let signed_to = |to: &IpAddr| enrolment_mac(&format!("hello|{to}|{node}"), key).verify_slice(&signature).is_ok();
+    if !local.iter().any(signed_to) {
+        return Err("has an invalid signature".to_string());
+    }
+
+    let ip = src.ip();
+    if !state::is_trusted(&ip.to_string()) {
+        return Err(format!("comes from {ip}, which is not in the inventory"));
+    }
+    if trust::is_revoked_ip(ip) {
+        return Err(format!("comes from {ip}, whose node was revoked"));
+    }
+    let hostname = state::find_machine(&ip.to_string()).and_then(|m| m.hostname).unwrap_or_default();
+    trust::trust_node(node, ip, &hostname, "enrolment key")
+}
+
+/// Handles an announcement received from the segment.
+///
+/// The announcement must come from the address it announces. If the local agent has an
+/// enrolment key, an announcement signed with it approves the peer and pins its node key in
+/// the trust store, while an announcement with a wrong signature is rejected. Unsigned
+/// announcements, or any announcement if there is no enrolment key, add the peer as pending:
+/// it waits for an operator to approve it before being trusted. The nodes revoked from an
+/// address are never approved again with the enrolment key.
+///
+/// # Arguments
+///
+/// * `announcement` - The announcement received.
+/// * `src` - The address the announcement was received from.
+/// * `node` - The node key the announcement was signed with.
+/// * `my_ip` - The IP address of the local machine.
+/// * `key` - The enrolment key, empty if there is none.
+///
+/// # Returns
+///
+/// * `Ok(Discovery)` - What was done with the announcement.
+/// * `Err(String)` - Why the announcement was rejected.
+pub fn handle_announcement(announcement: &Announcement, src: SocketAddr, node: &str, my_ip: IpAddr, key: &str) -> Result<Discovery, String>
+{
+    if announcement.ip != src.ip() {
+        return Err(format!("announces {} but was sent from {}", announcement.ip, src.ip()));
+    }
+    if announcement.ip == my_ip {
+        return Ok(Discovery::Own);
+    }
+
+    let approved = !key.is_empty() && announcement.signature.is_some();
+    if approved {
+        if !announcement.verify(node, key) {
+            return Err("has an invalid signature".to_string());
+        }
+        if trust::is_revoked_ip(announcement.ip) {
+            return Err(format!("comes from {}, whose node was revoked", announcement.ip));
+        }
+        trust::trust_node(node, announcement.ip, &announcement.hostname, "enrolment key")?;
+    }
+
+    let ip = announcement.ip.to_string();
+    if state::add_discovered(&ip, &announcement.hostname, node, approved) {
+        return Ok(if approved { Discovery::Approved } else { Discovery::Pending });
+    }
+
+    if !announcement.hostname.is_empty() {
+        state::update_discovered(&ip, None, Some(&announcement.hostname));
+    }
+    if approved && state::approve_peer(&ip).is_some() {
+        return Ok(Discovery::Approved);
+    }
+    Ok(Discovery::Known)
+}
+
+/// Creates the callback handling the announcements received by the network watcher.
+///
+/// New peers are recorded in the event log, and rejected announcements are reported.
+///
+/// # Arguments
+///
+/// * `my_ip` - The IP address of the local machine.
+/// * `key` - The enrolment key, empty if there is none.
+pub fn discovery_callback(my_ip: IpAddr, key: String) -> MessageCallback
+{
+    Arc::new(Mutex::new(Box::new(move |payload: &Payload, src: SocketAddr, node: &str| {
+        let Payload::Announce(announcement) = payload else {
+            return;
+        };
+
+        let node_short = trust::short_key(node);
+        match handle_announcement(announcement, src, node, my_ip, &key) {
+            Ok(Discovery::Pending) => {
+                let message = format!("Discovered {} ({}, node {node_short}), pending approval", announcement.ip, announcement.hostname);
+                println!("{message}");
+                record_event(Event::new(EventKind::Discovery, Some(announcement.ip), &src.to_string(), message).with_detail("key", node));
+            },
+            Ok(Discovery::Approved) => {
+                let message = format!("Discovered {} ({}, node {node_short}), approved with the enrolment key", announcement.ip, announcement.hostname);
+                println!("{message}");
+                record_event(Event::new(EventKind::Discovery, Some(announcement.ip), &src.to_string(), message).with_detail("key", node));
+            },
+            Ok(Discovery::Own | Discovery::Known) => {},
+            Err(e) => println!("Rejected the announcement from {src}: it {e}"),
+        }
+    })))
+}
+
+/// Returns the local IP address announced to `address`: `my_ip` if it is of the same family,
+/// otherwise the first local address of that family, link-local ones last as the peers could
+/// not reach them without knowing the interface.
+fn announced_address(address: IpAddr, my_ip: IpAddr, local: &[IpAddr]) -> Option<IpAddr>
+{
+    let link_local = |ip: &IpAddr| matches!(ip, IpAddr::V6(ip) if ip.is_unicast_link_local());
+    if my_ip.is_ipv4() == address.is_ipv4() {
+        return Some(my_ip);
+    }
+    let mut candidates: Vec<IpAddr> = local.iter().copied().filter(|ip| ip.is_ipv4() == address.is_ipv4()).collect();
+    candidates.sort_by_key(link_local);
+    candidates.first().copied()
+}
+
+/// Starts announcing the local agent on the segment.
+///
+/// The announcements are sent in a separate thread, every `interval`, to `address` on the
+/// port the peers listen on. They are sent from the local IP address of the same family as
+/// `address` (IPv4 broadcast or IPv6 multicast), which is the address announced.
+///
+/// # Arguments
+///
+/// * `address` - The (broadcast) address the announcements are sent to.
+/// * `port` - The port the agents listen on.
+/// * `interval` - The time between two announcements.
+/// * `my_ip` - The IP address of the local machine.
+/// * `key` - The enrolment key, empty if there is none.
+pub fn start_announcer(address: IpAddr, port: u16, interval: Duration, my_ip: IpAddr, key: String)
+{
+    thread::spawn(move || {
+        let Some(source) = announced_address(address, my_ip, &local_addresses()) else {
+            println!("Discovery announcements disabled: no local address to announce to {address}");
+            return;
+        };
+        let socket = match UdpSocket::bind((source, 0)).and_then(|socket| socket.set_broadcast(source.is_ipv4()).map(|_| socket)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                println!("Discovery announcements disabled: {e}");
+                return;
+            },
+        };
+
+        loop {
+            let node = crate::identity::identity().node_key();
+            let announcement = Announcement::new(source, port, &node, &key);
+            if let Ok(message) = serde_json::to_vec(&PeerMessage::new(Payload::Announce(announcement))) {
+                if let Err(e) = socket.send_to(&message, (address, port)) {
+                    println!("Failed to send the discovery announcement: {e}");
+                }
+            }
+            thread::sleep(interval);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+
+    const MY_IP: &str = "10.50.0.1";
+
+    struct Node {
+        ip: IpAddr,
+        key: String,
+    }
+
+    fn node(ip: &str) -> Node {
+        Node { ip: ip.parse().unwrap(), key: Identity::generate().unwrap().node_key() }
+    }
+
+    fn announcement(node: &Node, key: &str) -> Announcement {
+        let mut announcement = Announcement::new(node.ip, 21335, &node.key, key);
+        announcement.hostname = format!("node-{}", node.ip);
+        if !key.is_empty() {
+            announcement.signature = Some(announcement.sign(&node.key, key));
+        }
+        announcement
+    }
+
+    fn handle(node: &Node, enrolment: &str, key: &str) -> Result<Discovery, String> {
+        let src = SocketAddr::new(node.ip, 40000);
+        handle_announcement(&announcement(node, enrolment), src, &node.key, MY_IP.parse().unwrap(), key)
+    }
+
+    #[test]
+    fn test_signature() {
+        let node = node("10.50.0.2");
+        let signed = announcement(&node, "secret");
+        assert!(signed.verify(&node.key, "secret"));
+        assert!(!signed.verify(&node.key, "other"));
+        assert!(!signed.verify(&Identity::generate().unwrap().node_key(), "secret"));
+
+        let mut tampered = signed.clone();
+        tampered.port = 21336;
+        assert!(!tampered.verify(&node.key, "secret"));
+    }
+
+    #[test]
+    fn test_announced_address() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let local = [ip("10.50.0.1"), ip("fe80::1"), ip("2001:db8::1")];
+
+        assert_eq!(announced_address(ip("10.50.255.255"), ip("10.50.0.1"), &local), Some(ip("10.50.0.1")));
+        assert_eq!(announced_address(ip("ff02::1"), ip("10.50.0.1"), &local), Some(ip("2001:db8::1")));
+        assert_eq!(announced_address(ip("ff02::1"), ip("10.50.0.1"), &local[..1]), None);
+    }
+
+    #[test]
+    fn test_handle_announcement() {
+        let _guard = state::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        assert_eq!(handle(&node(MY_IP), "", ""), Ok(Discovery::Own));
+
+        // Without an enrolment key, new peers are pending.
+        let pending = node("10.50.0.3");
+        assert_eq!(handle(&pending, "", ""), Ok(Discovery::Pending));
+        assert_eq!(handle(&pending, "", ""), Ok(Discovery::Known));
+        assert!(!state::is_trusted("10.50.0.3"));
+        assert!(!trust::is_trusted_key(&pending.key));
+
+        // A pending peer announcing itself with the enrolment key is approved and pinned.
+        assert_eq!(handle(&pending, "secret", "secret"), Ok(Discovery::Approved));
+        assert!(state::is_trusted("10.50.0.3"));
+        assert!(trust::is_trusted_key(&pending.key));
+
+        assert_eq!(handle(&node("10.50.0.4"), "secret", "secret"), Ok(Discovery::Approved));
+        assert!(handle(&node("10.50.0.5"), "guess", "secret").is_err());
+        assert_eq!(handle(&node("10.50.0.6"), "", "secret"), Ok(Discovery::Pending));
+    }
+
+    #[test]
+    fn test_handle_hello() {
+        let _guard = state::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let local = [MY_IP.parse().unwrap()];
+        let hello = |node: &Node, signature: Option<&str>, key: &str| {
+            handle_hello(signature, SocketAddr::new(node.ip, 40000), &node.key, &local, key)
+        };
+
+        let peer = node("10.50.1.1");
+        assert!(state::add_discovered("10.50.1.1", "", "", true));
+        let signed = sign_hello(&peer.key, local[0], "secret");
+
+        // No proof, or no enrolment key to check it against, trusts nobody.
+        assert_eq!(hello(&peer, None, "secret"), Ok(false));
+        assert_eq!(hello(&peer, Some(&signed), ""), Ok(false));
+
+        // A proof for another key, another node or another receiver is rejected.
+        assert!(hello(&peer, Some(&sign_hello(&peer.key, local[0], "guess")), "secret").is_err());
+        assert!(hello(&node("10.50.1.1"), Some(&signed), "secret").is_err());
+        assert!(hello(&peer, Some(&sign_hello(&peer.key, "10.50.0.99".parse().unwrap(), "secret")), "secret").is_err());
+        assert!(!trust::is_trusted_key(&peer.key));
+
+        assert_eq!(hello(&peer, Some(&signed), "secret"), Ok(true));
+        assert!(trust::is_trusted_key(&peer.key));
+        assert_eq!(hello(&peer, Some(&signed), "secret"), Ok(false));
+
+        // Only the machines of the inventory are enrolled.
+        let stranger = node("10.50.1.2");
+        assert!(hello(&stranger, Some(&sign_hello(&stranger.key, local[0], "secret")), "secret").is_err());
+    }
+
+    #[test]
+    fn test_handle_announcement_rejects_spoofed_and_revoked() {
+        let _guard = state::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let spoofed = node("10.50.0.7");
+        let src: SocketAddr = "10.50.0.8:40000".parse().unwrap();
+        assert!(handle_announcement(&announcement(&spoofed, ""), src, &spoofed.key, MY_IP.parse().unwrap(), "").is_err());
+
+        // A revoked node cannot come back with a new key and the enrolment key.
+        assert_eq!(handle(&node("10.50.0.9"), "secret", "secret"), Ok(Discovery::Approved));
+        trust::revoke_node("10.50.0.9", "test");
+        assert!(handle(&node("10.50.0.9"), "secret", "secret").is_err());
+    }
+}
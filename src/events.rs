@@ -0,0 +1,397 @@
+use serde::{Serialize, Deserialize};
+use std::{collections::BTreeMap, fs::{File, OpenOptions}, io::{self, BufRead, Write}, net::IpAddr, path::Path, sync::{Arc, Mutex}};
+use once_cell::sync::Lazy;
+
+use crate::utils::timestamp;
+
+/// The default path of the event log, relative to the working directory.
+pub const DEFAULT_EVENT_LOG: &str = "./events.log";
+
+/// The maximum number of events kept in memory in `EVENTS`, the oldest ones being dropped first.
+///
+/// Older events are still in the event log file, which the queries reaching them read.
+const MAX_EVENTS: usize = 10000;
+
+/// The default number of events returned by a query.
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// The maximum number of events returned by a query.
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// The kind of an event of the event log.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// An alert raised by one of the local detection sources.
+    Detection,
+    /// An alert received from a peer.
+    PeerAlert,
+    /// A firewall rule added or removed.
+    Firewall,
+    /// A machine isolated in response to an incident.
+    Isolation,
+    /// An action run by an operator.
+    Operator,
+    /// A machine released from isolation.
+    Release,
+    /// The inventory reloaded.
+    Inventory,
+    /// A peer discovered, approved or rejected.
+    Discovery,
+    /// A node key trusted or revoked.
+    Trust,
+}
+
+/// Represents an entry of the event log.
+///
+/// The event log is the append-only history of everything that happened on the machine:
+/// why, when and by whom a machine was isolated or released.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The identifier of the event, increasing with time.
+    pub id: u64,
+    /// The time of the event, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// The kind of the event.
+    pub kind: EventKind,
+    /// The IP address of the machine the event is about, if any.
+    pub machine: Option<IpAddr>,
+    /// Who or what triggered the event (a detection source, the address of a peer, an operator, ...).
+    pub actor: String,
+    /// A human readable description of the event.
+    pub message: String,
+    /// Kind specific details about the event (e.g. the severity of a detection).
+    #[serde(default)]
+    pub details: BTreeMap<String, String>,
+}
+
+impl Event {
+    /// Creates a new event, timestamped now.
+    ///
+    /// The identifier is assigned when the event is recorded with `record_event`.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The kind of the event.
+    /// * `machine` - The IP address of the machine the event is about, if any.
+    /// * `actor` - Who or what triggered the event.
+    /// * `message` - A human readable description of the event.
+    pub fn new(kind: EventKind, machine: Option<IpAddr>, actor: &str, message: String) -> Self
+    {
+        Event {
+            id: 0,
+            timestamp: timestamp(),
+            kind,
+            machine,
+            actor: actor.to_string(),
+            message,
+            details: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a detail to the event.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The name of the detail.
+    /// * `value` - The value of the detail.
+    pub fn with_detail(mut self, key: &str, value: impl ToString) -> Self
+    {
+        self.details.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+/// The latest events of the log, oldest first, kept in memory to answer the queries.
+pub static EVENTS: Lazy<Arc<Mutex<Vec<Event>>>> = Lazy::new(|| {Arc::new(Mutex::new(Vec::new()))});
+
+/// The event log file the events are appended to, once opened with `open_event_log`.
+static EVENT_LOG: Lazy<Mutex<Option<File>>> = Lazy::new(|| Mutex::new(None));
+
+/// The path of the event log file, read by the queries reaching older events than `EVENTS` holds.
+static EVENT_LOG_PATH: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// Reads the events stored in an event log file.
+///
+/// The file holds one JSON event per line. Lines that cannot be parsed are skipped.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the event log file.
+///
+/// # Returns
+///
+/// * `Ok(Vec<Event>)` - The events of the file, oldest first.
+/// * `Err(io::Error)` - An error if the file cannot be opened or read.
+pub fn read_events_from_file(filename: &str) -> io::Result<Vec<Event>>
+{
+    let file = File::open(Path::new(filename))?;
+    let reader = io::BufReader::new(file);
+    let mut events = Vec::new();
+
+    for line in reader.lines() {
+        if let Ok(event) = serde_json::from_str(&line?) {
+            events.push(event);
+        }
+    }
+
+    Ok(events)
+}
+
+/// Opens the event log file, loading the events it already holds.
+///
+/// The file is created if it doesn't exist. Every event recorded afterwards is appended to it.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the event log file.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of events loaded from the file.
+/// * `Err(io::Error)` - An error if the file cannot be opened or read.
+pub fn open_event_log(filename: &str) -> io::Result<usize>
+{
+    let file = OpenOptions::new().create(true).append(true).open(filename)?;
+    let loaded = read_events_from_file(filename)?;
+    let count = loaded.len();
+
+    let mut events = EVENTS.lock().unwrap();
+    let skip = loaded.len().saturating_sub(MAX_EVENTS);
+    events.splice(0..0, loaded.into_iter().skip(skip));
+    events.truncate(MAX_EVENTS);
+
+    *EVENT_LOG.lock().unwrap() = Some(file);
+    *EVENT_LOG_PATH.lock().unwrap() = Some(filename.to_string());
+
+    Ok(count)
+}
+
+/// Records an event, appending it to the event log file if one is open.
+///
+/// # Arguments
+///
+/// * `event` - The event to record. Its identifier is overwritten.
+///
+/// # Returns
+///
+/// * `Event` - The event recorded, with its identifier.
+pub fn record_event(mut event: Event) -> Event
+{
+    let mut events = EVENTS.lock().unwrap();
+    event.id = events.last().map(|e| e.id + 1).unwrap_or(1);
+
+    if let Some(file) = EVENT_LOG.lock().unwrap().as_mut() {
+        if let Ok(line) = serde_json::to_string(&event) {
+            if let Err(e) = writeln!(file, "{line}") {
+                println!("Failed to write the event log: {e}");
+            }
+        }
+    }
+
+    if events.len() >= MAX_EVENTS {
+        events.remove(0);
+    }
+    events.push(event.clone());
+
+    event
+}
+
+/// The filters and pagination of an event query.
+///
+/// All the filters are optional, and the results are paginated with `offset` and `limit`.
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct EventFilter {
+    /// If set, only the events about this IP address are returned.
+    pub machine: Option<IpAddr>,
+    /// If set, only the events of this kind are returned.
+    #[serde(rename = "type")]
+    pub kind: Option<EventKind>,
+    /// If set, only the events at or after this time are returned.
+    pub since: Option<u64>,
+    /// If set, only the events at or before this time are returned.
+    pub until: Option<u64>,
+    /// The number of matching events to skip.
+    #[serde(default)]
+    pub offset: usize,
+    /// The maximum number of events to return (100 by default, at most 1000).
+    pub limit: Option<usize>,
+}
+
+impl EventFilter {
+    /// Checks whether an event matches the filters.
+    fn matches(&self, event: &Event) -> bool
+    {
+        self.machine.is_none_or(|ip| event.machine == Some(ip))
+            && self.kind.is_none_or(|kind| event.kind == kind)
+            && self.since.is_none_or(|since| event.timestamp >= since)
+            && self.until.is_none_or(|until| event.timestamp <= until)
+    }
+}
+
+/// A page of events matching a query.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EventPage {
+    /// The total number of events matching the filters.
+    pub total: usize,
+    /// The number of matching events skipped.
+    pub offset: usize,
+    /// The events of the page, oldest first.
+    pub events: Vec<Event>,
+    /// Whether older events, no longer in memory, could not be read from the event log file,
+    /// so that the page and the total may miss some.
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+/// Filters and paginates a list of events.
+///
+/// # Arguments
+///
+/// * `events` - The events to filter, oldest first.
+/// * `filter` - The filters and pagination to apply.
+///
+/// # Returns
+///
+/// * `EventPage` - The page of matching events.
+pub fn filter_events<'a>(events: impl IntoIterator<Item = &'a Event>, filter: &EventFilter) -> EventPage
+{
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let mut page = EventPage { total: 0, offset: filter.offset, events: Vec::new(), truncated: false };
+
+    for event in events.into_iter().filter(|event| filter.matches(event)) {
+        if page.total >= filter.offset && page.events.len() < limit {
+            page.events.push(event.clone());
+        }
+        page.total += 1;
+    }
+    page
+}
+
+/// Reads the events of an event log file older than `before`, the identifier of the oldest
+/// event in memory.
+fn read_older_events(filename: &str, before: u64) -> io::Result<Vec<Event>>
+{
+    let mut events = read_events_from_file(filename)?;
+    events.retain(|event| event.id < before);
+    Ok(events)
+}
+
+/// Queries the events recorded.
+///
+/// The events in memory answer most queries. The ones reaching older events, which were
+/// dropped from memory, read them from the event log file; if it can't be read, the page is
+/// marked as truncated.
+///
+/// # Arguments
+///
+/// * `filter` - The filters and pagination to apply.
+///
+/// # Returns
+///
+/// * `EventPage` - The page of matching events.
+pub fn query_events(filter: &EventFilter) -> EventPage
+{
+    let events = EVENTS.lock().unwrap();
+    // The events dropped from memory are all at or before the oldest one kept.
+    let oldest = match events.first() {
+        Some(oldest) if oldest.id > 1 && filter.since.is_none_or(|since| since <= oldest.timestamp) => oldest.id,
+        _ => return filter_events(events.iter(), filter),
+    };
+    let events = events.clone();
+    let path = EVENT_LOG_PATH.lock().unwrap().clone();
+
+    match path.map(|path| read_older_events(&path, oldest)) {
+        Some(Ok(older)) => filter_events(older.iter().chain(&events), filter),
+        _ => EventPage { truncated: true, ..filter_events(&events, filter) },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: u64, kind: EventKind, machine: &str, timestamp: u64) -> Event {
+        let mut event = Event::new(kind, Some(machine.parse().unwrap()), "test", "Test event".to_string());
+        event.id = id;
+        event.timestamp = timestamp;
+        event
+    }
+
+    fn events() -> Vec<Event> {
+        vec![
+            event(1, EventKind::Detection, "10.30.0.1", 1000),
+            event(2, EventKind::Firewall, "10.30.0.1", 1001),
+            event(3, EventKind::PeerAlert, "10.30.0.2", 1002),
+            event(4, EventKind::Detection, "10.30.0.2", 1003),
+            event(5, EventKind::Detection, "10.30.0.1", 1004),
+        ]
+    }
+
+    #[test]
+    fn test_filter_events_by_machine_and_type() {
+        let filter = EventFilter {
+            machine: Some("10.30.0.1".parse().unwrap()),
+            kind: Some(EventKind::Detection),
+            ..Default::default()
+        };
+
+        let page = filter_events(&events(), &filter);
+        assert_eq!(page.total, 2);
+        assert_eq!(page.events.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1, 5]);
+    }
+
+    #[test]
+    fn test_filter_events_by_time_range() {
+        let filter = EventFilter { since: Some(1001), until: Some(1003), ..Default::default() };
+
+        let page = filter_events(&events(), &filter);
+        assert_eq!(page.events.iter().map(|e| e.id).collect::<Vec<_>>(), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_filter_events_pagination() {
+        let filter = EventFilter { offset: 1, limit: Some(2), ..Default::default() };
+
+        let page = filter_events(&events(), &filter);
+        assert_eq!(page.total, 5);
+        assert_eq!(page.offset, 1);
+        assert_eq!(page.events.iter().map(|e| e.id).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_read_events_from_file() {
+        let path = std::env::temp_dir().join("test_events.log");
+        let mut file = File::create(&path).unwrap();
+        for event in events().iter().take(2) {
+            writeln!(file, "{}", serde_json::to_string(event).unwrap()).unwrap();
+        }
+        writeln!(file, "not an event").unwrap();
+
+        let read = read_events_from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(read, events()[..2]);
+    }
+
+    #[test]
+    fn test_read_older_events() {
+        let path = std::env::temp_dir().join("test_older_events.log");
+        let mut file = File::create(&path).unwrap();
+        for event in events() {
+            writeln!(file, "{}", serde_json::to_string(&event).unwrap()).unwrap();
+        }
+
+        let older = read_older_events(path.to_str().unwrap(), 4).unwrap();
+        assert_eq!(older.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        let recent = events().split_off(3);
+        let page = filter_events(older.iter().chain(&recent), &EventFilter::default());
+        assert_eq!(page.total, 5);
+        assert!(!page.truncated);
+    }
+
+    #[test]
+    fn test_event_kind_serialization() {
+        let json = serde_json::to_string(&event(1, EventKind::PeerAlert, "10.30.0.3", 1000)).unwrap();
+        assert!(json.contains("\"kind\":\"peer_alert\""));
+    }
+}
@@ -0,0 +1,371 @@
+use std::{collections::{HashMap, HashSet}, fs, net::{IpAddr, SocketAddr}, thread, time::Duration};
+
+use crate::alert::{Alert, AlertCallback, Severity};
+use crate::procnet::{parse_proc_net_tcp, ConnState, Connection};
+use crate::process::find_process_by_socket;
+use crate::utils::local_addresses;
+
+/// Parses the content of `/proc/net/nf_conntrack`, keeping the flows originated locally.
+///
+/// # Arguments
+///
+/// * `content` - The content of the file.
+/// * `local_ips` - The IP addresses of the local machine.
+///
+/// # Returns
+///
+/// * `Vec<Connection>` - The flows whose originator is one of `local_ips`.
+pub fn parse_conntrack(content: &str, local_ips: &[IpAddr]) -> Vec<Connection>
+{
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let value = |key: &str| {
+                fields.iter().find_map(|field| field.strip_prefix(key)?.strip_prefix('='))
+            };
+            let state = fields
+                .iter()
+                .take_while(|field| !field.contains('='))
+                .last()
+                .map(|state| match *state {
+                    "SYN_SENT" => ConnState::SynSent,
+                    "ESTABLISHED" => ConnState::Established,
+                    _ => ConnState::Other,
+                })?;
+
+            let src: IpAddr = value("src")?.parse().ok()?;
+            let dst: IpAddr = value("dst")?.parse().ok()?;
+
+            if !local_ips.contains(&src) {
+                return None;
+            }
+
+            Some(Connection {
+                local: SocketAddr::new(src, value("sport")?.parse().ok()?),
+                remote: SocketAddr::new(dst, value("dport")?.parse().ok()?),
+                state,
+                inode: None,
+            })
+        })
+        .collect()
+}
+
+/// Keeps the connections opened by the local machine towards other hosts.
+///
+/// A connection is outbound when it is still trying to connect (`SYN_SENT`) or when its local
+/// port is not one the machine listens on. Loopback and unspecified peers are ignored.
+pub fn outbound(connections: &[Connection]) -> Vec<&Connection>
+{
+    let listening: HashSet<u16> = connections
+        .iter()
+        .filter(|c| c.state == ConnState::Listen)
+        .map(|c| c.local.port())
+        .collect();
+
+    connections
+        .iter()
+        .filter(|c| c.state != ConnState::Listen)
+        .filter(|c| !c.remote.ip().is_loopback() && !c.remote.ip().is_unspecified())
+        .filter(|c| c.state == ConnState::SynSent || !listening.contains(&c.local.port()))
+        .collect()
+}
+
+/// An exponentially weighted moving average and variance of a metric.
+#[derive(Debug, Clone, Copy, Default)]
+struct Ewma {
+    mean: f64,
+    var: f64,
+}
+
+impl Ewma {
+    fn update(&mut self, value: f64, alpha: f64)
+    {
+        let diff = value - self.mean;
+        let incr = alpha * diff;
+
+        self.mean += incr;
+        self.var = (1.0 - alpha) * (self.var + diff * incr);
+    }
+
+    fn threshold(&self, k: f64, min: f64) -> f64
+    {
+        (self.mean + k * self.var.sqrt()).max(min)
+    }
+}
+
+/// The settings of the fan-out detection.
+#[derive(Debug, Clone, Copy)]
+pub struct FanoutSettings {
+    /// The time between two samples.
+    pub interval: Duration,
+    /// The weight of the latest sample in the learned baseline.
+    pub alpha: f64,
+    /// How many standard deviations above the mean a sample must be to be anomalous.
+    pub k: f64,
+    /// The number of samples to learn from before raising any alert.
+    pub warmup: u32,
+    /// The number of samples a destination is remembered for.
+    pub memory: u32,
+    /// The number of new destinations under which no alert is raised, whatever the baseline.
+    pub min_new_destinations: f64,
+    /// The number of pending connections under which no alert is raised, whatever the baseline.
+    pub min_syn_sent: f64,
+}
+
+impl Default for FanoutSettings {
+    fn default() -> Self
+    {
+        FanoutSettings {
+            interval: Duration::from_secs(5),
+            alpha: 0.1,
+            k: 4.0,
+            warmup: 12,
+            memory: 60,
+            min_new_destinations: 20.0,
+            min_syn_sent: 20.0,
+        }
+    }
+}
+
+/// A sample that stands out of the learned baseline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    /// The number of destinations not contacted recently.
+    pub new_destinations: usize,
+    /// The threshold for new destinations at the time of the sample.
+    pub new_destinations_threshold: f64,
+    /// The number of connections waiting for an answer (`SYN_SENT`).
+    pub syn_sent: usize,
+    /// The threshold for pending connections at the time of the sample.
+    pub syn_sent_threshold: f64,
+    /// The inode of one of the pending connections, to find the process behind them.
+    pub inode: Option<u64>,
+}
+
+/// Learns the normal outbound fan-out of the machine and spots the samples standing out.
+///
+/// Two metrics are tracked: the number of destinations that were not contacted recently,
+/// and the number of connections waiting for an answer. A worm scanning its neighbours
+/// makes both spike.
+#[derive(Debug)]
+pub struct FanoutDetector {
+    settings: FanoutSettings,
+    samples: u32,
+    new_destinations: Ewma,
+    syn_sent: Ewma,
+    seen: HashMap<IpAddr, u32>,
+}
+
+impl FanoutDetector {
+    /// Creates a detector with no baseline yet.
+    pub fn new(settings: FanoutSettings) -> Self
+    {
+        FanoutDetector {
+            settings,
+            samples: 0,
+            new_destinations: Ewma::default(),
+            syn_sent: Ewma::default(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Feeds a sample to the detector.
+    ///
+    /// Anomalous samples are not learned from, so that a long scan keeps being reported
+    /// instead of becoming the new normal.
+    ///
+    /// # Arguments
+    ///
+    /// * `connections` - The connections of the machine at the time of the sample.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Anomaly)` if the sample stands out of the baseline.
+    /// * `None` otherwise, or while the baseline is still being learned.
+    pub fn observe(&mut self, connections: &[Connection]) -> Option<Anomaly>
+    {
+        let outbound = outbound(connections);
+        let destinations: HashSet<IpAddr> = outbound.iter().map(|c| c.remote.ip()).collect();
+        let new_destinations = destinations.iter().filter(|ip| !self.seen.contains_key(ip)).count();
+        let pending: Vec<&&Connection> = outbound.iter().filter(|c| c.state == ConnState::SynSent).collect();
+        let syn_sent = pending.iter().map(|c| (c.local, c.remote)).collect::<HashSet<_>>().len();
+
+        self.samples += 1;
+        let now = self.samples;
+        let memory = self.settings.memory;
+        self.seen.retain(|_, last| now - *last < memory);
+        for ip in destinations {
+            self.seen.insert(ip, now);
+        }
+
+        let s = self.settings;
+        let new_destinations_threshold = self.new_destinations.threshold(s.k, s.min_new_destinations);
+        let syn_sent_threshold = self.syn_sent.threshold(s.k, s.min_syn_sent);
+        let anomalous = new_destinations as f64 > new_destinations_threshold
+            || syn_sent as f64 > syn_sent_threshold;
+
+        // The very first sample reports every established connection as new, never alert on it.
+        if now > s.warmup.max(1) && anomalous {
+            return Some(Anomaly {
+                new_destinations,
+                new_destinations_threshold,
+                syn_sent,
+                syn_sent_threshold,
+                inode: pending.iter().find_map(|c| c.inode),
+            });
+        }
+
+        if now > 1 {
+            self.new_destinations.update(new_destinations as f64, s.alpha);
+            self.syn_sent.update(syn_sent as f64, s.alpha);
+        }
+
+        None
+    }
+}
+
+/// Builds the alert raised for an anomalous fan-out of the local machine.
+///
+/// The alert is of high severity when both metrics spike, which is what scanning looks
+/// like, and of medium severity otherwise.
+///
+/// # Arguments
+///
+/// * `anomaly` - The anomaly detected.
+/// * `my_ip` - The IP address of the local machine, the target of the alert.
+pub fn anomaly_alert(anomaly: &Anomaly, my_ip: IpAddr) -> Alert
+{
+    let both = anomaly.new_destinations as f64 > anomaly.new_destinations_threshold
+        && anomaly.syn_sent as f64 > anomaly.syn_sent_threshold;
+
+    let mut alert = Alert::new(
+        "fanout",
+        if both { Severity::High } else { Severity::Medium },
+        my_ip,
+        format!(
+            "Outbound connection spike: {} new destinations, {} pending connections",
+            anomaly.new_destinations, anomaly.syn_sent,
+        ),
+    );
+
+    alert.details.insert("new_destinations".to_string(), anomaly.new_destinations.to_string());
+    alert.details.insert("new_destinations_threshold".to_string(), format!("{:.1}", anomaly.new_destinations_threshold));
+    alert.details.insert("syn_sent".to_string(), anomaly.syn_sent.to_string());
+    alert.details.insert("syn_sent_threshold".to_string(), format!("{:.1}", anomaly.syn_sent_threshold));
+
+    alert
+}
+
+/// Reads the current connections of the machine.
+///
+/// `/proc/net/tcp` and `/proc/net/tcp6` are always read, the conntrack table only when it
+/// is available.
+fn sample(local_ips: &[IpAddr]) -> Vec<Connection>
+{
+    let mut connections = Vec::new();
+
+    for file in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        if let Ok(content) = fs::read_to_string(file) {
+            connections.extend(parse_proc_net_tcp(&content));
+        }
+    }
+    if let Ok(content) = fs::read_to_string("/proc/net/nf_conntrack") {
+        connections.extend(parse_conntrack(&content, local_ips));
+    }
+
+    connections
+}
+
+/// Starts sampling the connections of the local machine and reports fan-out anomalies.
+///
+/// The detector runs in a separate thread, sampling every `settings.interval`. Each anomaly
+/// raises an alert about the local machine, with the process owning one of the pending
+/// connections when it could be identified.
+///
+/// # Arguments
+///
+/// * `settings` - The settings of the detection.
+/// * `my_ip` - The IP address of the local machine.
+/// * `callback` - The callback to run with the alerts raised.
+pub fn start_fanout_detector(settings: FanoutSettings, my_ip: IpAddr, callback: AlertCallback)
+{
+    thread::spawn(move || {
+        let mut detector = FanoutDetector::new(settings);
+
+        loop {
+            // The addresses of the machine may change, e.g. with IPv6 privacy addresses.
+            let mut local_ips = local_addresses();
+            local_ips.push(my_ip);
+            if let Some(anomaly) = detector.observe(&sample(&local_ips)) {
+                let mut alert = anomaly_alert(&anomaly, my_ip);
+                if let Some(process) = anomaly.inode.and_then(find_process_by_socket) {
+                    alert = alert.with_process(process.pid);
+                }
+
+                let cb = callback.lock().unwrap();
+                cb(alert);
+            }
+            thread::sleep(settings.interval);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TCP_IDLE_1: &str = include_str!("../testdata/fanout/tcp_idle_1.txt");
+    const TCP_IDLE_2: &str = include_str!("../testdata/fanout/tcp_idle_2.txt");
+    const TCP_SCAN: &str = include_str!("../testdata/fanout/tcp_scan.txt");
+    const NF_CONNTRACK: &str = include_str!("../testdata/fanout/nf_conntrack.txt");
+
+    #[test]
+    fn test_parse_conntrack() {
+        let local_ips = vec!["172.42.0.3".parse().unwrap()];
+        let connections = parse_conntrack(NF_CONNTRACK, &local_ips);
+
+        assert_eq!(connections.len(), 3);
+        assert_eq!(connections[1].state, ConnState::SynSent);
+        assert_eq!(connections[1].remote, "172.42.0.77:445".parse().unwrap());
+        assert_eq!(connections[2].state, ConnState::Other);
+    }
+
+    #[test]
+    fn test_outbound_skips_inbound_connections() {
+        let connections = parse_proc_net_tcp(TCP_IDLE_1);
+        let outbound = outbound(&connections);
+
+        // The SSH session from 172.42.0.1 is inbound (local port 22 is listening).
+        assert_eq!(outbound.len(), 1);
+        assert_eq!(outbound[0].remote.ip(), "172.42.0.4".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_detector_learns_then_reports_scan() {
+        let mut detector = FanoutDetector::new(FanoutSettings::default());
+        let idle_1 = parse_proc_net_tcp(TCP_IDLE_1);
+        let idle_2 = parse_proc_net_tcp(TCP_IDLE_2);
+
+        for i in 0..30 {
+            let snapshot = if i % 2 == 0 { &idle_1 } else { &idle_2 };
+            assert!(detector.observe(snapshot).is_none());
+        }
+
+        let anomaly = detector.observe(&parse_proc_net_tcp(TCP_SCAN)).unwrap();
+        assert_eq!(anomaly.new_destinations, 40);
+        assert_eq!(anomaly.syn_sent, 40);
+        assert!(anomaly.inode.is_some());
+
+        let alert = anomaly_alert(&anomaly, "172.42.0.3".parse().unwrap());
+        assert_eq!(alert.severity, Severity::High);
+    }
+
+    #[test]
+    fn test_detector_silent_during_warmup() {
+        let mut detector = FanoutDetector::new(FanoutSettings::default());
+
+        assert!(detector.observe(&parse_proc_net_tcp(TCP_IDLE_1)).is_none());
+        assert!(detector.observe(&parse_proc_net_tcp(TCP_SCAN)).is_none());
+    }
+}
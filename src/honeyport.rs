@@ -0,0 +1,267 @@
+use std::{fmt, fs::File, io::{self, BufRead}, net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket}, path::Path, str::FromStr, thread, time::Duration};
+
+use crate::alert::{Alert, AlertCallback, Severity};
+use crate::transport::{bind_tcp, bind_udp, canonical};
+use crate::utils::local_addresses;
+
+/// The transport protocol of a honeyport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// A decoy port that no real service uses.
+///
+/// Legitimate hosts have no reason to connect to a honeyport, so any connection attempt
+/// is considered to come from a host scanning its neighbours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Honeyport {
+    /// The port to listen on.
+    pub port: u16,
+    /// The protocol to listen with.
+    pub protocol: Protocol,
+}
+
+impl fmt::Display for Honeyport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{}/{}", self.port, self.protocol)
+    }
+}
+
+impl FromStr for Honeyport {
+    type Err = String;
+
+    /// Parses a honeyport written as `<port>/<protocol>` (e.g. `445/tcp`), the protocol
+    /// defaulting to TCP when omitted.
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let (port, protocol) = s.split_once('/').unwrap_or((s, "tcp"));
+
+        let port = port.parse().map_err(|_| format!("invalid port \"{port}\""))?;
+        let protocol = match protocol.to_lowercase().as_str() {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => return Err(format!("invalid protocol \"{protocol}\"")),
+        };
+
+        Ok(Honeyport { port, protocol })
+    }
+}
+
+/// Reads the honeyports from a file and returns them as a vector.
+///
+/// Each line holds a honeyport written as `<port>/<protocol>` (e.g. `3389/tcp`). Empty lines
+/// and lines starting with `#` are ignored.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the file listing the honeyports.
+///
+/// # Returns
+///
+/// * `Ok(Vec<Honeyport>)` - A vector containing the honeyports listed in the file.
+/// * `Err(io::Error)` - An error if the file cannot be read or contains an invalid honeyport.
+pub fn read_honeyports_from_file(filename: &str) -> io::Result<Vec<Honeyport>>
+{
+    let file = File::open(Path::new(filename))?;
+    let reader = io::BufReader::new(file);
+    let mut honeyports = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        honeyports.push(line.parse().map_err(io::Error::other)?);
+    }
+
+    Ok(honeyports)
+}
+
+/// Builds the alert raised for a connection attempt on a honeyport.
+///
+/// The target of the alert is the host that connected, as it is the one scanning. A TCP
+/// connection completed the handshake, so its source is genuine and the alert is high. The
+/// source of a UDP datagram can be forged by anyone, so that the alert is only medium: it is
+/// recorded and correlated, but doesn't isolate the host on its own.
+///
+/// # Arguments
+///
+/// * `honeyport` - The honeyport that was hit.
+/// * `src` - The address the connection attempt came from.
+pub fn hit_alert(honeyport: Honeyport, src: SocketAddr) -> Alert
+{
+    let severity = match honeyport.protocol {
+        Protocol::Tcp => Severity::High,
+        Protocol::Udp => Severity::Medium,
+    };
+    let mut alert = Alert::new(
+        "honeyport",
+        severity,
+        src.ip(),
+        format!("Connection attempt on decoy port {honeyport} from {src}"),
+    );
+
+    alert.details.insert("port".to_string(), honeyport.port.to_string());
+    alert.details.insert("protocol".to_string(), honeyport.protocol.to_string());
+    alert.details.insert("source_port".to_string(), src.port().to_string());
+
+    alert
+}
+
+/// Reports a hit on a honeyport, unless it comes from the local machine, from any of its
+/// addresses.
+fn report(honeyport: Honeyport, src: SocketAddr, my_ip: IpAddr, callback: &AlertCallback)
+{
+    let src = canonical(src);
+    if src.ip().is_loopback() || src.ip() == my_ip || local_addresses().contains(&src.ip()) {
+        return;
+    }
+
+    println!("Honeyport {honeyport} hit by {src}");
+
+    let cb = callback.lock().unwrap();
+    cb(hit_alert(honeyport, src));
+}
+
+/// Accepts connections on a TCP honeyport forever, reporting each of them.
+///
+/// Connections are closed right away, nothing is ever sent back. Errors (e.g. too many open
+/// files) are reported, and the next connection waited for after a pause.
+fn serve_tcp(listener: TcpListener, honeyport: Honeyport, my_ip: IpAddr, callback: AlertCallback)
+{
+    loop {
+        match listener.accept() {
+            Ok((_stream, src)) => report(honeyport, src, my_ip, &callback),
+            Err(e) => {
+                println!("Honeyport {honeyport} failed to accept a connection: {e}");
+                thread::sleep(Duration::from_secs(1));
+            },
+        }
+    }
+}
+
+/// Receives datagrams on a UDP honeyport forever, reporting each of them.
+fn serve_udp(socket: UdpSocket, honeyport: Honeyport, my_ip: IpAddr, callback: AlertCallback)
+{
+    let mut buf = [0; 512];
+
+    loop {
+        if let Ok((_, src)) = socket.recv_from(&mut buf) {
+            report(honeyport, src, my_ip, &callback);
+        }
+    }
+}
+
+/// Opens the honeyports and reports every connection attempt on them.
+///
+/// Each honeyport is served by its own thread. Every connection attempt raises an alert about
+/// the host it came from (see `hit_alert`): high for a TCP connection, so that the response
+/// policy can lock the host out and broadcast it to the other machines, medium for a UDP
+/// datagram, whose source may be forged. Connections from the local machine are ignored.
+///
+/// The honeyports listen on IPv4 and IPv6 both, unless IPv6 is disabled (see `transport::bind_tcp`).
+///
+/// # Arguments
+///
+/// * `honeyports` - The honeyports to open.
+/// * `my_ip` - The IP address of the local machine.
+/// * `callback` - The callback to run with the alerts raised.
+pub fn start_honeyports(honeyports: Vec<Honeyport>, my_ip: IpAddr, callback: AlertCallback)
+{
+    for honeyport in honeyports {
+        let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, honeyport.port));
+        let callback = callback.clone();
+
+        let started = match honeyport.protocol {
+            Protocol::Tcp => bind_tcp(addr).map(|listener| {
+                thread::spawn(move || serve_tcp(listener, honeyport, my_ip, callback));
+            }),
+            Protocol::Udp => bind_udp(addr).map(|socket| {
+                thread::spawn(move || serve_udp(socket, honeyport, my_ip, callback));
+            }),
+        };
+
+        match started {
+            Ok(()) => println!("Honeyport {honeyport} open"),
+            Err(e) => println!("Failed to open honeyport {honeyport}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    #[test]
+    fn test_parse_honeyport() {
+        assert_eq!("445/tcp".parse(), Ok(Honeyport { port: 445, protocol: Protocol::Tcp }));
+        assert_eq!("161/UDP".parse(), Ok(Honeyport { port: 161, protocol: Protocol::Udp }));
+        assert_eq!("23".parse(), Ok(Honeyport { port: 23, protocol: Protocol::Tcp }));
+        assert!("23/sctp".parse::<Honeyport>().is_err());
+        assert!("telnet/tcp".parse::<Honeyport>().is_err());
+    }
+
+    #[test]
+    fn test_read_honeyports_from_file() {
+        let path = std::env::temp_dir().join("test_honeyports.txt");
+        std::fs::write(&path, "# decoys\n23/tcp\n\n161/udp\n").unwrap();
+
+        let honeyports = read_honeyports_from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(honeyports, vec![
+            Honeyport { port: 23, protocol: Protocol::Tcp },
+            Honeyport { port: 161, protocol: Protocol::Udp },
+        ]);
+    }
+
+    #[test]
+    fn test_hit_alert_targets_source() {
+        let src: SocketAddr = "172.42.0.9:51234".parse().unwrap();
+        let alert = hit_alert(Honeyport { port: 3389, protocol: Protocol::Tcp }, src);
+
+        assert_eq!(alert.target, src.ip());
+        assert_eq!(alert.severity, Severity::High);
+        assert_eq!(alert.details["port"], "3389");
+        assert_eq!(alert.details["source_port"], "51234");
+
+        // A datagram doesn't prove where it comes from.
+        let alert = hit_alert(Honeyport { port: 161, protocol: Protocol::Udp }, src);
+        assert_eq!(alert.severity, Severity::Medium);
+    }
+
+    #[test]
+    fn test_report_ignores_local_machine() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let callback: AlertCallback = Arc::new(Mutex::new(Box::new(move |alert: Alert| {
+            tx.lock().unwrap().send(alert).unwrap();
+        })));
+        let honeyport = Honeyport { port: 23, protocol: Protocol::Tcp };
+        let my_ip: IpAddr = "172.42.0.2".parse().unwrap();
+
+        report(honeyport, "127.0.0.1:40000".parse().unwrap(), my_ip, &callback);
+        report(honeyport, "172.42.0.2:40000".parse().unwrap(), my_ip, &callback);
+        report(honeyport, "[::ffff:172.42.0.2]:40000".parse().unwrap(), my_ip, &callback);
+        report(honeyport, "172.42.0.9:40000".parse().unwrap(), my_ip, &callback);
+
+        let alerts: Vec<Alert> = rx.try_iter().collect();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].target, "172.42.0.9".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,213 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use once_cell::sync::OnceCell;
+use std::{fs::{self, File, OpenOptions}, io::{self, Read, Write}, os::unix::fs::OpenOptionsExt, path::Path};
+
+/// The default path of the file holding the identity key of the agent.
+pub const DEFAULT_KEY_FILE: &str = "./identity.key";
+
+/// The identity of the agent, loaded once by `load_identity`.
+static IDENTITY: OnceCell<Identity> = OnceCell::new();
+
+/// The identity keypair of an agent, which signs every message it sends to its peers.
+///
+/// The public key, in hexadecimal, identifies the agent (the node) in the trust stores of
+/// its peers.
+pub struct Identity {
+    /// The secret key of the agent.
+    signing_key: SigningKey,
+}
+
+/// Reads `N` bytes from the random generator of the kernel.
+pub fn random_bytes<const N: usize>() -> io::Result<[u8; N]>
+{
+    let mut bytes = [0; N];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Encodes bytes in lower-case hexadecimal.
+pub fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decodes hexadecimal into `N` bytes.
+///
+/// # Returns
+///
+/// * `Some([u8; N])` - The bytes.
+/// * `None` if `hex` is not `2 * N` hexadecimal digits.
+pub fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]>
+{
+    if hex.len() != 2 * N || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Parses a public key (a node key) written in hexadecimal.
+///
+/// # Returns
+///
+/// * `Some(VerifyingKey)` - The public key.
+/// * `None` if `key` is not a valid ed25519 public key.
+pub fn parse_node_key(key: &str) -> Option<VerifyingKey>
+{
+    VerifyingKey::from_bytes(&from_hex::<32>(key)?).ok()
+}
+
+/// Returns the X25519 public key of a node, which the peer channel encrypts to.
+///
+/// The identity key is used for both signatures and key agreement, so that a node is
+/// identified by a single key.
+///
+/// # Returns
+///
+/// * `Some([u8; 32])` - The X25519 public key.
+/// * `None` if `key` is not a valid node key.
+pub fn channel_public_key(key: &str) -> Option<[u8; 32]>
+{
+    Some(parse_node_key(key)?.to_montgomery().to_bytes())
+}
+
+/// Checks a signature made by a node.
+///
+/// # Arguments
+///
+/// * `key` - The public key of the node, in hexadecimal.
+/// * `message` - The signed message.
+/// * `signature` - The signature, in hexadecimal.
+///
+/// # Returns
+///
+/// * `true` if `signature` is the signature of `message` by `key`.
+pub fn verify(key: &str, message: &[u8], signature: &str) -> bool
+{
+    let (Some(key), Some(signature)) = (parse_node_key(key), from_hex::<64>(signature)) else {
+        return false;
+    };
+    key.verify(message, &Signature::from_bytes(&signature)).is_ok()
+}
+
+impl Identity {
+    /// Generates a new identity from the random generator of the kernel.
+    pub fn generate() -> io::Result<Self>
+    {
+        Ok(Identity { signing_key: SigningKey::from_bytes(&random_bytes()?) })
+    }
+
+    /// Reads the identity stored in a key file, generating it on first run.
+    ///
+    /// The file holds the secret key in hexadecimal, and is only readable by its owner.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The path to the key file.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Identity)` - The identity of the agent.
+    /// * `Err(io::Error)` - An error if the file cannot be read, created or parsed.
+    pub fn load_or_generate(filename: &str) -> io::Result<Self>
+    {
+        match fs::read_to_string(filename) {
+            Ok(content) => {
+                let secret = from_hex::<32>(content.trim()).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{filename} does not hold an identity key"))
+                })?;
+                Ok(Identity { signing_key: SigningKey::from_bytes(&secret) })
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity::generate()?;
+                if let Some(parent) = Path::new(filename).parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(filename)?;
+                writeln!(file, "{}", to_hex(&identity.signing_key.to_bytes()))?;
+                Ok(identity)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the public key of the agent, in hexadecimal: its node key.
+    pub fn node_key(&self) -> String
+    {
+        to_hex(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// Returns the X25519 secret key of the agent, derived from its identity key.
+    pub fn channel_secret_key(&self) -> [u8; 32]
+    {
+        self.signing_key.to_scalar_bytes()
+    }
+
+    /// Signs a message.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The signature, in hexadecimal.
+    pub fn sign(&self, message: &[u8]) -> String
+    {
+        to_hex(&self.signing_key.sign(message).to_bytes())
+    }
+}
+
+/// Loads the identity of the agent from its key file, generating it on first run.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the key file.
+///
+/// # Returns
+///
+/// * `Ok(&Identity)` - The identity of the agent.
+/// * `Err(io::Error)` - An error if the key file cannot be read or created.
+pub fn load_identity(filename: &str) -> io::Result<&'static Identity>
+{
+    let identity = Identity::load_or_generate(filename)?;
+    Ok(IDENTITY.get_or_init(|| identity))
+}
+
+/// Returns the identity of the agent.
+///
+/// If `load_identity` was not called (e.g. in tests), a temporary identity is generated.
+pub fn identity() -> &'static Identity
+{
+    IDENTITY.get_or_init(|| Identity::generate().expect("Failed to generate an identity"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let identity = Identity::generate().unwrap();
+        let signature = identity.sign(b"isolate 10.0.0.2");
+
+        assert!(verify(&identity.node_key(), b"isolate 10.0.0.2", &signature));
+        assert!(!verify(&identity.node_key(), b"isolate 10.0.0.3", &signature));
+        assert!(!verify(&Identity::generate().unwrap().node_key(), b"isolate 10.0.0.2", &signature));
+        assert!(!verify("not a key", b"isolate 10.0.0.2", &signature));
+    }
+
+    #[test]
+    fn test_load_or_generate() {
+        let path = std::env::temp_dir().join("test_wormsec_identity.key");
+        let _ = fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+
+        let generated = Identity::load_or_generate(path).unwrap();
+        let loaded = Identity::load_or_generate(path).unwrap();
+        assert_eq!(generated.node_key(), loaded.node_key());
+
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,311 @@
+use serde::{Serialize, Deserialize};
+use std::{net::IpAddr, sync::{Arc, Mutex}};
+use once_cell::sync::Lazy;
+
+use crate::alert::{Alert, Policy, Response, Severity};
+use crate::identity::identity;
+
+/// The default time, in seconds, after which a quiet incident is closed.
+///
+/// Alerts about a machine that keep coming less than this apart are grouped in the same incident.
+pub const DEFAULT_INCIDENT_WINDOW: u64 = 300;
+
+/// The maximum number of incidents kept in `INCIDENTS`, the oldest ones being dropped first.
+const MAX_INCIDENTS: usize = 1000;
+
+/// A group of related alerts about the same machine.
+///
+/// Detection sources can fire many times for a single compromise (every inotify batch,
+/// every scanned port, ...). Alerts are grouped per target machine, as long as they keep
+/// coming within the incident window, so that one incident leads to one response.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Incident {
+    /// The unique identifier of the incident.
+    pub id: u64,
+    /// The IP address of the machine the incident is about.
+    pub target: IpAddr,
+    /// The time of the first alert, in seconds since the UNIX epoch.
+    pub first_seen: u64,
+    /// The time of the latest alert, in seconds since the UNIX epoch.
+    pub last_seen: u64,
+    /// The number of alerts grouped in the incident.
+    pub count: u64,
+    /// The highest severity of the alerts grouped in the incident.
+    pub severity: Severity,
+    /// The detection sources that raised the alerts, without duplicates.
+    pub sources: Vec<String>,
+    /// The message of the first alert.
+    pub summary: String,
+    /// Whether the response (isolation and broadcast) was already run for this incident.
+    pub responded: bool,
+    /// The key of the node that opened the incident, its identifier being unique to that node.
+    #[serde(default)]
+    pub node: String,
+}
+
+/// The incidents opened by the local agent, oldest first, that new alerts are correlated with.
+pub static INCIDENTS: Lazy<Arc<Mutex<Vec<Incident>>>> = Lazy::new(|| {Arc::new(Mutex::new(Vec::new()))});
+
+/// The incidents opened by the peers, as received when synchronizing the state with them.
+///
+/// They are kept apart from `INCIDENTS`, so that alerts are only ever correlated with the
+/// incidents of the local agent.
+pub static REPLICAS: Lazy<Arc<Mutex<Vec<Incident>>>> = Lazy::new(|| {Arc::new(Mutex::new(Vec::new()))});
+
+/// Adds an alert to the open incident about its target, or opens a new one.
+///
+/// An incident is open while its latest alert is less than `window` seconds older than the
+/// new one. The incident takes the highest severity of its alerts. The response must only
+/// be run the first time the severity of an incident calls for it (see `Policy`), which
+/// this function keeps track of.
+///
+/// # Arguments
+///
+/// * `alert` - The alert to correlate.
+/// * `window` - The time, in seconds, after which a quiet incident is closed.
+/// * `policy` - The policy deciding which severities call for a response.
+///
+/// # Returns
+///
+/// * `(Incident, bool)` - A copy of the incident the alert was added to, and whether the
+///   response must be run now.
+pub fn correlate(alert: &Alert, window: u64, policy: &Policy) -> (Incident, bool)
+{
+    let mut incidents = INCIDENTS.lock().unwrap();
+
+    let open = incidents
+        .iter_mut()
+        .rev()
+        .find(|i| i.target == alert.target && alert.timestamp.saturating_sub(i.last_seen) <= window);
+
+    let incident = match open {
+        Some(incident) => {
+            incident.last_seen = incident.last_seen.max(alert.timestamp);
+            incident.count += 1;
+            incident.severity = incident.severity.max(alert.severity);
+            if !incident.sources.contains(&alert.source) {
+                incident.sources.push(alert.source.clone());
+            }
+            incident
+        },
+        None => {
+            let id = incidents.last().map(|i| i.id + 1).unwrap_or(1);
+            if incidents.len() >= MAX_INCIDENTS {
+                incidents.remove(0);
+            }
+            incidents.push(Incident {
+                id,
+                target: alert.target,
+                first_seen: alert.timestamp,
+                last_seen: alert.timestamp,
+                count: 1,
+                severity: alert.severity,
+                sources: vec![alert.source.clone()],
+                summary: alert.message.clone(),
+                responded: false,
+                node: identity().node_key(),
+            });
+            incidents.last_mut().unwrap()
+        },
+    };
+
+    let respond = !incident.responded && policy.response_for(incident.severity) == Response::Isolate;
+    if respond {
+        incident.responded = true;
+    }
+
+    (incident.clone(), respond)
+}
+
+/// Retrieves the incidents stored, those of the agent and those of its peers, oldest first.
+///
+/// # Arguments
+///
+/// * `target` - If set, only the incidents about this IP address are returned.
+///
+/// # Returns
+///
+/// * `Vec<Incident>` - The incidents stored.
+pub fn get_incidents(target: Option<IpAddr>) -> Vec<Incident>
+{
+    let mut incidents: Vec<Incident> = INCIDENTS.lock().unwrap().iter().chain(REPLICAS.lock().unwrap().iter())
+        .filter(|incident| target.is_none_or(|ip| incident.target == ip))
+        .cloned()
+        .collect();
+    incidents.sort_by_key(|incident| incident.first_seen);
+    incidents
+}
+
+/// Retrieves the incidents updated most recently, those of the agent and those of its peers,
+/// to be sent to the peers.
+///
+/// # Arguments
+///
+/// * `limit` - The maximum number of incidents returned.
+///
+/// # Returns
+///
+/// * `Vec<Incident>` - The incidents, latest updated first.
+pub fn latest_incidents(limit: usize) -> Vec<Incident>
+{
+    let mut incidents = get_incidents(None);
+    incidents.sort_by_key(|incident| std::cmp::Reverse(incident.last_seen));
+    incidents.truncate(limit);
+    incidents
+}
+
+/// Merges the incidents received from a peer with the ones stored.
+///
+/// An incident only ever grows on the node that opened it (more alerts, a higher severity,
+/// more sources, a response run), so two copies of it are merged by keeping the most of
+/// each: whatever the order the copies reach an agent in, all the agents end up with the
+/// same incident. The incidents opened by the agent itself are its own to update and are
+/// ignored.
+///
+/// # Arguments
+///
+/// * `received` - The incidents received.
+///
+/// # Returns
+///
+/// * `usize` - The number of incidents added or updated.
+pub fn merge_incidents(received: Vec<Incident>) -> usize
+{
+    let own = identity().node_key();
+    let mut replicas = REPLICAS.lock().unwrap();
+    let mut changed = 0;
+
+    for incident in received {
+        if incident.node.is_empty() || incident.node == own {
+            continue;
+        }
+
+        let Some(replica) = replicas.iter_mut().find(|i| i.node == incident.node && i.id == incident.id) else {
+            if replicas.len() >= MAX_INCIDENTS {
+                let oldest = replicas.iter().enumerate().min_by_key(|(_, i)| i.last_seen).map(|(index, _)| index);
+                replicas.remove(oldest.unwrap());
+            }
+            replicas.push(incident);
+            changed += 1;
+            continue;
+        };
+
+        let before = replica.clone();
+        replica.first_seen = replica.first_seen.min(incident.first_seen);
+        replica.last_seen = replica.last_seen.max(incident.last_seen);
+        replica.count = replica.count.max(incident.count);
+        replica.severity = replica.severity.max(incident.severity);
+        replica.responded |= incident.responded;
+        for source in incident.sources {
+            if !replica.sources.contains(&source) {
+                replica.sources.push(source);
+            }
+        }
+        if *replica != before {
+            changed += 1;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert(target: &str, severity: Severity, timestamp: u64) -> Alert {
+        let mut alert = Alert::new("canary", severity, target.parse().unwrap(), "Canary read".to_string());
+        alert.timestamp = timestamp;
+        alert
+    }
+
+    #[test]
+    fn test_alerts_grouped_in_one_incident() {
+        let policy = Policy::default();
+
+        let (first, respond) = correlate(&alert("10.20.0.1", Severity::High, 1000), 300, &policy);
+        assert!(respond);
+        assert_eq!(first.count, 1);
+
+        for i in 1..100 {
+            let (incident, respond) = correlate(&alert("10.20.0.1", Severity::High, 1000 + i), 300, &policy);
+            assert!(!respond);
+            assert_eq!(incident.id, first.id);
+        }
+
+        let incidents = get_incidents(Some("10.20.0.1".parse().unwrap()));
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].count, 100);
+        assert_eq!(incidents[0].first_seen, 1000);
+        assert_eq!(incidents[0].last_seen, 1099);
+    }
+
+    #[test]
+    fn test_incident_escalation_responds_once() {
+        let policy = Policy::default();
+
+        let (_, respond) = correlate(&alert("10.20.0.2", Severity::Low, 1000), 300, &policy);
+        assert!(!respond);
+
+        let (incident, respond) = correlate(&alert("10.20.0.2", Severity::Critical, 1010), 300, &policy);
+        assert!(respond);
+        assert_eq!(incident.severity, Severity::Critical);
+
+        let (_, respond) = correlate(&alert("10.20.0.2", Severity::High, 1020), 300, &policy);
+        assert!(!respond);
+    }
+
+    #[test]
+    fn test_new_incident_after_window() {
+        let policy = Policy::default();
+
+        let (first, _) = correlate(&alert("10.20.0.3", Severity::High, 1000), 300, &policy);
+        let (second, respond) = correlate(&alert("10.20.0.3", Severity::High, 1400), 300, &policy);
+
+        assert_ne!(first.id, second.id);
+        assert!(respond);
+    }
+
+    #[test]
+    fn test_incidents_per_target() {
+        let policy = Policy::default();
+
+        let (a, _) = correlate(&alert("10.20.0.4", Severity::High, 1000), 300, &policy);
+        let (b, respond) = correlate(&alert("10.20.0.5", Severity::High, 1000), 300, &policy);
+
+        assert_ne!(a.id, b.id);
+        assert!(respond);
+    }
+
+    #[test]
+    fn test_merge_incidents() {
+        let replica = |id, count, severity, source: &str| Incident {
+            id,
+            target: "10.20.1.1".parse().unwrap(),
+            first_seen: 1000,
+            last_seen: 1000 + count,
+            count,
+            severity,
+            sources: vec![source.to_string()],
+            summary: "Canary read".to_string(),
+            responded: false,
+            node: "peer-10.20.1".to_string(),
+        };
+
+        // Copies received in any order merge to the same incident, and copies already known change nothing.
+        assert_eq!(merge_incidents(vec![replica(1, 5, Severity::High, "canary")]), 1);
+        assert_eq!(merge_incidents(vec![replica(1, 3, Severity::Critical, "scan")]), 1);
+        assert_eq!(merge_incidents(vec![replica(1, 3, Severity::Critical, "scan")]), 0);
+
+        let incidents = get_incidents(Some("10.20.1.1".parse().unwrap()));
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].count, 5);
+        assert_eq!(incidents[0].last_seen, 1005);
+        assert_eq!(incidents[0].severity, Severity::Critical);
+        assert_eq!(incidents[0].sources, vec!["canary", "scan"]);
+
+        // The incidents of the agent itself are not replicated back.
+        let mut own = replica(2, 1, Severity::Low, "canary");
+        own.node = identity().node_key();
+        assert_eq!(merge_incidents(vec![own]), 0);
+    }
+}
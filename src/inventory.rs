@@ -0,0 +1,578 @@
+use inotify::{EventMask, WatchMask};
+use serde::{Serialize, Deserialize};
+use std::{fmt, fs, io, net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs}, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use crate::control::ReloadCallback;
+use crate::watcher::{start_path_watcher, PathCallback};
+
+/// The largest number of addresses a CIDR range of the inventory may expand to.
+const MAX_RANGE_SIZE: u128 = 256;
+
+/// The address of an inventory entry, as written in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// A single IP address (e.g. `172.42.0.2`).
+    Ip(IpAddr),
+    /// A CIDR range, expanded to the addresses of its hosts (e.g. `172.42.0.0/29`).
+    Network(IpAddr, u8),
+    /// A DNS name, resolved when the inventory is loaded (e.g. `db.internal`).
+    Host(String),
+}
+
+/// An entry of the inventory file: an address and its metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InventoryEntry {
+    /// The line of the entry in the file, starting at 1.
+    pub line: usize,
+    /// The address of the entry.
+    pub address: Address,
+    /// The display name of the machine.
+    pub name: Option<String>,
+    /// Free-form tags (e.g. `prod`, `web`).
+    pub tags: Vec<String>,
+    /// The role of the machine (e.g. `server`, `workstation`).
+    pub role: Option<String>,
+    /// Who is responsible for the machine.
+    pub owner: Option<String>,
+    /// The MAC address of the machine, if known.
+    pub mac: Option<String>,
+    /// The other IP addresses of the machine (e.g. its IPv6 addresses).
+    pub addresses: Vec<IpAddr>,
+}
+
+/// A machine of the inventory, once the addresses are expanded and resolved.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Host {
+    /// The IP address of the machine.
+    pub ip: IpAddr,
+    /// The display name of the machine.
+    pub name: Option<String>,
+    /// The DNS name the address was resolved from.
+    pub hostname: Option<String>,
+    /// Free-form tags.
+    pub tags: Vec<String>,
+    /// The role of the machine.
+    pub role: Option<String>,
+    /// Who is responsible for the machine.
+    pub owner: Option<String>,
+    /// The MAC address of the machine, if known.
+    pub mac: Option<String>,
+    /// The other IP addresses of the machine, such as the IPv6 addresses of an IPv4 host.
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+}
+
+impl Host {
+    /// Creates a host with no metadata.
+    pub fn from_ip(ip: IpAddr) -> Self
+    {
+        Host { ip, name: None, hostname: None, tags: Vec::new(), role: None, owner: None, mac: None, addresses: Vec::new() }
+    }
+
+    /// Returns all the IP addresses of the host, starting with its main one.
+    pub fn all_addresses(&self) -> impl Iterator<Item = IpAddr> + '_
+    {
+        std::iter::once(self.ip).chain(self.addresses.iter().copied())
+    }
+}
+
+/// An invalid line of the inventory file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InventoryError {
+    /// The line, starting at 1.
+    pub line: usize,
+    /// Why the line is invalid.
+    pub message: String,
+}
+
+impl fmt::Display for InventoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Checks whether a string is a MAC address written as six colon-separated hex pairs.
+pub fn is_mac(mac: &str) -> bool
+{
+    let parts: Vec<&str> = mac.split(':').collect();
+    parts.len() == 6 && parts.iter().all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Checks whether a string is a valid DNS name.
+fn is_hostname(name: &str) -> bool
+{
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && !name.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+/// Splits a line in whitespace-separated fields, keeping double-quoted values together
+/// and stopping at the first `#` outside quotes.
+fn split_fields(line: &str) -> Result<Vec<String>, String>
+{
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut in_field = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_field = true;
+            },
+            '#' if !quoted => break,
+            c if c.is_whitespace() && !quoted => {
+                if in_field {
+                    fields.push(std::mem::take(&mut field));
+                    in_field = false;
+                }
+            },
+            c => {
+                field.push(c);
+                in_field = true;
+            },
+        }
+    }
+
+    if quoted {
+        return Err("unterminated quote".to_string());
+    }
+    if in_field {
+        fields.push(field);
+    }
+
+    Ok(fields)
+}
+
+/// Parses the address of an inventory line.
+fn parse_address(field: &str) -> Result<Address, String>
+{
+    if let Some((ip, prefix)) = field.split_once('/') {
+        let ip: IpAddr = ip.parse().map_err(|_| format!("\"{field}\" is not a valid CIDR range"))?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= max)
+            .ok_or_else(|| format!("\"{field}\" has an invalid prefix length"))?;
+
+        if 1u128.checked_shl((max - prefix) as u32).is_none_or(|size| size > MAX_RANGE_SIZE) {
+            return Err(format!("\"{field}\" is too large, at most {MAX_RANGE_SIZE} addresses are allowed"));
+        }
+        return Ok(Address::Network(ip, prefix));
+    }
+
+    if let Ok(ip) = field.parse() {
+        return Ok(Address::Ip(ip));
+    }
+
+    if is_hostname(field) {
+        Ok(Address::Host(field.to_lowercase()))
+    } else {
+        Err(format!("\"{field}\" is not an IP address, a CIDR range or a DNS name"))
+    }
+}
+
+/// Parses a line of the inventory file.
+///
+/// # Returns
+///
+/// * `Ok(None)` for empty and comment lines.
+/// * `Ok(Some(InventoryEntry))` for a valid entry.
+/// * `Err(String)` describing why the line is invalid.
+fn parse_line(number: usize, line: &str) -> Result<Option<InventoryEntry>, String>
+{
+    let fields = split_fields(line)?;
+    let Some((address, metadata)) = fields.split_first() else {
+        return Ok(None);
+    };
+
+    let mut entry = InventoryEntry {
+        line: number,
+        address: parse_address(address)?,
+        name: None,
+        tags: Vec::new(),
+        role: None,
+        owner: None,
+        mac: None,
+        addresses: Vec::new(),
+    };
+
+    for field in metadata {
+        let (key, value) = field
+            .split_once('=')
+            .ok_or_else(|| format!("\"{field}\" is not a key=value pair"))?;
+        if value.is_empty() {
+            return Err(format!("{key} has an empty value"));
+        }
+
+        match key {
+            "name" => entry.name = Some(value.to_string()),
+            "role" => entry.role = Some(value.to_string()),
+            "owner" => entry.owner = Some(value.to_string()),
+            "tags" => entry.tags = value.split(',').filter(|t| !t.is_empty()).map(String::from).collect(),
+            "mac" if is_mac(value) => entry.mac = Some(value.to_uppercase()),
+            "mac" => return Err(format!("\"{value}\" is not a MAC address")),
+            "addresses" => {
+                entry.addresses = value
+                    .split(',')
+                    .filter(|ip| !ip.is_empty())
+                    .map(|ip| ip.parse().map_err(|_| format!("\"{ip}\" is not an IP address")))
+                    .collect::<Result<_, _>>()?;
+            },
+            _ => return Err(format!("unknown key \"{key}\" (expected name, tags, role, owner, mac or addresses)")),
+        }
+    }
+
+    if !entry.addresses.is_empty() && matches!(entry.address, Address::Network(..)) {
+        return Err("addresses cannot be given to a CIDR range".to_string());
+    }
+
+    Ok(Some(entry))
+}
+
+/// Parses the content of an inventory file.
+///
+/// Each line holds an address (IP address, CIDR range or DNS name) followed by optional
+/// `key=value` metadata: `name`, `tags` (comma-separated), `role`, `owner`, `mac` and
+/// `addresses` (the other IP addresses of the machine, comma-separated). Values with spaces
+/// are double-quoted. Everything after a `#` is a comment.
+///
+/// ```text
+/// # Web servers
+/// 172.42.0.2        name=target role=server owner="Blue team" tags=web,prod addresses=2001:db8::2
+/// 172.42.0.8/30     name=lab tags=lab
+/// db.internal       name=database role=server
+/// ```
+///
+/// # Arguments
+///
+/// * `content` - The content of the file.
+///
+/// # Returns
+///
+/// * `(Vec<InventoryEntry>, Vec<InventoryError>)` - The valid entries and the invalid lines.
+pub fn parse_inventory(content: &str) -> (Vec<InventoryEntry>, Vec<InventoryError>)
+{
+    let mut entries = Vec::new();
+    let mut errors = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        match parse_line(index + 1, line) {
+            Ok(Some(entry)) => entries.push(entry),
+            Ok(None) => {},
+            Err(message) => errors.push(InventoryError { line: index + 1, message }),
+        }
+    }
+
+    (entries, errors)
+}
+
+/// Returns the addresses of the hosts of a CIDR range.
+///
+/// For IPv4 ranges larger than a `/31`, the network and broadcast addresses are left out.
+/// The range must not be larger than `MAX_RANGE_SIZE`, which `parse_address` checks.
+fn expand_network(ip: IpAddr, prefix: u8) -> Vec<IpAddr>
+{
+    match ip {
+        IpAddr::V4(ip) => {
+            let size = 1u32 << (32 - prefix as u32);
+            let base = u32::from(ip) & !(size - 1);
+            let hosts = if prefix < 31 { 1..size - 1 } else { 0..size };
+            hosts.map(|i| IpAddr::V4(Ipv4Addr::from(base + i))).collect()
+        },
+        IpAddr::V6(ip) => {
+            let size = 1u128 << (128 - prefix as u32);
+            let base = u128::from(ip) & !(size - 1);
+            (0..size).map(|i| IpAddr::V6(Ipv6Addr::from(base + i))).collect()
+        },
+    }
+}
+
+/// Resolves a DNS name to its IP addresses with the system resolver.
+pub fn resolve_hostname(name: &str) -> io::Result<Vec<IpAddr>>
+{
+    Ok((name, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+}
+
+/// Expands and resolves the entries of the inventory into hosts.
+///
+/// CIDR ranges are expanded to one host per address, their name being suffixed with the
+/// index of the host. DNS names are resolved to a single host, with their first address as
+/// its main one and the others, IPv4 and IPv6, as its other addresses. Addresses listed more
+/// than once are only kept the first time.
+///
+/// # Arguments
+///
+/// * `entries` - The entries of the inventory.
+/// * `resolve` - The function resolving DNS names (see `resolve_hostname`).
+///
+/// # Returns
+///
+/// * `(Vec<Host>, Vec<InventoryError>)` - The hosts, and the entries that could not be resolved.
+pub fn expand_entries(
+    entries: Vec<InventoryEntry>,
+    resolve: impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+) -> (Vec<Host>, Vec<InventoryError>)
+{
+    let mut hosts: Vec<Host> = Vec::new();
+    let mut errors = Vec::new();
+
+    for entry in entries {
+        let host = |ip, name, hostname, others: &[IpAddr]| Host {
+            ip,
+            name,
+            hostname,
+            tags: entry.tags.clone(),
+            role: entry.role.clone(),
+            owner: entry.owner.clone(),
+            mac: entry.mac.clone(),
+            addresses: others.iter().chain(&entry.addresses).copied().collect(),
+        };
+
+        let new_hosts = match &entry.address {
+            Address::Ip(ip) => vec![host(*ip, entry.name.clone(), None, &[])],
+            Address::Network(ip, prefix) => expand_network(*ip, *prefix)
+                .into_iter()
+                .enumerate()
+                .map(|(i, ip)| host(ip, entry.name.as_ref().map(|name| format!("{name}-{}", i + 1)), None, &[]))
+                .collect(),
+            Address::Host(name) => match resolve(name) {
+                Ok(ips) if !ips.is_empty() => vec![host(ips[0], entry.name.clone(), Some(name.clone()), &ips[1..])],
+                Ok(_) => {
+                    errors.push(InventoryError { line: entry.line, message: format!("\"{name}\" has no address") });
+                    continue;
+                },
+                Err(e) => {
+                    errors.push(InventoryError { line: entry.line, message: format!("cannot resolve \"{name}\": {e}") });
+                    continue;
+                },
+            },
+        };
+
+        for mut new_host in new_hosts {
+            if hosts.iter().any(|h| h.all_addresses().any(|ip| ip == new_host.ip)) {
+                continue;
+            }
+            // An address belongs to the first machine listing it.
+            for other in std::mem::take(&mut new_host.addresses) {
+                let known = |host: &Host| host.all_addresses().any(|ip| ip == other);
+                if !known(&new_host) && !hosts.iter().any(known) {
+                    new_host.addresses.push(other);
+                }
+            }
+            hosts.push(new_host);
+        }
+    }
+
+    (hosts, errors)
+}
+
+/// Reads the inventory file and returns its hosts.
+///
+/// Invalid lines and names that cannot be resolved don't prevent the other entries from
+/// being loaded, they are returned along with their line number.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the inventory file.
+///
+/// # Returns
+///
+/// * `Ok((Vec<Host>, Vec<InventoryError>))` - The hosts, and the invalid lines.
+/// * `Err(io::Error)` - An error if the file cannot be opened or read.
+pub fn read_inventory_from_file(filename: &str) -> io::Result<(Vec<Host>, Vec<InventoryError>)>
+{
+    let content = fs::read_to_string(filename)?;
+    let (entries, mut errors) = parse_inventory(&content);
+    let (hosts, resolve_errors) = expand_entries(entries, resolve_hostname);
+
+    errors.extend(resolve_errors);
+    errors.sort_by_key(|e| e.line);
+
+    Ok((hosts, errors))
+}
+
+/// Returns the directory of a file and the path of the file inside it, as the watcher reports it.
+fn watched_path(file: &Path) -> (PathBuf, PathBuf)
+{
+    let dir = match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let path = match file.file_name() {
+        Some(name) => dir.join(name),
+        None => file.to_path_buf(),
+    };
+    (dir, path)
+}
+
+/// Watches the inventory and configuration files, and reloads the inventory when one changes.
+///
+/// The directories of the files are watched rather than the files themselves, so that files
+/// replaced by an editor (written to a temporary file, then renamed) are still followed.
+///
+/// # Arguments
+///
+/// * `files` - The files to watch.
+/// * `reload` - The callback reloading the inventory, called with `"inotify"` as actor.
+pub fn watch_inventory(files: Vec<PathBuf>, reload: ReloadCallback)
+{
+    let (mut dirs, paths): (Vec<PathBuf>, Vec<PathBuf>) = files.iter().map(|file| watched_path(file)).unzip();
+    dirs.sort();
+    dirs.dedup();
+
+    let path_callback: PathCallback = Arc::new(Mutex::new(Box::new(move |path: &Path, _mask: EventMask| {
+        if !paths.iter().any(|p| p == path) {
+            return;
+        }
+
+        println!("{} changed, reloading the inventory", path.display());
+        let reload = reload.lock().unwrap();
+        if let Err(e) = reload("inotify") {
+            println!("Inventory not reloaded: {e}");
+        }
+    })));
+
+    start_path_watcher(dirs, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO, false, path_callback);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn create_temp_file_with_ips(contents: &str) -> io::Result<String> {
+        let tmp_dir = std::env::temp_dir();
+        let tmp_file_path = tmp_dir.join("test_inventory.txt");
+        let mut file = fs::File::create(&tmp_file_path)?;
+        file.write_all(contents.as_bytes())?;
+        Ok(tmp_file_path.to_str().unwrap().to_string())
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_read_inventory_from_file_success() {
+        let contents = "192.168.1.1\n127.0.0.1\n8.8.8.8\n";
+        let file_path = create_temp_file_with_ips(contents).unwrap();
+
+        let (hosts, errors) = read_inventory_from_file(&file_path).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(hosts.iter().map(|h| h.ip).collect::<Vec<_>>(), vec![ip("192.168.1.1"), ip("127.0.0.1"), ip("8.8.8.8")]);
+    }
+
+    #[test]
+    fn test_read_inventory_from_file_empty_file() {
+        let file_path = create_temp_file_with_ips("").unwrap();
+
+        let (hosts, errors) = read_inventory_from_file(&file_path).unwrap();
+        assert!(hosts.is_empty());
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_read_inventory_from_file_file_not_found() {
+        assert!(read_inventory_from_file("non_existent_file.txt").is_err());
+    }
+
+    #[test]
+    fn test_parse_inventory_metadata() {
+        let (entries, errors) = parse_inventory(
+            "# web\n172.42.0.2 name=target role=server owner=\"Blue team\" tags=web,prod # main\n\n  db.internal name=db\n"
+        );
+
+        assert!(errors.is_empty());
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].line, 2);
+        assert_eq!(entries[0].address, Address::Ip(ip("172.42.0.2")));
+        assert_eq!(entries[0].name.as_deref(), Some("target"));
+        assert_eq!(entries[0].owner.as_deref(), Some("Blue team"));
+        assert_eq!(entries[0].tags, vec!["web", "prod"]);
+        assert_eq!(entries[1].address, Address::Host("db.internal".to_string()));
+    }
+
+    #[test]
+    fn test_parse_inventory_errors() {
+        let (entries, errors) = parse_inventory(
+            "10.0.0.1\n10.0.0.300\n10.0.0.0/8\n10.0.0.2 colour=blue\n10.0.0.3 mac=nope\n10.0.0.4 name=\"open\n"
+        );
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), vec![2, 3, 4, 5, 6]);
+        assert_eq!(errors[0].to_string(), "line 2: \"10.0.0.300\" is not an IP address, a CIDR range or a DNS name");
+        assert!(errors[1].message.contains("too large"));
+    }
+
+    #[test]
+    fn test_expand_entries() {
+        let (entries, _) = parse_inventory("10.0.0.8/30 name=lab\n10.0.0.9\nfileserver tags=nas\nghost\n2001:db8::/127\n");
+        let resolve = |name: &str| match name {
+            "fileserver" => Ok(vec![ip("10.0.0.20")]),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "unknown host")),
+        };
+
+        let (hosts, errors) = expand_entries(entries, resolve);
+        assert_eq!(
+            hosts.iter().map(|h| h.ip).collect::<Vec<_>>(),
+            vec![ip("10.0.0.9"), ip("10.0.0.10"), ip("10.0.0.20"), ip("2001:db8::"), ip("2001:db8::1")]
+        );
+        assert_eq!(hosts[0].name.as_deref(), Some("lab-1"));
+        assert_eq!(hosts[2].hostname.as_deref(), Some("fileserver"));
+        assert_eq!(hosts[2].tags, vec!["nas"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 4);
+    }
+
+    #[test]
+    fn test_expand_entries_addresses() {
+        let (entries, errors) = parse_inventory(
+            "10.0.0.1 addresses=2001:db8::1,10.0.0.2\ndual\n2001:db8::1\n10.0.0.8/30 addresses=10.0.0.3\n10.0.0.4 addresses=nope\n"
+        );
+        assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), vec![4, 5]);
+
+        let resolve = |_: &str| Ok(vec![ip("10.0.0.5"), ip("2001:db8::5"), ip("10.0.0.2")]);
+        let (hosts, _) = expand_entries(entries, resolve);
+
+        // An address listed again, as another address or as a machine, stays with the first machine.
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].addresses, vec![ip("2001:db8::1"), ip("10.0.0.2")]);
+        assert_eq!(hosts[1].ip, ip("10.0.0.5"));
+        assert_eq!(hosts[1].addresses, vec![ip("2001:db8::5")]);
+    }
+
+    #[test]
+    fn test_watch_inventory() {
+        let dir = std::env::temp_dir().join("test_wormsec_watch_inventory");
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("ips.txt");
+        fs::write(&file, "10.0.0.1\n").unwrap();
+
+        let reloads = Arc::new(Mutex::new(Vec::new()));
+        let recorded = reloads.clone();
+        let reload: ReloadCallback = Arc::new(Mutex::new(Box::new(move |actor: &str| {
+            recorded.lock().unwrap().push(actor.to_string());
+            Ok("reloaded".to_string())
+        })));
+        watch_inventory(vec![file.clone()], reload);
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        fs::write(dir.join("other.txt"), "unrelated\n").unwrap();
+        fs::write(dir.join("ips.txt.tmp"), "10.0.0.2\n").unwrap();
+        fs::rename(dir.join("ips.txt.tmp"), &file).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(500));
+
+        assert_eq!(*reloads.lock().unwrap(), vec!["inotify".to_string()]);
+    }
+}
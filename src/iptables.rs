@@ -1,4 +1,13 @@
-use std::{error::Error, io, net::IpAddr, process::Command};
+use std::{collections::HashSet, error::Error, io, net::IpAddr, process::Command, sync::Mutex};
+use once_cell::sync::Lazy;
+
+use crate::events::{record_event, Event, EventKind};
+
+/// The default maximum number of machines locked out at the same time.
+pub const DEFAULT_MAX_LOCKED: usize = 256;
+
+/// The IP addresses locked out by the agent, whose rules are in the firewall.
+static LOCKED: Lazy<Mutex<HashSet<IpAddr>>> = Lazy::new(|| Mutex::new(HashSet::new()));
 
 // Kinda redundant, but I don't have enough Rust knowledge to do that otherwise
 
@@ -31,8 +40,7 @@ fn execute_iptables_command(is_v4: bool, args: &[&str]) -> Result<(), Box<dyn Er
             } else {
                 Err(
                     Box::new(
-                        io::Error::new(
-                            io::ErrorKind::Other,
+                        io::Error::other(
                             format!("Command exited with error code {}", status.code().unwrap_or(-1))
                         )
                     )
@@ -43,31 +51,70 @@ fn execute_iptables_command(is_v4: bool, args: &[&str]) -> Result<(), Box<dyn Er
     }
 }
 
+/// Records a firewall action in the event log, along with its outcome.
+///
+/// # Arguments
+///
+/// * `action` - The action run (e.g. "lock").
+/// * `addr` - The IP address the action is about.
+/// * `result` - The outcome of the action.
+fn record_firewall_event(action: &str, addr: IpAddr, result: &Result<(), Box<dyn Error>>)
+{
+    let actor = if addr.is_ipv4() { "iptables" } else { "ip6tables" };
+    let message = match result {
+        Ok(()) => format!("{action} {addr}: done"),
+        Err(e) => format!("{action} {addr}: failed ({e})"),
+    };
+
+    record_event(
+        Event::new(EventKind::Firewall, Some(addr), actor, message)
+            .with_detail("action", action)
+            .with_detail("success", result.is_ok())
+    );
+}
+
 /// Locks the specified IP address by adding rules to drop incoming and outgoing traffic.
 ///
 /// This function creates and executes two iptables rules to lock the provided IP address.
 /// It will drop both incoming (`INPUT`) and outgoing (`OUTPUT`) traffic for the specified
-/// IP address, preventing any network communication to or from it.
+/// IP address, preventing any network communication to or from it. The action is recorded
+/// in the event log.
+///
+/// An IP address already locked is not locked again, so that repeated isolations do not
+/// pile up rules, and no more than `max_locked` IP addresses are locked at the same time,
+/// so that a flood of isolations cannot exhaust the firewall.
 ///
 /// # Arguments
 ///
 /// * `addr` - The IP address to lock. Can be either IPv4 or IPv6.
+/// * `max_locked` - The maximum number of IP addresses locked at the same time.
 ///
 /// # Returns
 ///
-/// * `Ok(())` if the IP was successfully locked.
-/// * `Err(Box<dyn Error>)` if there was an error while applying the iptables rules.
+/// * `Ok(())` if the IP was successfully locked, or already was.
+/// * `Err(Box<dyn Error>)` if there was an error while applying the iptables rules, the
+///   ones applied being removed, or if too many IP addresses are locked already.
 ///
 /// # Example
 ///
 /// ```rust
 /// use std::net::IpAddr;
 /// let ip: IpAddr = "192.168.1.100".parse().unwrap();  // Replace with your IP address
-/// lock_ip(ip).unwrap();
+/// lock_ip(ip, 256).unwrap();
 /// ```
-pub fn lock_ip(addr: IpAddr) -> Result<(), Box<dyn Error>>
+pub fn lock_ip(addr: IpAddr, max_locked: usize) -> Result<(), Box<dyn Error>>
 {
     let ip = addr.to_string();
+    let mut locked = LOCKED.lock().unwrap();
+    if locked.contains(&addr) {
+        return Ok(());
+    }
+    if locked.len() >= max_locked {
+        println!("Not locking IP {ip}: {} IP addresses are locked already", locked.len());
+        let result = Err(io::Error::other(format!("{} IP addresses are locked already", locked.len())).into());
+        record_firewall_event("lock", addr, &result);
+        return result;
+    }
 
     println!("Locking IP {ip}");
 
@@ -76,20 +123,35 @@ pub fn lock_ip(addr: IpAddr) -> Result<(), Box<dyn Error>>
         vec!["-A", "OUTPUT", "-d", &ip, "-j", "DROP"],
     ];
 
-    for rule in &rules {
-        execute_iptables_command(match addr {
-            IpAddr::V4(_) => true,
-            IpAddr::V6(_) => false,
-        }, rule)?;
-    }
+    let mut applied = 0;
+    let result = rules.iter().try_for_each(|rule| {
+        execute_iptables_command(addr.is_ipv4(), rule)?;
+        applied += 1;
+        Ok(())
+    });
 
-    Ok(())
+    record_firewall_event("lock", addr, &result);
+    match result {
+        Ok(()) => {
+            locked.insert(addr);
+        },
+        // The address is either locked out both ways or not at all.
+        Err(_) => {
+            for rule in &rules[..applied] {
+                let mut rule = rule.clone();
+                rule[0] = "-D";
+                execute_iptables_command(addr.is_ipv4(), &rule).ok();
+            }
+        },
+    }
+    result
 }
 
 /// Unlocks the specified IP address by removing iptables rules to allow incoming and outgoing traffic.
 ///
 /// This function reverses the action of `lock_ip`. It removes two iptables rules to allow both
-/// incoming (`INPUT`) and outgoing (`OUTPUT`) traffic for the specified IP address.
+/// incoming (`INPUT`) and outgoing (`OUTPUT`) traffic for the specified IP address. The action
+/// is recorded in the event log.
 ///
 /// # Arguments
 ///
@@ -107,21 +169,19 @@ pub fn lock_ip(addr: IpAddr) -> Result<(), Box<dyn Error>>
 /// let ip: IpAddr = "192.168.1.100".parse().unwrap();  // Replace with your IP address
 /// unlock_ip(ip).unwrap();
 /// ```
-#[allow(dead_code)]
 pub fn unlock_ip(addr: IpAddr) -> Result<(), Box<dyn Error>>
 {
+    LOCKED.lock().unwrap().remove(&addr);
     let ip = addr.to_string();
     let rules = [
         vec!["-D", "INPUT", "-s", &ip, "-j", "DROP"],
         vec!["-D", "OUTPUT", "-d", &ip, "-j", "DROP"],
     ];
 
-    for rule in &rules {
-        execute_iptables_command(match addr {
-            IpAddr::V4(_) => true,
-            IpAddr::V6(_) => false,
-        }, rule)?;
-    }
+    let result = rules
+        .iter()
+        .try_for_each(|rule| execute_iptables_command(addr.is_ipv4(), rule));
 
-    Ok(())
+    record_firewall_event("unlock", addr, &result);
+    result
 }
\ No newline at end of file
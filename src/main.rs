@@ -1,40 +1,156 @@
-use std::{error::Error, sync::{Arc, Mutex}, thread::sleep, time::Duration};
-use iptables::lock_ip;
-use network::{broadcast, start_network_watcher, NetCallback};
-use state::change_machine_state;
-use utils::read_ips_from_file;
+use std::{error::Error, io, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, sync::{Arc, Mutex}, thread::sleep, time::Duration};
+use alert::{record_alert, Alert, AlertCallback, Policy, Severity};
+use auditd::{default_rules, start_auditd};
+use events::{open_event_log, record_event, Event, EventKind};
+use canary::{read_canaries_from_file, start_canaries};
+use cli::{parse_args, run_client, Command, USAGE};
+use config::{load_config, InventoryConfig};
+use discovery::{discovery_callback, handle_hello, start_announcer};
+use control::{start_control_socket, ControlContext, ReloadCallback};
+use fanout::{start_fanout_detector, FanoutSettings};
+use fim::{read_fim_entries_from_file, rebaseline, start_fim};
+use honeyport::{read_honeyports_from_file, start_honeyports};
+use identity::load_identity;
+use incident::correlate;
+use inventory::{read_inventory_from_file, watch_inventory, Host};
+use neighbour::start_neighbour_discovery;
+use network::{send_hello, send_to_node, set_enrolment_key, start_greeting, start_network_watcher, MessageCallback, NetCallback, Payload};
+use response::{Quorum, Responder};
+use state::IsolationVote;
+use sync::start_state_sync;
+use syslog::{read_syslog_settings_from_file, start_syslog_receiver};
+use transport::set_transport;
+use trust::open_trust_store;
+use utils::{local_addresses, timestamp};
 use watcher::{start_watcher, Callback};
-use local_ip_address::local_ip;
-use tokio::task;
-use web_server::run_web_server;
+use local_ip_address::{local_ip, local_ipv6};
+use tokio::{signal::unix::{signal, SignalKind}, task};
+use web_server::{run_web_server, AppState};
 
+mod alert;
+mod auditd;
+mod canary;
+mod channel;
+mod cli;
+mod config;
+mod control;
+mod discovery;
+mod events;
+mod fanout;
+mod fim;
+mod flood;
+mod honeyport;
+mod identity;
+mod incident;
+mod inventory;
 mod iptables;
+mod neighbour;
 mod network;
+mod process;
+mod procnet;
+mod response;
 mod state;
+mod sync;
+mod syslog;
+mod transport;
+mod trust;
 mod utils;
 mod watcher;
 mod web_server;
 
+/// Reads the hosts of the inventory: the ones of `ips_file`, then the configured peers.
+///
+/// The invalid lines of `ips_file` are reported with their line number and skipped.
+///
+/// # Arguments
+///
+/// * `inventory` - The `[inventory]` section of the configuration.
+///
+/// # Returns
+///
+/// * `Ok((Vec<Host>, usize))` - The hosts, without duplicates, and the number of invalid lines.
+/// * `Err(io::Error)` - An error if `ips_file` cannot be read.
+fn load_inventory(inventory: &InventoryConfig) -> io::Result<(Vec<Host>, usize)>
+{
+    let (mut hosts, errors) = read_inventory_from_file(&inventory.ips_file)?;
+    for error in &errors {
+        println!("{}: {error}", inventory.ips_file);
+    }
+
+    for peer in &inventory.peers {
+        if !hosts.iter().any(|host| host.ip == *peer) {
+            hosts.push(Host::from_ip(*peer));
+        }
+    }
+    Ok((hosts, errors.len()))
+}
+
+/// Reloads the inventory, re-reading the configuration for the `[inventory]` section.
+///
+/// The machines of the state are updated with `state::update_from_list`: new machines are
+/// added, the ones no longer listed are retired, and the others keep their status. The reload
+/// is recorded in the event log.
+///
+/// # Arguments
+///
+/// * `actor` - Who or what triggered the reload (`inotify`, `SIGHUP`, an operator).
+///
+/// # Returns
+///
+/// * `Ok(String)` - A description of the changes.
+/// * `Err(String)` - A message if the configuration or the inventory cannot be read, in
+///   which case the machines are left untouched.
+fn reload_inventory(actor: &str) -> Result<String, String>
+{
+    let (config, _) = load_config().map_err(|e| format!("Invalid configuration: {e}"))?;
+    let inventory = config.inventory;
+
+    let (hosts, invalid) = load_inventory(&inventory).map_err(|e| format!("Cannot read {}: {e}", inventory.ips_file))?;
+    let (added, retired) = state::update_from_list(hosts, &inventory.mac);
+
+    let message = format!("Inventory reloaded: {added} added, {retired} retired, {invalid} invalid lines skipped");
+    println!("{message}");
+    record_event(
+        Event::new(EventKind::Inventory, None, actor, message.clone())
+            .with_detail("added", added)
+            .with_detail("retired", retired)
+            .with_detail("invalid", invalid),
+    );
+
+    Ok(message)
+}
+
 /// The main entry point for the application.
 ///
 /// This is an asynchronous function that performs the following:
 /// 1. Retrieves the local IP address of the machine.
-/// 2. Loads a list of IP addresses from a file (`ips.txt`).
-/// 3. Initializes the application state based on the loaded IP addresses.
+/// 2. Loads the inventory of the machines from a file (`ips.txt`).
+/// 3. Initializes the application state based on the loaded inventory.
 ///
 /// It sets up a watcher that monitors network activity and performs actions when a specific IP
 /// is encountered. It also starts a web server asynchronously and runs in a loop waiting for events.
 ///
 /// # Workflow:
 ///
+/// 0. The configuration is loaded and validated (see `config::load_config`).
 /// 1. The function starts by loading the local IP of the current machine (`my_ip`).
-/// 2. The list of IP addresses is read from a file (`ips.txt`), and the state is initialized using these IPs.
-/// 3. It sets up two types of callbacks:
-///    - **General callback** (`callback`) for handling machine state transitions when specific IPs are detected.
-///    - **Network callback** (`net_callback`) to trigger actions when network activity with certain IPs is observed.
-/// 4. A **network watcher** and a **local callback handler** are set up to monitor the system and change the machine state and lock IPs if necessary.
-/// 5. The web server (`run_web_server`) is spawned asynchronously to handle web requests or status updates.
-/// 6. The function enters an infinite loop (`loop { sleep(Duration::from_millis(1000)); }`) to keep the program running.
+/// 2. The inventory (`ips.txt`) is read along with the configured peers, and the state is initialized
+///    using these hosts. It is reloaded when it changes (see `reload_inventory`).
+/// 3. It sets up three types of callbacks:
+///    - **Alert callback** (`alert_callback`) correlating the alerts of the detection sources into
+///      incidents, and isolating their targets (see `incident::correlate`).
+///    - **Network callback** (`net_callback`) handling the isolations requested by the trusted peers
+///      (see `trust` and `response::Quorum`).
+///    - **Message callback** (`messages`) handling the hellos, releases and state of the peers.
+/// 4. The detection sources are started: auth log, canaries, FIM, honeyports, fan-out, auditd,
+///    syslog and neighbour discovery (see their `start_*` functions).
+/// 5. A **network watcher** is set up to receive the messages of the peers, along with the discovery
+///    announcer, the state sync and the control socket (`control.sock`).
+/// 6. The web server (`run_web_server`) is spawned asynchronously to handle web requests or status updates.
+/// 7. The function enters an infinite loop (`loop { sleep(Duration::from_millis(1000)); }`) to keep the program running.
+///
+/// This workflow is the `run` command, the default one. The other commands (see `cli::USAGE`)
+/// check the configuration, rebuild the FIM baseline or drive the running agent, then exit.
 ///
 /// # Returns
 ///
@@ -44,32 +160,313 @@ mod web_server;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>>
 {
-    let my_ip = local_ip()?;
-    let ips = read_ips_from_file("./ips.txt")?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            std::process::exit(2);
+        },
+    };
+
+    if command == Command::Help {
+        println!("{USAGE}");
+        return Ok(());
+    }
+
+    let (config, config_path) = match load_config() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(1);
+        },
+    };
+
+    if matches!(command, Command::Run | Command::CheckConfig) {
+        if let Err(e) = config.check_files() {
+            eprintln!("Invalid configuration: {config_path}: {e}");
+            std::process::exit(1);
+        }
+    }
 
-    println!("Loaded {} IPS: {:?}", ips.len(), ips);
+    match command {
+        Command::Run => {},
+        Command::CheckConfig => {
+            println!("{config_path}: OK");
+            return Ok(());
+        },
+        Command::Rebaseline => {
+            let count = rebaseline(&config.sources.fim, &config.sources.fim_baseline)?;
+            println!("FIM baseline rebuilt with {count} files");
+            return Ok(());
+        },
+        command => {
+            if let Err(e) = run_client(command, &config) {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return Ok(());
+        },
+    }
+
+    println!("Configuration loaded from {config_path}");
+
+    let my_ip = local_ip().or_else(|_| local_ipv6())?;
+    let (hosts, _) = load_inventory(&config.inventory)?;
 
-    state::from_list(ips);
+    println!("Loaded {} IPS: {:?}", hosts.len(), hosts.iter().map(|host| host.ip).collect::<Vec<_>>());
+
+    state::from_list(hosts, &config.inventory.mac);
 
     println!("My IP: {my_ip}");
 
-    let callback: Callback = Arc::new(Mutex::new(Box::new(move || {
-        change_machine_state(&my_ip.to_string(), "isolated");
-        broadcast(&my_ip).ok();
+    match open_event_log(&config.events.path) {
+        Ok(count) => println!("Loaded {count} events from {}", config.events.path),
+        Err(e) => println!("Event log not persisted: {e}"),
+    }
+
+    let identity = load_identity(&config.identity.key_file)?;
+    println!("Node key: {} ({})", identity.node_key(), config.identity.key_file);
+
+    let nodes = open_trust_store(&config.identity.trust_store)?;
+    println!("Loaded {nodes} nodes from {}", config.identity.trust_store);
+
+    match state::open_clock(&config.network.clock_file) {
+        Ok(counter) => println!("Lamport clock at {counter} ({})", config.network.clock_file),
+        Err(e) => println!("Lamport clock not persisted: {e}"),
+    }
+
+    let responder = Responder {
+        my_ip,
+        firewall: config.firewall.backend,
+        port: config.network.port,
+        gossip_ttl: config.network.gossip_ttl,
+        max_locked: config.firewall.max_locked,
+    };
+
+    let quorum = Quorum {
+        votes: config.response.quorum,
+        window: config.response.quorum_window,
+        immediate_from: config.response.immediate_from,
+    };
+    let net_callback: NetCallback = Arc::new(Mutex::new(Box::new(move |ip, severity, src, node: &str, hops: &[String]| {
+        let node_short = trust::short_key(node);
+        if !trust::is_trusted_key(node) {
+            println!("Ignored the isolation of {ip} requested by {src}: node {node_short} is not trusted");
+            return;
+        }
+
+        let vote = IsolationVote { target: ip, node: node.to_string(), src: src.ip(), severity, timestamp: timestamp() };
+        let votes = state::record_vote(vote, quorum.window);
+        let count = state::get_votes(Some(ip)).len();
+        println!("Isolation of {ip} voted by node {node_short}: {count} of {} votes", quorum.votes);
+
+        let actor = format!("{src} (node {node_short})");
+        let mut event = Event::new(EventKind::PeerAlert, Some(ip), &actor, format!("Peer {src} reported {ip}"))
+            .with_detail("node", node)
+            .with_detail("votes", count);
+        if let Some(severity) = severity {
+            event = event.with_detail("severity", severity);
+        }
+        if !hops.is_empty() {
+            let hops: Vec<&str> = hops.iter().map(|hop| trust::short_key(hop)).collect();
+            event = event.with_detail("hops", hops.join(" -> "));
+        }
+        record_event(event);
+
+        if quorum.is_reached(votes, severity) && responder.isolate(ip, false, severity) {
+            record_event(
+                Event::new(EventKind::Isolation, Some(ip), "quorum", format!("Isolated after {count} of {} votes", quorum.votes))
+                    .with_detail("votes", count)
+            );
+        }
+    })));
+
+    let policy = Policy { isolate_from: config.response.isolate_from };
+    let incident_window = config.response.incident_window;
+
+    let alert_callback: AlertCallback = Arc::new(Mutex::new(Box::new(move |alert: Alert| {
+        println!("[{}] {} alert on {}: {}", alert.severity, alert.source, alert.target, alert.message);
+
+        let (incident, respond) = correlate(&alert, incident_window, &policy);
+        record_event(
+            Event::new(EventKind::Detection, Some(alert.target), &alert.source, alert.message.clone())
+                .with_detail("severity", alert.severity)
+                .with_detail("incident", incident.id)
+        );
+        record_alert(alert);
+
+        if respond {
+            println!("Incident #{} ({}) on {}: isolating", incident.id, incident.severity, incident.target);
+            if responder.isolate(incident.target, true, Some(incident.severity)) {
+                record_event(
+                    Event::new(EventKind::Isolation, Some(incident.target), "policy", format!("Isolated after incident #{}", incident.id))
+                        .with_detail("incident", incident.id)
+                        .with_detail("severity", incident.severity)
+                );
+            }
+        }
     })));
 
-    let net_callback: NetCallback = Arc::new(Mutex::new(Box::new(move |ip| {
-        change_machine_state(&ip.to_string(), "isolated");
-        lock_ip(ip).ok();
+    let sources = config.sources;
+
+    if !sources.auth_log.is_empty() {
+        let auth_alert_callback = alert_callback.clone();
+        let auth_log = sources.auth_log.clone();
+        let callback: Callback = Arc::new(Mutex::new(Box::new(move || {
+            let cb = auth_alert_callback.lock().unwrap();
+            cb(Alert::new("authlog", Severity::High, my_ip, format!("Access to {auth_log}")));
+        })));
+
+        start_watcher(sources.auth_log.clone(), callback);
+    }
+
+    match read_canaries_from_file(&sources.canaries) {
+        Ok(canaries) => start_canaries(canaries, my_ip, alert_callback.clone()),
+        Err(e) => println!("No canaries loaded: {e}"),
+    }
+
+    match read_fim_entries_from_file(&sources.fim) {
+        Ok(entries) => start_fim(entries, sources.fim_baseline.clone(), my_ip, alert_callback.clone()),
+        Err(e) => println!("No FIM entries loaded: {e}"),
+    }
+
+    match read_honeyports_from_file(&sources.honeyports) {
+        Ok(honeyports) => start_honeyports(honeyports, my_ip, alert_callback.clone()),
+        Err(e) => println!("No honeyports loaded: {e}"),
+    }
+
+    if sources.fanout {
+        start_fanout_detector(FanoutSettings::default(), my_ip, alert_callback.clone());
+    }
+
+    if !sources.audit_log.is_empty() {
+        start_auditd(sources.audit_log.clone(), default_rules(), my_ip, alert_callback.clone());
+    }
+
+    match read_syslog_settings_from_file(&sources.syslog) {
+        Ok(settings) => start_syslog_receiver(settings, alert_callback.clone()),
+        Err(e) => println!("Syslog receiver disabled: {e}"),
+    }
+
+    if config.inventory.discovery_interval > 0 {
+        let interval = Duration::from_secs(config.inventory.discovery_interval);
+        start_neighbour_discovery(interval, my_ip, alert_callback.clone());
+    }
+
+    let discovery = &config.discovery;
+    let announcements = discovery.enabled.then(|| discovery_callback(my_ip, discovery.enrolment_key.clone()));
+    let enrolment_key = discovery.enrolment_key.clone();
+    set_enrolment_key(&enrolment_key);
+    // The addresses the hellos of the peers may be sent to.
+    let bind = Some(config.network.bind).filter(|bind| !bind.is_unspecified());
+    let port = config.network.port;
+    // Only the nodes saying hello directly can be trusted on first use, from the address they sent from.
+    let trust_on_first_use = config.identity.trust_on_first_use;
+    let messages: MessageCallback = Arc::new(Mutex::new(Box::new(move |payload: &Payload, src: SocketAddr, node: &str| {
+        match payload {
+            Payload::Hello { .. } if node == identity::identity().node_key() => {},
+            Payload::Hello { reply, signature } => {
+                let local: Vec<IpAddr> = std::iter::once(my_ip).chain(bind).chain(local_addresses()).collect();
+                match handle_hello(signature.as_deref(), src, node, &local, &enrolment_key) {
+                    Ok(true) => println!("Trusted node {} of {src} with the enrolment key", trust::short_key(node)),
+                    Ok(false) => {},
+                    Err(e) => println!("Rejected the hello of {src}: it {e}"),
+                }
+                if trust_on_first_use && trust::trust_on_first_use(node, src.ip()) {
+                    println!("Trusted node {} of {src} on first use, to be confirmed by an operator", trust::short_key(node));
+                }
+                if !reply && trust::is_trusted_key(node) {
+                    if let Err(e) = send_hello(src.ip(), port, true) {
+                        println!("Failed to answer the hello of {src}: {e}");
+                    }
+                }
+            },
+            Payload::Release { .. } | Payload::ReleaseAck { .. } | Payload::Sync(_) if node == identity::identity().node_key() => {},
+            Payload::Release { .. } | Payload::ReleaseAck { .. } if !trust::is_trusted_key(node) => {
+                println!("Ignored a release from {src}: node {} is not trusted", trust::short_key(node));
+            },
+            Payload::Sync(_) if !trust::is_trusted_key(node) => {
+                println!("Ignored the state of {src}: node {} is not trusted", trust::short_key(node));
+            },
+            Payload::Sync(sync) => {
+                let (machines, incidents) = sync::apply(sync.clone());
+                if machines > 0 || incidents > 0 {
+                    println!("Synchronized with {src}: {machines} machine statuses and {incidents} incidents updated");
+                }
+            },
+            Payload::Release { ip, id, operator } => {
+                if !responder.peer_release(*ip, id, node, src, *operator, &quorum) {
+                    return;
+                }
+                let ack = Payload::ReleaseAck { ip: *ip, id: id.clone() };
+                if let Err(e) = send_to_node(ack, node, SocketAddr::new(src.ip(), port)) {
+                    println!("Failed to confirm the release of {ip} to {src}: {e}");
+                }
+            },
+            Payload::ReleaseAck { ip, id } => {
+                let peer = trust::node_ip(node).unwrap_or(src.ip());
+                if let Some(release) = state::confirm_release(id, peer) {
+                    let message = format!("Peer {peer} confirmed the release of {ip}");
+                    println!("{message} ({} of {} peers)", release.confirmed_by.len(), release.sent_to.len());
+                    record_event(Event::new(EventKind::Release, Some(*ip), &src.to_string(), message).with_detail("release", id));
+                }
+            },
+            payload => {
+                if let Some(announcements) = &announcements {
+                    let cb = announcements.lock().unwrap();
+                    cb(payload, src, node);
+                }
+            },
+        }
     })));
+    if discovery.enabled {
+        let interval = Duration::from_secs(discovery.interval);
+        start_announcer(discovery.address, config.network.port, interval, my_ip, discovery.enrolment_key.clone());
+    }
+
+    let transport = transport::bind(config.network.transport, config.network.bind_addr())
+        .map_err(|e| format!("Failed to listen for the peers on {}: {e}", config.network.bind_addr()))?;
+    println!("Listening for the peers on {}/{}", config.network.transport, transport.local_addr()?);
+    set_transport(transport.clone());
+    start_network_watcher(transport, config.network.port, config.network.listener_settings(), net_callback, Some(messages));
+    start_greeting(config.network.port);
+    if config.network.sync_interval > 0 {
+        start_state_sync(Duration::from_secs(config.network.sync_interval), config.network.port);
+    }
+
+    let started = timestamp();
 
-    start_watcher(callback.clone());
+    let reload: ReloadCallback = Arc::new(Mutex::new(Box::new(reload_inventory)));
 
-    start_network_watcher(net_callback);
+    let mut watched = vec![PathBuf::from(&config.inventory.ips_file)];
+    if Path::new(&config_path).exists() {
+        watched.push(PathBuf::from(&config_path));
+    }
+    watch_inventory(watched, reload.clone());
+
+    let sighup_reload = reload.clone();
+    let mut sighup = signal(SignalKind::hangup())?;
+    task::spawn(async move {
+        while sighup.recv().await.is_some() {
+            let reload = sighup_reload.lock().unwrap();
+            if let Err(e) = reload("SIGHUP") {
+                println!("Inventory not reloaded: {e}");
+            }
+        }
+    });
+
+    if !config.control.socket.is_empty() {
+        let context = ControlContext { responder, alert_callback: alert_callback.clone(), reload, started };
+        if let Err(e) = start_control_socket(&config.control.socket, context) {
+            println!("Control socket disabled: cannot create {}: {e}", config.control.socket);
+        }
+    }
 
-    let _web_server = task::spawn(run_web_server());
+    let app_state = AppState { my_ip, started };
+    let _web_server = task::spawn(run_web_server(config.ui.bind_addr(), config.ui.path.clone(), app_state));
 
     loop {
         sleep(Duration::from_millis(1000));
     }
-}
\ No newline at end of file
+}
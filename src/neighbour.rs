@@ -0,0 +1,400 @@
+use std::{collections::HashMap, ffi::CStr, fs, mem, net::IpAddr, process::Command, ptr, thread, time::{Duration, Instant}};
+use local_ip_address::list_afinet_netifas;
+
+use crate::alert::{Alert, AlertCallback, Severity};
+use crate::state::{self, AddressSource};
+use crate::utils::{hostname, local_addresses};
+
+/// How long a hostname found by reverse DNS is kept before being looked up again.
+const HOSTNAME_TTL: Duration = Duration::from_secs(600);
+
+/// An entry of the kernel neighbour table: the MAC address an IP address resolves to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbour {
+    /// The IP address of the neighbour.
+    pub ip: IpAddr,
+    /// The MAC address of the neighbour, in upper case.
+    pub mac: String,
+    /// The network interface the neighbour is reachable through.
+    pub device: String,
+}
+
+/// A change of the MAC address of a known IP address, a sign of ARP (or NDP) spoofing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacChange {
+    /// The IP address whose MAC address changed.
+    pub ip: IpAddr,
+    /// The MAC address seen before.
+    pub old_mac: String,
+    /// The MAC address seen now.
+    pub new_mac: String,
+    /// Another machine of the inventory already known with the new MAC address, which is
+    /// then likely the one spoofing `ip`.
+    pub claimed_by: Option<IpAddr>,
+    /// The network interface the neighbour is reachable through.
+    pub device: String,
+}
+
+/// Parses the IPv4 neighbour table, as printed in `/proc/net/arp`.
+///
+/// Incomplete entries (flags `0x0`, or an all-zero MAC address) are skipped.
+///
+/// # Arguments
+///
+/// * `content` - The content of `/proc/net/arp`.
+///
+/// # Returns
+///
+/// * `Vec<Neighbour>` - The complete entries of the table.
+pub fn parse_proc_net_arp(content: &str) -> Vec<Neighbour>
+{
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 || fields[2] == "0x0" || fields[3] == "00:00:00:00:00:00" {
+                return None;
+            }
+            Some(Neighbour {
+                ip: fields[0].parse().ok()?,
+                mac: fields[3].to_uppercase(),
+                device: fields[5].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Parses the output of `ip neigh show` (e.g. `fe80::1 dev eth0 lladdr 52:54:00:12:34:56 router REACHABLE`).
+///
+/// Entries without a link-layer address (`FAILED`, `INCOMPLETE`) are skipped.
+///
+/// # Arguments
+///
+/// * `content` - The output of the command.
+///
+/// # Returns
+///
+/// * `Vec<Neighbour>` - The entries with a MAC address.
+pub fn parse_ip_neigh(content: &str) -> Vec<Neighbour>
+{
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let value = |key: &str| fields.iter().position(|f| *f == key).and_then(|i| fields.get(i + 1));
+
+            Some(Neighbour {
+                ip: fields.first()?.parse().ok()?,
+                mac: value("lladdr")?.to_uppercase(),
+                device: value("dev").map(|d| d.to_string()).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// Reads the kernel neighbour tables: `/proc/net/arp` for IPv4, `ip -6 neigh` for IPv6.
+///
+/// A table that cannot be read is skipped.
+fn read_neighbours() -> Vec<Neighbour>
+{
+    let mut neighbours = Vec::new();
+
+    if let Ok(content) = fs::read_to_string("/proc/net/arp") {
+        neighbours.extend(parse_proc_net_arp(&content));
+    }
+    if let Ok(output) = Command::new("ip").args(["-6", "neigh", "show"]).output() {
+        neighbours.extend(parse_ip_neigh(&String::from_utf8_lossy(&output.stdout)));
+    }
+
+    neighbours
+}
+
+/// Looks the hostname of an IP address up with reverse DNS (`getnameinfo`).
+///
+/// # Arguments
+///
+/// * `ip` - The IP address to look up.
+///
+/// # Returns
+///
+/// * `Some(String)` - The hostname of the address.
+/// * `None` if the address has no name.
+pub fn reverse_lookup(ip: IpAddr) -> Option<String>
+{
+    // SAFETY: an all-zero `sockaddr_storage` is valid, and is large enough for both address families.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match ip {
+        IpAddr::V4(v4) => {
+            // SAFETY: `sockaddr_storage` is larger than, and aligned for, `sockaddr_in`.
+            let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(v4.octets()) };
+            mem::size_of::<libc::sockaddr_in>()
+        },
+        IpAddr::V6(v6) => {
+            // SAFETY: `sockaddr_storage` is larger than, and aligned for, `sockaddr_in6`.
+            let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_addr = libc::in6_addr { s6_addr: v6.octets() };
+            mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+
+    let mut host = [0 as libc::c_char; 1025];
+
+    // SAFETY: `storage` holds an address of `len` bytes and `host` is valid for writes of its length.
+    let ret = unsafe {
+        libc::getnameinfo(
+            &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+            len as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+
+    // SAFETY: `getnameinfo` succeeded, so `host` holds a NUL-terminated string.
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Remembers the MAC addresses of the machines to spot the ones that change.
+#[derive(Debug, Default)]
+pub struct NeighbourTracker {
+    /// The last MAC address seen for each IP address.
+    known: HashMap<IpAddr, String>,
+}
+
+impl NeighbourTracker {
+    /// Creates a tracker knowing no MAC address yet.
+    pub fn new() -> Self
+    {
+        NeighbourTracker::default()
+    }
+
+    /// Records the neighbours of the machines and returns the MAC addresses that changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `neighbours` - The entries of the neighbour tables.
+    /// * `machines` - The IP addresses of the machines to track, the others being ignored.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<MacChange>` - The known IP addresses now resolving to another MAC address.
+    pub fn observe(&mut self, neighbours: &[Neighbour], machines: &[IpAddr]) -> Vec<MacChange>
+    {
+        let mut changes = Vec::new();
+
+        for neighbour in neighbours.iter().filter(|n| machines.contains(&n.ip)) {
+            let Some(old_mac) = self.known.get(&neighbour.ip).filter(|mac| **mac != neighbour.mac) else {
+                continue;
+            };
+            let claimed_by = self
+                .known
+                .iter()
+                .find(|(ip, mac)| **ip != neighbour.ip && **mac == neighbour.mac)
+                .map(|(ip, _)| *ip);
+
+            changes.push(MacChange {
+                ip: neighbour.ip,
+                old_mac: old_mac.clone(),
+                new_mac: neighbour.mac.clone(),
+                claimed_by,
+                device: neighbour.device.clone(),
+            });
+        }
+
+        for neighbour in neighbours.iter().filter(|n| machines.contains(&n.ip)) {
+            self.known.insert(neighbour.ip, neighbour.mac.clone());
+        }
+
+        changes
+    }
+}
+
+/// Creates the alert raised for a MAC address change.
+///
+/// When another machine is known with the new MAC address, it is the target of the alert,
+/// with a high severity, as it is likely spoofing the address. Otherwise the alert is about
+/// the address that changed, with a medium severity, as the change may be legitimate (e.g.
+/// a replaced network card).
+///
+/// # Arguments
+///
+/// * `change` - The change of MAC address.
+///
+/// # Returns
+///
+/// * `Alert` - The alert describing the change.
+pub fn mac_change_alert(change: &MacChange) -> Alert
+{
+    let message = format!(
+        "MAC address of {} changed from {} to {}: possible ARP spoofing",
+        change.ip, change.old_mac, change.new_mac
+    );
+    let mut alert = match change.claimed_by {
+        Some(spoofer) => Alert::new("arp", Severity::High, spoofer, format!("{message} by {spoofer}")),
+        None => Alert::new("arp", Severity::Medium, change.ip, message),
+    };
+
+    alert.details.insert("ip".to_string(), change.ip.to_string());
+    alert.details.insert("old_mac".to_string(), change.old_mac.clone());
+    alert.details.insert("new_mac".to_string(), change.new_mac.clone());
+    alert.details.insert("device".to_string(), change.device.clone());
+    alert
+}
+
+/// Returns the MAC address and hostname of the local machine.
+fn local_identity(my_ip: IpAddr) -> (Option<String>, Option<String>)
+{
+    let mac = list_afinet_netifas()
+        .ok()
+        .and_then(|ifas| ifas.into_iter().find(|(_, ip)| *ip == my_ip))
+        .and_then(|(name, _)| fs::read_to_string(format!("/sys/class/net/{name}/address")).ok())
+        .map(|mac| mac.trim().to_uppercase());
+    let hostname = Some(hostname()).filter(|name| !name.is_empty());
+
+    (mac, hostname)
+}
+
+/// Starts discovering the MAC addresses and hostnames of the machines of the inventory.
+///
+/// The discovery runs in a separate thread. Every `interval`, the kernel neighbour tables
+/// are read to update the MAC addresses of the machines, and the hostnames are looked up
+/// with reverse DNS (at most every ten minutes for each machine). The local machine reports
+/// its own MAC address and hostname. A known IP address resolving to another MAC address
+/// raises an alert.
+///
+/// The IPv6 addresses of the local machine are added to it (see `state::add_address`), so
+/// that it is known by them as well. The IPv6 neighbours are only shown with the machine
+/// having the same MAC address, as any machine of the segment can forge them: the inventory
+/// must list the IPv6 addresses of a machine for it to be isolated by them.
+///
+/// # Arguments
+///
+/// * `interval` - The time between two discoveries.
+/// * `my_ip` - The IP address of the local machine.
+/// * `callback` - The callback to run with the alerts raised.
+pub fn start_neighbour_discovery(interval: Duration, my_ip: IpAddr, callback: AlertCallback)
+{
+    thread::spawn(move || {
+        let mut tracker = NeighbourTracker::new();
+        let mut looked_up: HashMap<IpAddr, Instant> = HashMap::new();
+
+        loop {
+            let (mac, hostname) = local_identity(my_ip);
+            state::update_discovered(&my_ip.to_string(), mac.as_deref(), hostname.as_deref());
+            if let Some(mac) = &mac {
+                for ip in local_addresses().into_iter().filter(IpAddr::is_ipv6) {
+                    state::add_address(mac, ip, AddressSource::Local);
+                }
+            }
+
+            let neighbours = read_neighbours();
+            for neighbour in neighbours.iter().filter(|n| n.ip.is_ipv6()) {
+                state::add_address(&neighbour.mac, neighbour.ip, AddressSource::Neighbour);
+            }
+
+            let known = state::get_machines().into_iter().filter(|m| !m.retired).collect::<Vec<_>>();
+            let machines: Vec<IpAddr> = known
+                .iter()
+                .flat_map(|m| std::iter::once(&m.ip).chain(&m.addresses))
+                .filter_map(|ip| ip.parse().ok())
+                .collect();
+            for neighbour in neighbours.iter().filter(|n| machines.contains(&n.ip)) {
+                state::update_discovered(&neighbour.ip.to_string(), Some(&neighbour.mac), None);
+            }
+            for change in tracker.observe(&neighbours, &machines) {
+                println!("MAC address of {} changed from {} to {}", change.ip, change.old_mac, change.new_mac);
+                let cb = callback.lock().unwrap();
+                cb(mac_change_alert(&change));
+            }
+
+            let local = local_addresses();
+            for ip in known.iter().filter_map(|m| m.ip.parse::<IpAddr>().ok()).filter(|ip| *ip != my_ip && !local.contains(ip)) {
+                if looked_up.get(&ip).is_some_and(|at| at.elapsed() < HOSTNAME_TTL) {
+                    continue;
+                }
+                looked_up.insert(ip, Instant::now());
+                if let Some(hostname) = reverse_lookup(ip) {
+                    state::update_discovered(&ip.to_string(), None, Some(&hostname));
+                }
+            }
+
+            thread::sleep(interval);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARP: &str = include_str!("../testdata/neighbour/arp.txt");
+    const IP_NEIGH: &str = include_str!("../testdata/neighbour/ip_neigh.txt");
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_proc_net_arp() {
+        let neighbours = parse_proc_net_arp(ARP);
+        assert_eq!(neighbours.len(), 3);
+        assert_eq!(neighbours[0], Neighbour {
+            ip: ip("172.42.0.3"),
+            mac: "02:42:AC:2A:00:03".to_string(),
+            device: "eth0".to_string(),
+        });
+        assert!(!neighbours.iter().any(|n| n.ip == ip("172.42.0.9")));
+    }
+
+    #[test]
+    fn test_parse_ip_neigh() {
+        let neighbours = parse_ip_neigh(IP_NEIGH);
+        assert_eq!(neighbours.len(), 2);
+        assert_eq!(neighbours[1], Neighbour {
+            ip: ip("2001:db8:42::5"),
+            mac: "02:42:AC:2A:00:05".to_string(),
+            device: "eth0".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_neighbour_tracker() {
+        let machines = vec![ip("172.42.0.3"), ip("172.42.0.4")];
+        let mut tracker = NeighbourTracker::new();
+
+        let neighbours = parse_proc_net_arp(ARP);
+        assert!(tracker.observe(&neighbours, &machines).is_empty());
+        assert!(tracker.observe(&neighbours, &machines).is_empty());
+
+        // 172.42.0.4 now answers for 172.42.0.3.
+        let mut spoofed = neighbours.clone();
+        spoofed[0].mac = "02:42:AC:2A:00:04".to_string();
+        let changes = tracker.observe(&spoofed, &machines);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].old_mac, "02:42:AC:2A:00:03");
+        assert_eq!(changes[0].claimed_by, Some(ip("172.42.0.4")));
+
+        let alert = mac_change_alert(&changes[0]);
+        assert_eq!((alert.target, alert.severity), (ip("172.42.0.4"), Severity::High));
+
+        // The gateway is not a machine of the inventory, its changes are ignored.
+        let mut gateway = spoofed.clone();
+        gateway[2].mac = "02:42:00:00:00:99".to_string();
+        assert!(tracker.observe(&gateway, &machines).is_empty());
+    }
+
+    #[test]
+    fn test_reverse_lookup_localhost() {
+        assert!(reverse_lookup(ip("127.0.0.1")).is_some());
+    }
+}
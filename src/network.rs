@@ -1,96 +1,729 @@
-use std::{io, net::{IpAddr, SocketAddr, UdpSocket}, str::FromStr, sync::{Arc, Mutex}, thread};
+use once_cell::sync::OnceCell;
+use serde::{Serialize, Deserialize};
+use std::{io, net::{IpAddr, SocketAddr}, str::FromStr, sync::{mpsc::{self, TrySendError}, Arc, Mutex}, thread, time::{Duration, Instant}};
 
+use crate::alert::Severity;
+use crate::channel::{self, ReplayGuard, MAX_DATAGRAM};
+use crate::discovery::{sign_hello, Announcement};
+use crate::flood::{record_drop, record_error, record_received, supervise, DropReason, ListenerSettings, RateLimiter};
+use crate::identity::{identity, random_bytes, to_hex, verify};
 use crate::state;
+use crate::sync::StateSync;
+use crate::transport::{transport, Transport};
+use crate::trust;
+use crate::utils::{local_addresses, timestamp};
+
+/// How far, in seconds, the timestamp of a message may be from the local clock.
+///
+/// Older messages are dropped, so that a recorded message cannot be replayed long after it
+/// was sent.
+pub const MAX_CLOCK_SKEW: u64 = 300;
 
 /// A type alias for a callback function that accepts an IP address and performs an action.
 ///
 /// The callback is wrapped in a `Mutex` to allow for safe concurrent access and 
-/// in an `Arc` to allow sharing between threads. It is expected to take an `IpAddr`
+/// in an `Arc` to allow sharing between threads. It is expected to take an `IpAddr`,
+/// the severity sent with it, the `SocketAddr` of the peer that sent it, the key of the node that signed it
+/// and the keys of the nodes that relayed it (empty if it was received from the node itself),
 /// and return nothing (i.e., it's a side-effecting function).
-pub type NetCallback = Arc<Mutex<Box<dyn Fn(IpAddr) + Send + 'static>>>;
+pub type NetCallback = Arc<Mutex<Box<dyn Fn(IpAddr, Option<Severity>, SocketAddr, &str, &[String]) + Send + 'static>>>;
+
+/// The time the listener waits before receiving again after a first error of the transport,
+/// doubled on each consecutive error up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The longest time the listener waits before receiving again after an error of the transport.
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum number of nodes a message is relayed through, whatever the TTL it was sent with.
+pub const MAX_HOPS: usize = 16;
+
+/// The time between two greetings of the machines whose node key is unknown.
+const GREETING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The enrolment key the hellos are signed with, set once at startup.
+static ENROLMENT_KEY: OnceCell<String> = OnceCell::new();
+
+/// A type alias for a callback function handling the messages of the peers other than the
+/// isolation requests (e.g. the announcements of the discovery).
+///
+/// Like `NetCallback`, it is wrapped in an `Arc<Mutex<...>>`. It receives the payload of the
+/// message, the `SocketAddr` of the peer that sent it and the key of the node that signed it.
+pub type MessageCallback = Arc<Mutex<Box<dyn Fn(&Payload, SocketAddr, &str) + Send + 'static>>>;
+
+/// The content of a message exchanged between agents.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Payload {
+    /// Asks the peers to isolate a machine, with the severity of the incident behind the
+    /// request if there is one.
+    Isolate {
+        ip: IpAddr,
+        #[serde(default)]
+        severity: Option<Severity>,
+    },
+    /// Announces an agent on the segment (see `discovery`).
+    Announce(Announcement),
+    /// Asks the peers to release an isolated machine. `id` identifies the release in the
+    /// acknowledgements. `operator` marks the releases an operator issued, which the peers
+    /// apply without waiting for a quorum (see `response::Responder::peer_release`); the
+    /// signature of the message covers it.
+    Release {
+        ip: IpAddr,
+        id: String,
+        #[serde(default)]
+        operator: bool,
+    },
+    /// Confirms to the originator of a release that the machine was released.
+    ReleaseAck { ip: IpAddr, id: String },
+    /// Makes the node key of an agent known to a peer, which answers with its own unless
+    /// `reply` is set. `signature` proves, with the enrolment key, that the agent may join
+    /// (see `discovery::handle_hello`).
+    Hello {
+        reply: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+    },
+    /// Shares the state of an agent with its peers (see `sync`).
+    Sync(StateSync),
+    /// Carries an isolation request from node to node, so that it reaches the peers the node
+    /// that sent it cannot reach directly.
+    Gossip(Gossip),
+}
+
+/// An isolation request spreading from node to node.
+///
+/// The request is the message signed by the node it comes from, its nonce identifying it
+/// across the paths it takes: every agent acts on it and relays it once, the copies received
+/// later being dropped. Each node relaying it records its key in `hops` and decrements `ttl`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Gossip {
+    /// The message relayed, as signed by the node it comes from.
+    pub message: Box<PeerMessage>,
+    /// The number of times the message can still be relayed.
+    pub ttl: u8,
+    /// The keys of the nodes that relayed the message, in order.
+    pub hops: Vec<String>,
+}
+
+impl Gossip {
+    /// Checks a gossip received from a node before acting on it.
+    ///
+    /// The relayed message must be a correctly signed isolation request, and the node that
+    /// sent the gossip must be the last one it went through: the node that signed the message
+    /// if it was not relayed yet, the last hop otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - The key of the node the gossip was received from.
+    /// * `now` - The current time, in seconds since the UNIX epoch.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if the request can be acted on.
+    /// * `Err(String)` - Why the gossip is dropped.
+    pub fn verify(&self, sender: &str, now: u64) -> Result<(), String>
+    {
+        if !matches!(self.message.payload, Payload::Isolate { .. }) {
+            return Err("relays something else than an isolation request".to_string());
+        }
+        if self.hops.len() > MAX_HOPS {
+            return Err(format!("went through more than {MAX_HOPS} nodes"));
+        }
+        if self.hops.last().unwrap_or(&self.message.node) != sender {
+            return Err("was not relayed by its sender".to_string());
+        }
+        self.message.verify(now).map_err(|e| format!("relays a message that {e}"))
+    }
+
+    /// Returns the gossip to relay to the peers of `node`, unless the message went far enough.
+    pub fn relayed(&self, node: &str) -> Option<Gossip>
+    {
+        if self.ttl == 0 || self.hops.len() >= MAX_HOPS {
+            return None;
+        }
+        let mut hops = self.hops.clone();
+        hops.push(node.to_string());
+        Some(Gossip { message: self.message.clone(), ttl: self.ttl - 1, hops })
+    }
+}
+
+impl Payload {
+    /// Checks whether the payload must go through the encrypted channel.
+    ///
+    /// Only the announcements and the hellos, which make the node keys known, are sent in
+    /// clear text: they are needed before a peer can be sent anything encrypted, and only
+    /// tell that an agent runs on the sender.
+    pub fn requires_channel(&self) -> bool
+    {
+        !matches!(self, Payload::Announce(_) | Payload::Hello { .. })
+    }
+}
+
+/// A message exchanged between agents, signed by the node that sent it.
+///
+/// Every message is attributable to a node: the receivers check the signature against the
+/// node key, then whether the node is in their trust store.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PeerMessage {
+    /// The key of the node that sent the message, in hexadecimal.
+    pub node: String,
+    /// The time the message was sent, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// A random value in hexadecimal, telling apart the messages of a node sent within the
+    /// same second.
+    pub nonce: String,
+    /// The content of the message.
+    pub payload: Payload,
+    /// The signature of the node, in hexadecimal, over the other fields.
+    pub signature: String,
+}
+
+impl PeerMessage {
+    /// Returns the bytes signed for a message.
+    fn signed_bytes(node: &str, timestamp: u64, nonce: &str, payload: &Payload) -> Vec<u8>
+    {
+        serde_json::to_vec(&(node, timestamp, nonce, payload)).unwrap_or_default()
+    }
+
+    /// Creates a message, timestamped now and signed with the identity of the agent.
+    ///
+    /// # Arguments
+    ///
+    /// * `payload` - The content of the message.
+    pub fn new(payload: Payload) -> Self
+    {
+        let identity = identity();
+        let node = identity.node_key();
+        let timestamp = timestamp();
+        let nonce = to_hex(&random_bytes::<16>().expect("Failed to read random bytes"));
+        let signature = identity.sign(&PeerMessage::signed_bytes(&node, timestamp, &nonce, &payload));
+
+        PeerMessage { node, timestamp, nonce, payload, signature }
+    }
+
+    /// Returns the identifier of the message, unique across the nodes.
+    pub fn id(&self) -> String
+    {
+        format!("{}:{}", trust::short_key(&self.node), self.nonce)
+    }
+
+    /// Checks that the message is signed by its node and recent.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current time, in seconds since the UNIX epoch.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` if the message can be attributed to its node.
+    /// * `Err(String)` - Why the message is rejected.
+    pub fn verify(&self, now: u64) -> Result<(), String>
+    {
+        if self.timestamp.abs_diff(now) > MAX_CLOCK_SKEW {
+            return Err(format!("is too old or too far in the future ({})", self.timestamp));
+        }
+        if !verify(&self.node, &PeerMessage::signed_bytes(&self.node, self.timestamp, &self.nonce, &self.payload), &self.signature) {
+            return Err("has an invalid signature".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Reads a datagram received from a peer: opens it if it is sealed, then checks its signature.
+///
+/// # Arguments
+///
+/// * `datagram` - The datagram received.
+/// * `now` - The current time, in seconds since the UNIX epoch.
+///
+/// # Returns
+///
+/// * `Ok(PeerMessage)` - The message, attributed to its node.
+/// * `Err(String)` - Why the datagram is dropped.
+pub fn read_datagram(datagram: &[u8], now: u64) -> Result<PeerMessage, String>
+{
+    let sealed = channel::is_sealed(datagram);
+    let message: PeerMessage = if sealed {
+        channel::open(datagram)?
+    } else {
+        serde_json::from_slice(datagram).map_err(|_| "is unsigned or malformed".to_string())?
+    };
+
+    message.verify(now)?;
+    if !sealed && message.payload.requires_channel() {
+        return Err("was sent in clear text instead of the encrypted channel".to_string());
+    }
+    Ok(message)
+}
 
-/// Starts a network watcher that listens for incoming UDP packets and invokes the callback when an IP address is received.
+/// Starts a network watcher that listens for incoming messages and invokes the callback when an isolation is requested.
+///
+/// This function listens on the given transport (UDP port `21335` by default), expecting to receive datagrams containing a
+/// signed `PeerMessage`, sealed for the agent (see `channel`) unless it is an announcement or a hello. When a message
+/// correctly signed by its node asks to isolate an IP address, the provided callback function is called with the IP
+/// address, the severity sent with it, the address of the sender and the key of the node, allowing the application to
+/// check that the node is trusted, count its vote and act on the detected IP (e.g., locking it). The other messages are passed to `messages`. Unsigned
+/// messages, messages with an invalid signature, replayed messages and isolation requests sent in clear text are dropped.
 ///
-/// This function listens on a specific port (`21335`), expecting to receive UDP packets containing an
-/// IP address. When a valid IP address is received, the provided callback function is called with
-/// the IP address, allowing the application to act on the detected IP (e.g., locking it).
+/// Isolation requests relayed by the peers (see `Gossip`) are acted on once, whatever the number of paths they arrive
+/// through, and relayed in turn to the peers of the local machine if they come from a trusted node and may still be.
 ///
-/// The function runs in a separate thread to handle incoming data asynchronously.
+/// The messages are received in a thread, and processed in another, both restarted if they fail. Each source can
+/// only send `settings.rate_limit` messages per second, and only `settings.queue_size` messages wait to be processed,
+/// the others being dropped, so that a flood cannot exhaust the agent. The messages dropped are counted (see
+/// `flood::PeerStats`), and only reported once a minute for each source.
 ///
 /// # Arguments
 ///
-/// * `callback` - A callback function wrapped in an `Arc<Mutex<Box<dyn Fn(IpAddr) + Send + 'static>>>`.
-///   This callback is triggered whenever a valid IP address is received.
-pub fn start_network_watcher(callback: NetCallback)
+/// * `transport` - The transport to listen on (see `transport`).
+/// * `port` - The port the other machines listen on, to relay the isolation requests to.
+/// * `settings` - The rate limit of each source and the size of the queue of the messages to process.
+/// * `callback` - A callback function wrapped in an `Arc<Mutex<Box<dyn Fn(IpAddr, Option<Severity>, SocketAddr, &str, &[String]) + Send + 'static>>>`.
+///   This callback is triggered whenever an isolation is requested.
+/// * `messages` - The callback the other messages are passed to, if any.
+pub fn start_network_watcher(transport: Arc<dyn Transport>, port: u16, settings: ListenerSettings, callback: NetCallback, messages: Option<MessageCallback>)
 {
-    thread::spawn(move || {
-        let socket = UdpSocket::bind("0.0.0.0:21335").unwrap(); // 21335 => b"WS"
+    let (queue, datagrams) = mpsc::sync_channel::<(Vec<u8>, SocketAddr)>(settings.queue_size);
+    let datagrams = Arc::new(Mutex::new(datagrams));
+    let replays = Arc::new(Mutex::new(ReplayGuard::default()));
 
-        let mut buf = [0; 128];
+    supervise("peer listener", move || {
+        let mut limiter = RateLimiter::new(settings.rate_limit);
+        let mut backoff = Duration::ZERO;
+        let mut buf = vec![0; MAX_DATAGRAM];
         loop {
-            let (amt, _src) = socket.recv_from(&mut buf).unwrap();
-            let received_data = String::from_utf8_lossy(&buf[..amt]).trim().to_string();
-
-            match IpAddr::from_str(&received_data) {
-                Ok(ip) => {
-                    println!("Received valid IP {received_data}. Locking IP...");
-                    let cb = callback.lock().unwrap();
-                    cb(ip);
+            let (amt, src) = match transport.recv(&mut buf) {
+                Ok(received) => {
+                    backoff = Duration::ZERO;
+                    received
+                },
+                Err(e) => {
+                    record_error();
+                    backoff = (backoff * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+                    println!("Failed to receive a message from the peers: {e}, retrying in {}ms", backoff.as_millis());
+                    thread::sleep(backoff);
+                    continue;
                 },
-                Err(_) => {},
+            };
+
+            record_received();
+            if !limiter.allow(src.ip(), Instant::now()) {
+                if record_drop(DropReason::RateLimited, src.ip()) {
+                    println!("Dropping messages from {src}: it sends more than {} per second", settings.rate_limit);
+                }
+                continue;
+            }
+            if let Err(TrySendError::Full(_)) = queue.try_send((buf[..amt].to_vec(), src)) {
+                if record_drop(DropReason::QueueFull, src.ip()) {
+                    println!("Dropping messages from {src}: too many messages are waiting to be processed");
+                }
             }
         }
     });
+
+    supervise("peer message handler", move || loop {
+        let next = datagrams.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let Ok((datagram, src)) = next else {
+            return;
+        };
+        let mut replays = replays.lock().unwrap_or_else(|e| e.into_inner());
+        handle_datagram(&datagram, src, &mut replays, port, &callback, messages.as_ref());
+    });
 }
 
+/// Handles a datagram received from a peer (see `start_network_watcher`).
+fn handle_datagram(datagram: &[u8], src: SocketAddr, replays: &mut ReplayGuard, port: u16, callback: &NetCallback, messages: Option<&MessageCallback>)
+{
+    let now = timestamp();
+    let message = match read_datagram(datagram, now) {
+        Ok(message) => message,
+        Err(e) => {
+            if record_drop(DropReason::Malformed, src.ip()) {
+                println!("Dropped a message from {src}: it {e}");
+            }
+            return;
+        },
+    };
+    if !replays.check(&message, now) {
+        if record_drop(DropReason::Replayed, src.ip()) {
+            println!("Dropped a message from {src}: it was replayed");
+        }
+        return;
+    }
+
+    match &message.payload {
+        Payload::Isolate { ip, severity } => {
+            println!("Received valid IP {ip} from {src} (node {}).", trust::short_key(&message.node));
+            let cb = callback.lock().unwrap_or_else(|e| e.into_inner());
+            cb(*ip, *severity, src, &message.node, &[]);
+        },
+        Payload::Gossip(gossip) => {
+            if let Err(e) = gossip.verify(&message.node, now) {
+                if record_drop(DropReason::Malformed, src.ip()) {
+                    println!("Dropped a message from {src}: it {e}");
+                }
+                return;
+            }
+            if !gossip.hops.is_empty() && !trust::is_trusted_key(&message.node) {
+                println!("Dropped a message from {src}: node {} relaying it is not trusted", trust::short_key(&message.node));
+                return;
+            }
+            // Already received through another path.
+            if !replays.check(&gossip.message, now) {
+                return;
+            }
+            let Payload::Isolate { ip, severity } = gossip.message.payload else {
+                return;
+            };
+
+            let origin = &gossip.message.node;
+            if gossip.hops.is_empty() {
+                println!("Received valid IP {ip} from {src} (node {}).", trust::short_key(origin));
+            } else {
+                let hops: Vec<&str> = gossip.hops.iter().map(|hop| trust::short_key(hop)).collect();
+                println!("Received valid IP {ip} from {src} (node {}, message {}, via {}).", trust::short_key(origin), gossip.message.id(), hops.join(" -> "));
+            }
+            {
+                let cb = callback.lock().unwrap_or_else(|e| e.into_inner());
+                cb(ip, severity, src, origin, &gossip.hops);
+            }
+
+            if trust::is_trusted_key(origin) {
+                if let Some(relayed) = gossip.relayed(&identity().node_key()) {
+                    if let Err(e) = relay(relayed, ip, port) {
+                        println!("Failed to relay the isolation of {ip}: {e}");
+                    }
+                }
+            }
+        },
+        payload => {
+            if let Some(messages) = messages {
+                let cb = messages.lock().unwrap_or_else(|e| e.into_inner());
+                cb(payload, src, &message.node);
+            }
+        },
+    }
+}
+
+/// Returns the machines the messages are sent to: all but the machines retired from the
+/// inventory, the pending peers and the revoked nodes.
+fn recipients() -> Vec<IpAddr>
+{
+    state::get_machines()
+        .into_iter()
+        .filter(|m| !m.retired && !m.pending)
+        .filter_map(|m| IpAddr::from_str(&m.ip).ok())
+        .filter(|ip| !trust::is_revoked_ip(*ip))
+        .collect()
+}
+
+/// Sets the enrolment key the hellos are signed with.
+pub fn set_enrolment_key(key: &str)
+{
+    if !key.is_empty() {
+        ENROLMENT_KEY.set(key.to_string()).ok();
+    }
+}
+
+/// Sends a hello, in clear text, to a machine, making the node key of the agent known to it.
+///
+/// The hello is signed with the enrolment key, if there is one, for the machine to trust the
+/// agent right away.
+///
+/// # Arguments
+///
+/// * `ip` - The IP address of the machine.
+/// * `port` - The port the machine listens on.
+/// * `reply` - Whether the hello answers a hello of the machine.
+pub fn send_hello(ip: IpAddr, port: u16, reply: bool) -> io::Result<()>
+{
+    let signature = ENROLMENT_KEY.get().map(|key| sign_hello(&identity().node_key(), ip, key));
+    let message = serde_json::to_vec(&PeerMessage::new(Payload::Hello { reply, signature }))?;
+    transport().send(&message, SocketAddr::new(ip, port))
+}
+
+/// Sends a hello to the machines whose node key is unknown.
+///
+/// # Arguments
+///
+/// * `port` - The port the other machines listen on.
+fn greet_peers(port: u16)
+{
+    let local = local_addresses();
+    for ip in recipients().into_iter().filter(|ip| !local.contains(ip) && trust::node_key_for_ip(*ip).is_none()) {
+        if let Err(e) = send_hello(ip, port, false) {
+            println!("Failed to greet {ip}: {e}");
+        }
+    }
+}
+
+/// Greets the machines whose node key is unknown now, then every `GREETING_INTERVAL`, so
+/// that the agents started at the same time, or later, still get to know each other.
+///
+/// # Arguments
+///
+/// * `port` - The port the other machines listen on.
+pub fn start_greeting(port: u16)
+{
+    thread::spawn(move || loop {
+        greet_peers(port);
+        thread::sleep(GREETING_INTERVAL);
+    });
+}
+
+/// Sends a message to the peers, sealed for the node key pinned for each of them.
+///
+/// The peers whose node key is unknown are sent a hello instead if `greet` is set, skipped
+/// otherwise, and the agent itself is skipped.
+///
+/// # Arguments
+///
+/// * `payload` - The content of the message.
+/// * `exclude` - The machines not to send the message to (e.g. the one being isolated).
+/// * `port` - The port the other machines listen on.
+/// * `greet` - Whether to greet the peers whose node key is unknown.
+///
+/// # Returns
+///
+/// * `Ok(Vec<IpAddr>)` - The machines the message was sent to.
+/// * `Err(io::Error)` - An error if the message could not be sealed.
+fn send_to_peers(payload: Payload, exclude: &[IpAddr], port: u16, greet: bool) -> io::Result<Vec<IpAddr>>
+{
+    let transport = transport();
+    let message = PeerMessage::new(payload);
+    let mut sent = Vec::new();
+
+    for ip in recipients().into_iter().filter(|ip| !exclude.contains(ip)) {
+        let Some(node) = trust::node_key_for_ip(ip) else {
+            if greet {
+                println!("Not sending a message to {ip}: its node key is unknown");
+                if let Err(e) = send_hello(ip, port, false) {
+                    println!("Failed to greet {ip}: {e}");
+                }
+            }
+            continue;
+        };
+        if node == message.node {
+            continue;
+        }
+        let datagram = channel::seal(&message, &node).map_err(io::Error::other)?;
+        // A peer out of reach (e.g. over TCP) must not keep the message from the others.
+        match transport.send(&datagram, SocketAddr::new(ip, port)) {
+            Ok(()) => sent.push(ip),
+            Err(e) => println!("Failed to send a message to {ip}: {e}"),
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Sends a message to a single node, sealed for its key.
+///
+/// # Arguments
+///
+/// * `payload` - The content of the message.
+/// * `node` - The key of the node.
+/// * `addr` - The address the node listens on.
+pub fn send_to_node(payload: Payload, node: &str, addr: SocketAddr) -> io::Result<()>
+{
+    let datagram = channel::seal(&PeerMessage::new(payload), node).map_err(io::Error::other)?;
+    transport().send(&datagram, addr)
+}
+
+/// Sends the state of the agent to the peers, sealed for each of them.
+///
+/// The peers whose node key is unknown are skipped: they are greeted by the other messages.
+///
+/// # Arguments
+///
+/// * `sync` - The state to send, fitting in a datagram.
+/// * `port` - The port the other machines listen on.
+///
+/// # Returns
+///
+/// * `Ok(Vec<IpAddr>)` - The machines the state was sent to.
+/// * `Err(io::Error)` - An error if the state could not be sent.
+pub fn broadcast_state(sync: StateSync, port: u16) -> io::Result<Vec<IpAddr>>
+{
+    send_to_peers(Payload::Sync(sync), &[], port, false)
+}
 
 /// Broadcasts the provided IP address to all other machines in the state.
 ///
-/// This function sends the provided IP address to all other machines except the local machine.
-/// It uses UDP to send the IP address to each machine in the list of machines stored in the state,
-/// on port `21335`. The function is typically used when an unusual action is detected
+/// This function sends a signed isolation request for the provided IP address to all other
+/// machines except the local machine, the machines retired from the inventory, the pending
+/// peers and the revoked nodes. It uses the transport to send the message to each machine in the list
+/// of machines stored in the state, on the given port, sealed for the node key pinned for the
+/// machine so that only it can read the request. The machines whose node key is unknown are
+/// sent a hello instead. The function is typically used when an unusual action is detected
 /// and needs to be communicated to other machines.
 ///
+/// The request is sent as a `Gossip`, so that the peers relay it to the machines the local
+/// machine cannot reach, up to `ttl` times.
+///
 /// # Arguments
 ///
 /// * `text` - The IP address to broadcast to the other machines.
+/// * `severity` - The severity of the incident behind the isolation, if any.
+/// * `port` - The port the other machines listen on.
+/// * `ttl` - The number of times the request can be relayed.
 ///
 /// # Returns
 ///
 /// This function returns a `io::Result<()>` indicating whether the broadcasting was successful.
 /// 
 /// * `Ok(())` if the broadcast was successfully sent.
-/// * `Err(io::Error)` if there was an error while sending the UDP message.
-pub fn broadcast(text: &IpAddr) -> io::Result<()>
+/// * `Err(io::Error)` if there was an error while sending the message.
+pub fn broadcast(text: &IpAddr, severity: Option<Severity>, port: u16, ttl: u8) -> io::Result<()>
 {
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-
     println!("Unusual action detected. Broadcasting info.");
+    let message = PeerMessage::new(Payload::Isolate { ip: *text, severity });
+    let gossip = Gossip { message: Box::new(message), ttl, hops: Vec::new() };
+    send_to_peers(Payload::Gossip(gossip), &[*text], port, true)?;
+    Ok(())
+}
 
-    for machine in state::get_machines() {
-        if IpAddr::from_str(&machine.ip).unwrap() != *text {
-            let server = SocketAddr::new(IpAddr::from_str(&machine.ip).unwrap(), 21335);
-            socket.send_to(text.to_string().as_bytes(), server)?;
-        }
-    }
+/// Relays a gossip to the peers, but the machine to isolate and the nodes the gossip comes from.
+///
+/// # Arguments
+///
+/// * `gossip` - The gossip to relay, with the local node recorded in its hops.
+/// * `target` - The IP address of the machine to isolate.
+/// * `port` - The port the other machines listen on.
+fn relay(gossip: Gossip, target: IpAddr, port: u16) -> io::Result<Vec<IpAddr>>
+{
+    let mut exclude = vec![target];
+    exclude.extend(std::iter::once(&gossip.message.node).chain(&gossip.hops).filter_map(|node| trust::node_ip(node)));
+    send_to_peers(Payload::Gossip(gossip), &exclude, port, false)
+}
 
-    Ok(())
+/// Broadcasts the release of a machine to the other machines, the same way as `broadcast`.
+///
+/// The machine released is sent the release too, so that its agent marks it as connected
+/// again. Each peer confirms the release with a `Payload::ReleaseAck` carrying `id`.
+///
+/// # Arguments
+///
+/// * `ip` - The IP address of the machine released.
+/// * `id` - The identifier of the release.
+/// * `operator` - Whether an operator issued the release.
+/// * `port` - The port the other machines listen on.
+///
+/// # Returns
+///
+/// * `Ok(Vec<IpAddr>)` - The machines the release was sent to, which should confirm it.
+/// * `Err(io::Error)` - An error if the release could not be sent.
+pub fn broadcast_release(ip: IpAddr, id: &str, operator: bool, port: u16) -> io::Result<Vec<IpAddr>>
+{
+    send_to_peers(Payload::Release { ip, id: id.to_string(), operator }, &[], port, true)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::inventory::Host;
+    use crate::transport::MemoryNetwork;
 
     #[test]
     fn test_broadcast_function() {
+        let _guard = state::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         state::from_list(vec![
-            IpAddr::from_str("127.0.0.1").unwrap()
-        ]);
+            Host::from_ip(IpAddr::from_str("127.0.0.1").unwrap())
+        ], "AA:BB:CC:DD:EE:FF");
 
         let ip_to_broadcast = IpAddr::from_str("192.168.1.3").unwrap();
-        let result = broadcast(&ip_to_broadcast);
+        let result = broadcast(&ip_to_broadcast, Some(Severity::High), 21335, 3);
 
         assert!(result.is_ok());
 
     }
+
+    #[test]
+    fn test_peer_message_signature() {
+        let message = PeerMessage::new(Payload::Isolate { ip: "10.0.0.2".parse().unwrap(), severity: None });
+        assert_eq!(message.node, identity().node_key());
+        assert!(message.verify(timestamp()).is_ok());
+
+        let json = serde_json::to_string(&message).unwrap();
+        let mut tampered: PeerMessage = serde_json::from_str(&json.replace("10.0.0.2", "10.0.0.3")).unwrap();
+        assert!(tampered.verify(timestamp()).is_err());
+
+        tampered = message.clone();
+        tampered.timestamp += 1;
+        assert!(tampered.verify(timestamp()).is_err());
+
+        assert!(message.verify(message.timestamp + MAX_CLOCK_SKEW + 1).is_err());
+    }
+
+    #[test]
+    fn test_read_datagram() {
+        let now = timestamp();
+        let isolate = PeerMessage::new(Payload::Isolate { ip: "10.0.0.2".parse().unwrap(), severity: None });
+        let hello = PeerMessage::new(Payload::Hello { reply: false, signature: None });
+
+        let sealed = channel::seal(&isolate, &identity().node_key()).unwrap();
+        assert_eq!(read_datagram(&sealed, now), Ok(isolate.clone()));
+        assert_eq!(read_datagram(&serde_json::to_vec(&hello).unwrap(), now), Ok(hello));
+
+        // Isolation requests must be encrypted, and signed.
+        let clear = serde_json::to_vec(&isolate).unwrap();
+        assert_eq!(read_datagram(&clear, now), Err("was sent in clear text instead of the encrypted channel".to_string()));
+        assert!(read_datagram(b"10.0.0.2", now).is_err());
+    }
+
+    #[test]
+    fn test_gossip() {
+        let now = timestamp();
+        let node = identity().node_key();
+        let relay = crate::identity::Identity::generate().unwrap().node_key();
+        let message = PeerMessage::new(Payload::Isolate { ip: "10.0.0.2".parse().unwrap(), severity: None });
+        let gossip = Gossip { message: Box::new(message.clone()), ttl: 1, hops: Vec::new() };
+
+        // Sent by the node that signed the message, then by each relay in turn.
+        assert!(gossip.verify(&node, now).is_ok());
+        assert_eq!(gossip.verify(&relay, now), Err("was not relayed by its sender".to_string()));
+
+        let relayed = gossip.relayed(&relay).unwrap();
+        assert_eq!((relayed.ttl, relayed.hops.clone()), (0, vec![relay.clone()]));
+        assert!(relayed.verify(&relay, now).is_ok());
+        assert!(relayed.verify(&node, now).is_err());
+        assert!(relayed.relayed(&node).is_none());
+
+        // Only signed isolation requests are relayed.
+        let mut tampered = gossip.clone();
+        tampered.message.timestamp += 1;
+        assert!(tampered.verify(&node, now).is_err());
+        let hello = Gossip { message: Box::new(PeerMessage::new(Payload::Hello { reply: false, signature: None })), ttl: 1, hops: Vec::new() };
+        assert!(hello.verify(&node, now).is_err());
+
+        let mut looping = gossip.clone();
+        looping.ttl = u8::MAX;
+        looping.hops = vec![node.clone(); MAX_HOPS];
+        assert!(looping.relayed(&node).is_none());
+    }
+
+    #[test]
+    fn test_network_watcher() {
+        let network = MemoryNetwork::default();
+        let peer = network.bind("10.0.0.1:21335".parse().unwrap());
+        let agent: SocketAddr = "10.0.0.2:21335".parse().unwrap();
+
+        let (sender, requests) = mpsc::channel();
+        let callback: NetCallback = Arc::new(Mutex::new(Box::new(move |ip, _, src, node: &str, hops: &[String]| {
+            sender.send((ip, src, node.to_string(), hops.len())).unwrap();
+        })));
+        start_network_watcher(Arc::new(network.bind(agent)), 21335, ListenerSettings::default(), callback, None);
+
+        // A request is acted on once, replayed or not, and a forged one is dropped.
+        let message = PeerMessage::new(Payload::Isolate { ip: "10.0.0.9".parse().unwrap(), severity: None });
+        let datagram = channel::seal(&message, &identity().node_key()).unwrap();
+        peer.send(&datagram, agent).unwrap();
+        peer.send(&datagram, agent).unwrap();
+        peer.send(b"{}", agent).unwrap();
+
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(request, ("10.0.0.9".parse().unwrap(), "10.0.0.1:21335".parse().unwrap(), message.node, 0));
+        assert!(requests.recv_timeout(Duration::from_millis(200)).is_err());
+    }
 }
@@ -0,0 +1,336 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, fs::{self, File}, io, path::Path};
+
+use crate::procnet::{parse_proc_net_tcp, ConnState};
+
+/// Basic information about a running process, as read from `/proc`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    /// The PID of the process.
+    pub pid: u32,
+    /// The name of the process (`/proc/<pid>/comm`).
+    pub name: String,
+    /// The command line of the process, arguments separated by spaces.
+    pub cmdline: String,
+}
+
+/// The forensic context of the process responsible for a detection.
+///
+/// This is everything an investigator would want to look at first, read from `/proc` at
+/// the time of the detection since the process may be gone by the time someone looks.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ProcessContext {
+    /// The PID of the process.
+    pub pid: u32,
+    /// The name of the process.
+    pub name: String,
+    /// The command line of the process, arguments separated by spaces.
+    pub cmdline: String,
+    /// The path of the executable of the process.
+    pub exe: Option<String>,
+    /// The SHA-256 of the executable of the process.
+    pub exe_sha256: Option<String>,
+    /// The real user ID of the process.
+    pub uid: Option<u32>,
+    /// The name of the user running the process.
+    pub user: Option<String>,
+    /// The working directory of the process.
+    pub cwd: Option<String>,
+    /// The parent, grand-parent, ... of the process, up to `init`.
+    pub ancestors: Vec<ProcessInfo>,
+    /// The network sockets of the process (e.g. `tcp 10.0.0.2:40000 -> 10.0.0.9:445 SYN_SENT`).
+    pub sockets: Vec<String>,
+}
+
+/// Reads the information of the process with the given PID from `/proc`.
+///
+/// # Arguments
+///
+/// * `pid` - The PID of the process.
+///
+/// # Returns
+///
+/// * `Some(ProcessInfo)` if the process exists.
+/// * `None` if the process is gone or `/proc` could not be read.
+pub fn process_info(pid: u32) -> Option<ProcessInfo>
+{
+    let name = fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).unwrap_or_default();
+
+    Some(ProcessInfo {
+        pid,
+        name: name.trim().to_string(),
+        cmdline: String::from_utf8_lossy(&cmdline)
+            .split('\0')
+            .filter(|arg| !arg.is_empty())
+            .collect::<Vec<_>>()
+            .join(" "),
+    })
+}
+
+/// Reads the parent PID of a process from `/proc/<pid>/stat`.
+fn parent_pid(pid: u32) -> Option<u32>
+{
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+
+    // The name of the process is between parentheses and may contain spaces.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Reads the real user ID of a process from `/proc/<pid>/status`.
+fn user_id(pid: u32) -> Option<u32>
+{
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Finds the name of a user in `/etc/passwd`.
+fn user_name(uid: u32) -> Option<String>
+{
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        (fields.get(2)?.parse::<u32>().ok()? == uid).then(|| fields[0].to_string())
+    })
+}
+
+/// Computes the SHA-256 of the executable of a process.
+///
+/// `/proc/<pid>/exe` is read rather than the path it points to, so that executables deleted
+/// after being started (a common trick) can still be hashed.
+fn exe_sha256(pid: u32) -> Option<String>
+{
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(format!("/proc/{pid}/exe")).ok()?, &mut hasher).ok()?;
+
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the inodes of the sockets held open by a process.
+fn socket_inodes(pid: u32) -> HashSet<u64>
+{
+    let Ok(fds) = fs::read_dir(format!("/proc/{pid}/fd")) else {
+        return HashSet::new();
+    };
+
+    fds.filter_map(|fd| {
+        let link = fs::read_link(fd.ok()?.path()).ok()?;
+        link.to_str()?.strip_prefix("socket:[")?.strip_suffix(']')?.parse().ok()
+    })
+    .collect()
+}
+
+/// Describes the TCP and UDP sockets held open by a process.
+///
+/// The socket tables are read from the network namespace of the process itself.
+fn sockets(pid: u32) -> Vec<String>
+{
+    let inodes = socket_inodes(pid);
+    if inodes.is_empty() {
+        return Vec::new();
+    }
+
+    let mut sockets = Vec::new();
+    for protocol in ["tcp", "tcp6", "udp", "udp6"] {
+        let Ok(content) = fs::read_to_string(format!("/proc/{pid}/net/{protocol}")) else {
+            continue;
+        };
+
+        for connection in parse_proc_net_tcp(&content) {
+            if !connection.inode.is_some_and(|inode| inodes.contains(&inode)) {
+                continue;
+            }
+
+            let state = match (protocol.starts_with("udp"), connection.state) {
+                (true, _) => "",
+                (false, ConnState::Listen) => " LISTEN",
+                (false, ConnState::SynSent) => " SYN_SENT",
+                (false, ConnState::Established) => " ESTABLISHED",
+                (false, ConnState::Other) => "",
+            };
+            sockets.push(format!(
+                "{} {} -> {}{state}",
+                protocol.trim_end_matches('6'),
+                connection.local,
+                connection.remote,
+            ));
+        }
+    }
+
+    sockets
+}
+
+/// Reads the forensic context of the process with the given PID from `/proc`.
+///
+/// # Arguments
+///
+/// * `pid` - The PID of the process.
+///
+/// # Returns
+///
+/// * `Some(ProcessContext)` if the process exists. Parts of the context that could not be
+///   read (e.g. the executable of a kernel thread) are left empty.
+/// * `None` if the process is gone.
+pub fn process_context(pid: u32) -> Option<ProcessContext>
+{
+    let info = process_info(pid)?;
+    let uid = user_id(pid);
+
+    let mut ancestors = Vec::new();
+    let mut current = parent_pid(pid);
+    while let Some(ppid) = current.filter(|ppid| *ppid != 0) {
+        let Some(parent) = process_info(ppid) else {
+            break;
+        };
+        ancestors.push(parent);
+        current = parent_pid(ppid);
+    }
+
+    Some(ProcessContext {
+        pid,
+        name: info.name,
+        cmdline: info.cmdline,
+        exe: fs::read_link(format!("/proc/{pid}/exe")).ok().map(|p| p.to_string_lossy().to_string()),
+        exe_sha256: exe_sha256(pid),
+        uid,
+        user: uid.and_then(user_name),
+        cwd: fs::read_link(format!("/proc/{pid}/cwd")).ok().map(|p| p.to_string_lossy().to_string()),
+        ancestors,
+        sockets: sockets(pid),
+    })
+}
+
+/// Returns the PIDs of all the processes currently running.
+pub fn list_pids() -> Vec<u32>
+{
+    fs::read_dir("/proc")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Finds the processes that currently hold the given file open.
+///
+/// This walks `/proc/<pid>/fd` for every process and compares the target of each file
+/// descriptor with `path`. Files that were deleted while open are matched too. The lookup
+/// is best effort: a process that already closed the file can't be found anymore.
+///
+/// # Arguments
+///
+/// * `path` - The path of the file to look for.
+///
+/// # Returns
+///
+/// * `Vec<ProcessInfo>` - The processes holding the file open, possibly empty.
+pub fn find_processes_using(path: &Path) -> Vec<ProcessInfo>
+{
+    let deleted = format!("{} (deleted)", path.display());
+
+    list_pids()
+        .into_iter()
+        .filter(|pid| {
+            let Ok(fds) = fs::read_dir(format!("/proc/{pid}/fd")) else {
+                return false;
+            };
+
+            fds.filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+                .any(|target| target == path || target.to_string_lossy() == deleted)
+        })
+        .filter_map(process_info)
+        .collect()
+}
+
+/// Finds the process owning the socket with the given inode.
+///
+/// Sockets show up in `/proc/<pid>/fd` as links to `socket:[<inode>]`, the inode being the
+/// one listed in `/proc/net/tcp`. Like `find_processes_using`, the lookup is best effort.
+///
+/// # Arguments
+///
+/// * `inode` - The inode of the socket.
+///
+/// # Returns
+///
+/// * `Some(ProcessInfo)` if a process holding the socket was found.
+/// * `None` otherwise.
+pub fn find_process_by_socket(inode: u64) -> Option<ProcessInfo>
+{
+    let target = format!("socket:[{inode}]");
+
+    list_pids()
+        .into_iter()
+        .find(|pid| {
+            let Ok(fds) = fs::read_dir(format!("/proc/{pid}/fd")) else {
+                return false;
+            };
+
+            fds.filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+                .any(|link| link.to_string_lossy() == target)
+        })
+        .and_then(process_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_process_info_self() {
+        let info = process_info(std::process::id()).unwrap();
+
+        assert_eq!(info.pid, std::process::id());
+        assert!(!info.name.is_empty());
+        assert!(!info.cmdline.is_empty());
+    }
+
+    #[test]
+    fn test_parent_pid_self() {
+        assert_eq!(parent_pid(std::process::id()), Some(std::os::unix::process::parent_id()));
+    }
+
+    #[test]
+    fn test_process_context_self() {
+        let context = process_context(std::process::id()).unwrap();
+
+        assert_eq!(context.pid, std::process::id());
+        assert!(context.exe.is_some());
+        assert_eq!(context.exe_sha256.as_ref().map(|h| h.len()), Some(64));
+        assert!(context.uid.is_some());
+        assert_eq!(context.cwd, std::env::current_dir().ok().map(|p| p.to_string_lossy().to_string()));
+        assert!(!context.ancestors.is_empty());
+        assert_eq!(context.ancestors[0].pid, std::os::unix::process::parent_id());
+    }
+
+    #[test]
+    fn test_process_context_sockets() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let context = process_context(std::process::id()).unwrap();
+        assert!(context.sockets.iter().any(|s| s.starts_with(&format!("tcp {addr} ")) && s.ends_with("LISTEN")));
+    }
+
+    #[test]
+    fn test_find_processes_using() {
+        let path = std::env::temp_dir().join("test_process_open_file");
+        let _file = File::create(&path).unwrap();
+        let path = path.canonicalize().unwrap();
+
+        let processes = find_processes_using(&path);
+        assert!(processes.iter().any(|p| p.pid == std::process::id()));
+    }
+}
@@ -0,0 +1,110 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The state of a connection, as far as the detections are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    Listen,
+    SynSent,
+    Established,
+    Other,
+}
+
+/// A connection read from `/proc/net/tcp`, `/proc/net/tcp6` or the conntrack table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connection {
+    /// The local end of the connection (the originator for conntrack entries).
+    pub local: SocketAddr,
+    /// The remote end of the connection.
+    pub remote: SocketAddr,
+    /// The state of the connection.
+    pub state: ConnState,
+    /// The inode of the socket, when known, to find the process owning it.
+    pub inode: Option<u64>,
+}
+
+/// Decodes an address of `/proc/net/tcp` or `/proc/net/tcp6` (e.g. `0100007F:0CEA`).
+///
+/// The kernel prints the address as 32-bit words in host byte order, followed by the port.
+fn parse_hex_addr(s: &str) -> Option<SocketAddr>
+{
+    let (addr, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let mut bytes = Vec::new();
+    for i in (0..addr.len()).step_by(8) {
+        let word = u32::from_str_radix(addr.get(i..i + 8)?, 16).ok()?;
+        bytes.extend(word.to_ne_bytes());
+    }
+
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Parses the content of `/proc/net/tcp` or `/proc/net/tcp6`.
+///
+/// # Arguments
+///
+/// * `content` - The content of the file.
+///
+/// # Returns
+///
+/// * `Vec<Connection>` - The connections listed, lines that can't be parsed being skipped.
+pub fn parse_proc_net_tcp(content: &str) -> Vec<Connection>
+{
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            Some(Connection {
+                local: parse_hex_addr(fields.get(1)?)?,
+                remote: parse_hex_addr(fields.get(2)?)?,
+                state: match u8::from_str_radix(fields.get(3)?, 16).ok()? {
+                    0x01 => ConnState::Established,
+                    0x02 => ConnState::SynSent,
+                    0x0A => ConnState::Listen,
+                    _ => ConnState::Other,
+                },
+                inode: fields.get(9)?.parse().ok(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TCP_IDLE_1: &str = include_str!("../testdata/fanout/tcp_idle_1.txt");
+    const TCP6_IDLE: &str = include_str!("../testdata/fanout/tcp6_idle.txt");
+
+    #[test]
+    fn test_parse_proc_net_tcp() {
+        let connections = parse_proc_net_tcp(TCP_IDLE_1);
+        assert_eq!(connections.len(), 5);
+
+        assert_eq!(connections[0].state, ConnState::Listen);
+        assert_eq!(connections[0].local, "0.0.0.0:22".parse().unwrap());
+        assert_eq!(connections[4], Connection {
+            local: "172.42.0.3:41822".parse().unwrap(),
+            remote: "172.42.0.4:21335".parse().unwrap(),
+            state: ConnState::Established,
+            inode: Some(2002),
+        });
+    }
+
+    #[test]
+    fn test_parse_proc_net_tcp6() {
+        let connections = parse_proc_net_tcp(TCP6_IDLE);
+        assert_eq!(connections.len(), 2);
+
+        assert_eq!(connections[1].local, "[2001:db8::1]:41906".parse().unwrap());
+        assert_eq!(connections[1].remote, "[2001:db8::2]:443".parse().unwrap());
+    }
+}
@@ -0,0 +1,281 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::alert::Severity;
+use crate::config::FirewallBackend;
+use crate::events::{record_event, Event, EventKind};
+use crate::identity::{random_bytes, to_hex};
+use crate::iptables::{lock_ip, unlock_ip};
+use crate::network::{broadcast, broadcast_release};
+use crate::state::{change_machine_state, clear_votes, machine_addresses, record_release, record_release_vote, set_locked, IsolationVote, Release};
+use crate::trust::short_key;
+use crate::utils::{local_addresses, timestamp};
+
+/// Runs the isolations and releases of machines.
+///
+/// Whether they come from an incident, a peer or an operator, isolations all go through
+/// the same steps: the machine is locked out by the firewall on all its IPv4 and IPv6
+/// addresses (unless it is the local machine), marked as isolated once it is and, when
+/// requested, broadcast to the other machines.
+#[derive(Debug, Clone, Copy)]
+pub struct Responder {
+    /// The IP address of the local machine, which is never locked out.
+    pub my_ip: IpAddr,
+    /// The firewall used to lock isolated machines out.
+    pub firewall: FirewallBackend,
+    /// The port the other machines listen on.
+    pub port: u16,
+    /// The number of times the peers relay the isolations broadcast (see `network::Gossip`).
+    pub gossip_ttl: u8,
+    /// The maximum number of machines locked out at the same time.
+    pub max_locked: usize,
+}
+
+impl Responder {
+    /// Returns the addresses of the machine having `ip` that the firewall locks, leaving out
+    /// the addresses of the local machine.
+    fn firewalled_addresses(&self, ip: IpAddr) -> Vec<IpAddr>
+    {
+        if self.firewall != FirewallBackend::Iptables {
+            return Vec::new();
+        }
+        let local = local_addresses();
+        machine_addresses(ip)
+            .into_iter()
+            .filter(|address| *address != self.my_ip && !local.contains(address))
+            .collect()
+    }
+
+    /// Isolates a machine.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - Any IP address of the machine to isolate.
+    /// * `notify` - Whether the isolation is broadcast to the other machines.
+    /// * `severity` - The severity of the incident behind the isolation, if any, sent along
+    ///   with the broadcast.
+    ///
+    /// # Returns
+    ///
+    /// * `true` if the machine was locked out on all its addresses and marked as isolated.
+    /// * `false` if one of them could not be locked: the ones locked are unlocked again, the
+    ///   machine keeps its status and the isolation is recorded as not enforced. It is still
+    ///   broadcast, for the other machines to lock it out.
+    pub fn isolate(&self, ip: IpAddr, notify: bool, severity: Option<Severity>) -> bool
+    {
+        let addresses = self.firewalled_addresses(ip);
+        let mut locked = Vec::new();
+        let mut failed = None;
+        for address in addresses.iter().copied() {
+            match lock_ip(address, self.max_locked) {
+                Ok(()) => locked.push(address),
+                Err(e) => {
+                    failed = Some((address, e.to_string()));
+                    break;
+                },
+            }
+        }
+
+        let enforced = match failed {
+            None => {
+                change_machine_state(&ip.to_string(), "isolated");
+                set_locked(&ip.to_string(), !addresses.is_empty());
+                true
+            },
+            Some((address, error)) => {
+                for address in locked {
+                    unlock_ip(address).ok();
+                }
+                println!("Isolation of {ip} not enforced: cannot lock {address} out ({error})");
+                record_event(
+                    Event::new(EventKind::Isolation, Some(ip), "firewall", format!("Isolation of {ip} not enforced: cannot lock {address} out ({error})"))
+                        .with_detail("enforced", false)
+                );
+                false
+            },
+        };
+
+        if notify {
+            broadcast(&ip, severity, self.port, self.gossip_ttl).ok();
+        }
+        enforced
+    }
+
+    /// Releases a machine from isolation, recording it in the event log.
+    ///
+    /// The votes of the peers for its isolation are forgotten, so that a new isolation needs
+    /// a new quorum.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address of the machine to release.
+    /// * `actor` - Who released the machine.
+    /// * `notify` - Whether the release is broadcast to the other machines. Only the
+    ///   releases of an operator are, and the peers apply them as such.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Release)` - The release broadcast, whose confirmations are recorded in `state`.
+    /// * `None` if the release was not broadcast.
+    pub fn release(&self, ip: IpAddr, actor: &str, notify: bool) -> Option<Release>
+    {
+        change_machine_state(&ip.to_string(), "connected");
+        for address in self.firewalled_addresses(ip) {
+            unlock_ip(address).ok();
+        }
+        set_locked(&ip.to_string(), false);
+        clear_votes(ip);
+        record_event(Event::new(EventKind::Release, Some(ip), actor, format!("Released {ip}")));
+
+        if !notify {
+            return None;
+        }
+
+        let id = to_hex(&random_bytes::<8>().ok()?);
+        match broadcast_release(ip, &id, true, self.port) {
+            Ok(sent_to) => {
+                let release = Release {
+                    id,
+                    ip,
+                    actor: actor.to_string(),
+                    timestamp: timestamp(),
+                    sent_to: sent_to.into_iter().filter(|peer| *peer != self.my_ip).collect(),
+                    confirmed_by: Vec::new(),
+                };
+                record_release(release.clone());
+                Some(release)
+            },
+            Err(e) => {
+                println!("Failed to broadcast the release of {ip}: {e}");
+                None
+            },
+        }
+    }
+
+    /// Handles the release of a machine asked for by a trusted peer.
+    ///
+    /// A release an operator issued on the peer takes effect right away: the signature of the
+    /// message vouches for its `operator` flag. Any other release is a vote, which takes effect
+    /// once `quorum` is reached.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address of the machine to release.
+    /// * `id` - The identifier of the release.
+    /// * `node` - The key of the node asking for the release.
+    /// * `src` - The address the release came from.
+    /// * `operator` - Whether an operator issued the release.
+    /// * `quorum` - The votes needed by the releases of the peers.
+    ///
+    /// # Returns
+    ///
+    /// * `true` if the machine was released, the release is then to be confirmed to `src`.
+    pub fn peer_release(&self, ip: IpAddr, id: &str, node: &str, src: SocketAddr, operator: bool, quorum: &Quorum) -> bool
+    {
+        let node_short = short_key(node);
+        let actor = format!("{src} (node {node_short})");
+        record_event(
+            Event::new(EventKind::Release, Some(ip), &actor, format!("Peer {src} asked for the release of {ip}"))
+                .with_detail("node", node)
+                .with_detail("release", id)
+                .with_detail("operator", operator)
+        );
+
+        if !operator {
+            // A single node, rogue or compromised, must not be able to unlock a machine everywhere.
+            let vote = IsolationVote { target: ip, node: node.to_string(), src: src.ip(), severity: None, timestamp: timestamp() };
+            let votes = record_release_vote(vote, quorum.window);
+            if !quorum.is_reached(votes, None) {
+                println!("Release of {ip} voted by node {node_short}: waiting for {} votes", quorum.votes);
+                return false;
+            }
+        }
+        self.release(ip, &actor, false);
+        true
+    }
+}
+
+/// Decides when the isolations requested by the peers take effect.
+///
+/// A single node, rogue or compromised, must not be able to make every agent lock a machine
+/// out: a peer-requested isolation takes effect once `votes` distinct trusted nodes asked for
+/// it within `window`, or right away if the severity they sent is `immediate_from` or above.
+/// Nor unlock it everywhere: a peer-requested release needs the same `votes`, unless an
+/// operator issued it.
+#[derive(Debug, Clone, Copy)]
+pub struct Quorum {
+    /// The number of distinct nodes that must vote for an isolation.
+    pub votes: usize,
+    /// How long, in seconds, the votes count.
+    pub window: u64,
+    /// The lowest severity isolated on a single vote.
+    pub immediate_from: Severity,
+}
+
+impl Default for Quorum {
+    fn default() -> Self
+    {
+        Quorum { votes: 1, window: 300, immediate_from: Severity::Critical }
+    }
+}
+
+impl Quorum {
+    /// Checks whether a vote makes an isolation take effect.
+    ///
+    /// # Arguments
+    ///
+    /// * `votes` - The number of distinct nodes voting for the isolation, `None` if the vote
+    ///   only renews an earlier vote of its node (see `state::record_vote`).
+    /// * `severity` - The severity sent with the vote, if any.
+    ///
+    /// # Returns
+    ///
+    /// * `true` if the machine is to be isolated.
+    pub fn is_reached(&self, votes: Option<usize>, severity: Option<Severity>) -> bool
+    {
+        let Some(votes) = votes else {
+            return false;
+        };
+        votes == self.votes || severity.is_some_and(|severity| severity >= self.immediate_from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state;
+
+    #[test]
+    fn test_quorum() {
+        let quorum = Quorum { votes: 2, window: 300, immediate_from: Severity::Critical };
+
+        assert!(!quorum.is_reached(Some(1), None));
+        assert!(!quorum.is_reached(Some(1), Some(Severity::High)));
+        assert!(quorum.is_reached(Some(1), Some(Severity::Critical)));
+        assert!(quorum.is_reached(Some(2), None));
+        // Isolated once, when the quorum is reached, not again on every vote.
+        assert!(!quorum.is_reached(Some(3), None));
+        assert!(!quorum.is_reached(None, Some(Severity::Critical)));
+
+        assert!(Quorum::default().is_reached(Some(1), None));
+    }
+
+    #[test]
+    fn test_peer_release() {
+        let _guard = state::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let responder = Responder { my_ip: "10.70.3.254".parse().unwrap(), firewall: FirewallBackend::None, port: 21336, gossip_ttl: 3, max_locked: 256 };
+        let quorum = Quorum { votes: 2, window: 300, immediate_from: Severity::Critical };
+        let ip: IpAddr = "10.70.3.1".parse().unwrap();
+        let src: SocketAddr = "10.70.3.2:1234".parse().unwrap();
+        state::add_discovered("10.70.3.1", "", "", true);
+        assert!(responder.isolate(ip, false, None));
+        assert_eq!(state::find_machine("10.70.3.1").map(|m| m.status).as_deref(), Some("isolated"));
+
+        // The release of a peer is a vote, short of the quorum.
+        assert!(!responder.peer_release(ip, "r1", "node-a", src, false, &quorum));
+        assert_eq!(state::find_machine("10.70.3.1").map(|m| m.status).as_deref(), Some("isolated"));
+
+        // The release of an operator takes effect on its own.
+        assert!(responder.peer_release(ip, "r2", "node-b", src, true, &quorum));
+        assert_eq!(state::find_machine("10.70.3.1").map(|m| m.status).as_deref(), Some("connected"));
+    }
+}
@@ -1,7 +1,105 @@
 use serde::{Serialize, Deserialize};
-use std::{net::IpAddr, sync::{Arc, Mutex}};
+use std::{fs, io, net::IpAddr, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
 use once_cell::sync::Lazy;
 
+use crate::alert::Severity;
+use crate::identity::identity;
+use crate::inventory::Host;
+
+/// The version of the status of a machine, replicated between the agents.
+///
+/// The status of a machine is a last-writer-wins register: every change is stamped with the
+/// Lamport clock of the node that made it, the node key breaking ties, and the agents keep
+/// the status with the highest version. They all converge to the same status, whatever the
+/// order the changes reach them in.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    /// The Lamport clock of the node when it changed the status.
+    pub counter: u64,
+    /// The key of the node that changed the status.
+    pub node: String,
+}
+
+/// The status of a machine, as replicated between the agents.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MachineStatus {
+    /// The IP address of the machine.
+    pub ip: String,
+    /// The status of the machine (e.g., "connected", "isolated").
+    pub status: String,
+    /// The version of the status.
+    pub version: Version,
+}
+
+/// The default path of the file the Lamport clock is saved to.
+pub const DEFAULT_CLOCK_FILE: &str = "./clock";
+
+/// The Lamport clock of the agent, ticking on every status change and moving past the
+/// versions received from the peers.
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+
+/// The file the Lamport clock is saved to, once opened.
+static CLOCK_FILE: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// Opens the file of the Lamport clock, moving the clock past the value it holds.
+///
+/// The clock is saved every time it moves, so that the changes made after a restart win
+/// over the ones made, or received from the peers, before it.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the file.
+///
+/// # Returns
+///
+/// * `Ok(u64)` - The value of the clock, `0` if the file doesn't exist yet.
+/// * `Err(io::Error)` - An error if the file cannot be read or parsed.
+pub fn open_clock(filename: &str) -> io::Result<u64>
+{
+    let counter = match fs::read_to_string(filename) {
+        Ok(content) => content.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{filename}: {e}")))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    CLOCK.fetch_max(counter, Ordering::SeqCst);
+    *CLOCK_FILE.lock().unwrap() = Some(filename.to_string());
+    Ok(CLOCK.load(Ordering::SeqCst))
+}
+
+/// Saves the Lamport clock to its file, if one is open.
+///
+/// The file is written next to the previous one and renamed over it, so that a crash never
+/// leaves a truncated clock.
+fn save_clock()
+{
+    let file = CLOCK_FILE.lock().unwrap();
+    let Some(filename) = file.as_ref() else {
+        return;
+    };
+
+    let tmp = format!("{filename}.tmp");
+    let result = fs::write(&tmp, CLOCK.load(Ordering::SeqCst).to_string()).and_then(|()| fs::rename(&tmp, filename));
+    if let Err(e) = result {
+        println!("Failed to save the Lamport clock to {filename}: {e}");
+    }
+}
+
+/// Advances the Lamport clock for a local change.
+fn tick() -> u64
+{
+    let counter = CLOCK.fetch_add(1, Ordering::SeqCst) + 1;
+    save_clock();
+    counter
+}
+
+/// Moves the Lamport clock past a version received from a peer.
+fn observe(counter: u64)
+{
+    if CLOCK.fetch_max(counter, Ordering::SeqCst) < counter {
+        save_clock();
+    }
+}
+
 /// Represents a machine in the network.
 ///
 /// This struct contains the details of a machine, including its ID, name, IP address,
@@ -20,7 +118,118 @@ pub struct Machine {
     /// The last time the machine was updated.
     pub last_update: String,
     /// The current status of the machine (e.g., "connected", "isolated").
+    ///
+    /// It is the status of the machine in the cluster, replicated between the agents: the
+    /// last agent to change it wins, whatever the local firewall enforces (see `locked`).
     pub status: String,
+    /// Whether the firewall of the local agent locks the machine out.
+    ///
+    /// Unlike the status, it is never replicated: a peer releasing or isolating the machine
+    /// changes its status, not the rules of the local firewall.
+    #[serde(default)]
+    pub locked: bool,
+    /// The DNS name of the machine, if it is listed by name in the inventory.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Free-form tags from the inventory.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The role of the machine, from the inventory.
+    #[serde(default)]
+    pub role: Option<String>,
+    /// Who is responsible for the machine, from the inventory.
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Whether the machine was removed from the inventory since the agent started.
+    ///
+    /// Retired machines are kept, with their status, so that their history stays available,
+    /// but they are no longer sent the isolation broadcasts.
+    #[serde(default)]
+    pub retired: bool,
+    /// Whether the machine is an agent found by the discovery rather than listed in the inventory.
+    #[serde(default)]
+    pub discovered: bool,
+    /// Whether the machine is a discovered agent waiting for approval.
+    ///
+    /// Pending peers are not trusted to send isolation orders, nor sent the isolation broadcasts.
+    #[serde(default)]
+    pub pending: bool,
+    /// The node key the agent of the machine signs its messages with, once known.
+    #[serde(default)]
+    pub key: Option<String>,
+    /// The version of the status, if it was changed since the agents started.
+    #[serde(default)]
+    pub version: Option<Version>,
+    /// The other IP addresses of the machine, from the inventory or, for the local machine,
+    /// from its network interfaces.
+    ///
+    /// The machine is known by any of them, and isolating it locks them all.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// The IPv6 addresses the neighbour tables (NDP) give for the MAC address of the machine.
+    ///
+    /// Any machine of the segment can forge them, so they are only shown: the machine is not
+    /// known by them, nor are they locked when it is isolated, unless the inventory lists them.
+    #[serde(default)]
+    pub neighbour_addresses: Vec<String>,
+}
+
+impl Machine {
+    /// Creates a connected machine from a host of the inventory.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier of the machine.
+    /// * `host` - The host of the inventory.
+    /// * `mac` - The MAC address shown if the inventory doesn't give one.
+    fn from_host(id: usize, host: Host, mac: &str) -> Self
+    {
+        let mut machine = Machine {
+            id: id.to_string(),
+            name: String::new(),
+            ip: host.ip.to_string(),
+            mac: String::new(),
+            last_update: "N/A".to_string(),
+            status: "connected".to_string(),
+            locked: false,
+            hostname: None,
+            tags: Vec::new(),
+            role: None,
+            owner: None,
+            retired: false,
+            discovered: false,
+            pending: false,
+            key: None,
+            version: None,
+            addresses: Vec::new(),
+            neighbour_addresses: Vec::new(),
+        };
+        machine.apply_host(host, mac);
+        machine
+    }
+
+    /// Updates the metadata of the machine from its host of the inventory.
+    ///
+    /// A hostname discovered earlier is kept if the inventory doesn't give one.
+    fn apply_host(&mut self, host: Host, mac: &str)
+    {
+        let hostname = host.hostname.or(self.hostname.take());
+        self.name = host.name.or(hostname.clone()).unwrap_or_else(|| self.id.clone());
+        self.mac = host.mac.unwrap_or_else(|| mac.to_string());
+        self.hostname = hostname;
+        self.tags = host.tags;
+        self.role = host.role;
+        self.owner = host.owner;
+        self.addresses = host.addresses.iter().map(IpAddr::to_string).collect();
+        let addresses = &self.addresses;
+        self.neighbour_addresses.retain(|address| !addresses.contains(address));
+    }
+
+    /// Checks whether the machine has an IP address, its main one or another.
+    pub fn has_address(&self, ip: &str) -> bool
+    {
+        self.ip == ip || self.addresses.iter().any(|address| address == ip)
+    }
 }
 
 /// A globally accessible, thread-safe vector holding machines in the system.
@@ -44,10 +253,31 @@ pub fn get_machines() -> Vec<Machine>
     machines.clone()
 }
 
-/// Changes the state of a machine identified by its IP address.
+/// Finds a machine by any of its IP addresses or its name.
+///
+/// # Arguments
+///
+/// * `ip_or_name` - The IP address or the name of the machine.
+///
+/// # Returns
+///
+/// * `Some(Machine)` - A clone of the machine found.
+/// * `None` if no machine has this IP address or name.
+pub fn find_machine(ip_or_name: &str) -> Option<Machine>
+{
+    let machines = MACHINES.lock().unwrap();
+    machines
+        .iter()
+        .find(|m| m.has_address(ip_or_name))
+        .or_else(|| machines.iter().find(|m| m.name.eq_ignore_ascii_case(ip_or_name)))
+        .cloned()
+}
+
+/// Changes the state of a machine identified by any of its IP addresses.
 ///
 /// This function locates the machine with the given IP address and updates its `status`
-/// field to the new status provided.
+/// field to the new status provided, stamping it with a new version so that the change
+/// wins over the older ones when the state is synchronized with the peers.
 ///
 /// # Arguments
 ///
@@ -56,50 +286,526 @@ pub fn get_machines() -> Vec<Machine>
 pub fn change_machine_state(ip: &str, new_status: &str)
 {
     let mut machines = MACHINES.lock().unwrap();
-    if let Some(machine) = machines.iter_mut().find(|m| m.ip == ip) {
+    if let Some(machine) = machines.iter_mut().find(|m| m.has_address(ip)) {
         machine.status = new_status.to_string();
+        machine.version = Some(Version { counter: tick(), node: identity().node_key() });
+    }
+}
+
+/// Records whether the local firewall locks a machine identified by any of its IP addresses out.
+///
+/// # Arguments
+///
+/// * `ip` - The IP address of the machine.
+/// * `locked` - Whether the machine is locked out.
+pub fn set_locked(ip: &str, locked: bool)
+{
+    let mut machines = MACHINES.lock().unwrap();
+    if let Some(machine) = machines.iter_mut().find(|m| m.has_address(ip)) {
+        machine.locked = locked;
+    }
+}
+
+/// Returns the statuses of the machines changed since the agents started, to be sent to the peers.
+pub fn machine_statuses() -> Vec<MachineStatus>
+{
+    let machines = MACHINES.lock().unwrap();
+    machines
+        .iter()
+        .filter_map(|m| Some(MachineStatus { ip: m.ip.clone(), status: m.status.clone(), version: m.version.clone()? }))
+        .collect()
+}
+
+/// Merges the statuses received from a peer: each machine keeps the status with the highest version.
+///
+/// Only the statuses are merged, the machines the local firewall locks out staying locked
+/// until the agent releases them (see `Machine::locked`). The machines the agent doesn't know are ignored, the inventory deciding which machines
+/// are part of the cluster.
+///
+/// # Arguments
+///
+/// * `statuses` - The statuses received.
+///
+/// # Returns
+///
+/// * `usize` - The number of machines whose status changed.
+pub fn merge_machine_statuses(statuses: Vec<MachineStatus>) -> usize
+{
+    let mut machines = MACHINES.lock().unwrap();
+    let mut changed = 0;
+
+    for status in statuses {
+        observe(status.version.counter);
+        let Some(machine) = machines.iter_mut().find(|m| m.ip == status.ip) else {
+            continue;
+        };
+        if machine.version.as_ref().is_some_and(|version| *version >= status.version) {
+            continue;
+        }
+        if machine.status != status.status {
+            changed += 1;
+        }
+        machine.status = status.status;
+        machine.version = Some(status.version);
+    }
+    changed
+}
+
+/// Updates what was discovered about a machine: its MAC address and its hostname.
+///
+/// A machine the inventory doesn't name is renamed after the hostname discovered.
+///
+/// # Arguments
+///
+/// * `ip` - The IP address of the machine.
+/// * `mac` - The MAC address discovered, if any.
+/// * `hostname` - The hostname discovered, if any.
+pub fn update_discovered(ip: &str, mac: Option<&str>, hostname: Option<&str>)
+{
+    let mut machines = MACHINES.lock().unwrap();
+    if let Some(machine) = machines.iter_mut().find(|m| m.has_address(ip)) {
+        if let Some(mac) = mac {
+            machine.mac = mac.to_string();
+        }
+        if let Some(hostname) = hostname {
+            if machine.name == machine.id || machine.hostname.as_ref().is_some_and(|h| *h == machine.name) {
+                machine.name = hostname.to_string();
+            }
+            machine.hostname = Some(hostname.to_string());
+        }
+    }
+}
+
+/// Checks whether a machine is trusted to send isolation orders.
+///
+/// The machines of the inventory and the approved peers are trusted. Retired machines,
+/// pending peers and unknown addresses are not.
+///
+/// # Arguments
+///
+/// * `ip` - The IP address of the machine.
+pub fn is_trusted(ip: &str) -> bool
+{
+    let machines = MACHINES.lock().unwrap();
+    machines.iter().any(|m| m.has_address(ip) && !m.retired && !m.pending)
+}
+
+/// Returns all the IP addresses of the machine having an address, its main one first.
+///
+/// # Arguments
+///
+/// * `ip` - Any IP address of the machine.
+///
+/// # Returns
+///
+/// * `Vec<IpAddr>` - The addresses of the machine, or only `ip` if no machine has it.
+pub fn machine_addresses(ip: IpAddr) -> Vec<IpAddr>
+{
+    let machines = MACHINES.lock().unwrap();
+    let Some(machine) = machines.iter().find(|m| m.has_address(&ip.to_string())) else {
+        return vec![ip];
+    };
+    std::iter::once(&machine.ip)
+        .chain(&machine.addresses)
+        .filter_map(|address| address.parse().ok())
+        .collect()
+}
+
+/// Where an IP address added to a machine was learned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSource {
+    /// The network interfaces of the local machine: the address is certain.
+    Local,
+    /// The neighbour tables (NDP), which any machine of the segment can forge.
+    Neighbour,
+}
+
+/// Adds an IP address learned for the machine with a MAC address, such as one of its IPv6 addresses.
+///
+/// The address is only added if no machine has it yet. The local addresses are added to
+/// `Machine::addresses`, the ones of the neighbour tables to `Machine::neighbour_addresses`.
+///
+/// # Arguments
+///
+/// * `mac` - The MAC address of the machine.
+/// * `ip` - The address learned.
+/// * `source` - Where the address was learned.
+///
+/// # Returns
+///
+/// * `true` if the address was added to a machine.
+pub fn add_address(mac: &str, ip: IpAddr, source: AddressSource) -> bool
+{
+    let mut machines = MACHINES.lock().unwrap();
+    let ip = ip.to_string();
+    if mac.is_empty() || machines.iter().any(|m| m.has_address(&ip) || m.neighbour_addresses.contains(&ip)) {
+        return false;
+    }
+    let mut owners = machines.iter_mut().filter(|m| !m.retired && m.mac.eq_ignore_ascii_case(mac));
+    match (owners.next(), owners.next()) {
+        (Some(machine), None) => {
+            match source {
+                AddressSource::Local => machine.addresses.push(ip),
+                AddressSource::Neighbour => machine.neighbour_addresses.push(ip),
+            }
+            true
+        },
+        // The MAC address shown for the machines the inventory gives none is shared, and proves nothing.
+        _ => false,
     }
 }
 
-/// Initializes the `MACHINES` list from a given list of IP addresses.
+/// Adds an agent found by the discovery to the `MACHINES` list.
+///
+/// # Arguments
+///
+/// * `ip` - The IP address of the agent.
+/// * `hostname` - The hostname the agent reported, used as its name.
+/// * `key` - The node key the agent signed its announcement with.
+/// * `approved` - Whether the agent is trusted right away, or pending until approved.
+///
+/// # Returns
+///
+/// * `true` if the agent was added, `false` if a machine with this IP address already exists.
+pub fn add_discovered(ip: &str, hostname: &str, key: &str, approved: bool) -> bool
+{
+    let mut machines = MACHINES.lock().unwrap();
+    if machines.iter().any(|m| m.has_address(ip)) {
+        return false;
+    }
+
+    let id = machines.iter().filter_map(|m| m.id.parse::<usize>().ok()).max().unwrap_or(0) + 1;
+    let hostname = Some(hostname.to_string()).filter(|h| !h.is_empty());
+    machines.push(Machine {
+        id: id.to_string(),
+        name: hostname.clone().unwrap_or_else(|| id.to_string()),
+        ip: ip.to_string(),
+        mac: String::new(),
+        last_update: "N/A".to_string(),
+        status: "connected".to_string(),
+        locked: false,
+        hostname,
+        tags: Vec::new(),
+        role: None,
+        owner: None,
+        retired: false,
+        discovered: true,
+        pending: !approved,
+        key: Some(key.to_string()),
+        version: None,
+        addresses: Vec::new(),
+        neighbour_addresses: Vec::new(),
+    });
+    true
+}
+
+/// Approves a pending peer, which is then trusted.
 ///
-/// This function populates the `MACHINES` vector with a machine entry for each IP address
-/// provided in `ip_list`. The machines are initialized with default values for `name`, `mac`,
-/// `last_update`, and `status`. The machine `id` is generated based on the index in the list.
+/// # Arguments
+///
+/// * `ip` - The IP address of the peer.
+///
+/// # Returns
+///
+/// * `Some(Machine)` - The peer, if it was pending.
+/// * `None` if there is no pending peer with this IP address.
+pub fn approve_peer(ip: &str) -> Option<Machine>
+{
+    let mut machines = MACHINES.lock().unwrap();
+    let machine = machines.iter_mut().find(|m| m.ip == ip && m.pending)?;
+    machine.pending = false;
+    Some(machine.clone())
+}
+
+/// Rejects a pending peer, removing it from the `MACHINES` list.
+///
+/// The peer is added again as pending if it keeps announcing itself.
 ///
 /// # Arguments
 ///
-/// * `ip_list` - A list of `IpAddr` values representing the IP addresses of the machines.
-pub fn from_list(ip_list: Vec<IpAddr>)
+/// * `ip` - The IP address of the peer.
+///
+/// # Returns
+///
+/// * `true` if the peer was pending, `false` otherwise.
+pub fn reject_peer(ip: &str) -> bool
 {
     let mut machines = MACHINES.lock().unwrap();
-    *machines = ip_list
+    let before = machines.len();
+    machines.retain(|m| !(m.ip == ip && m.pending));
+    machines.len() != before
+}
+
+/// Initializes the `MACHINES` list from the hosts of the inventory.
+///
+/// This function populates the `MACHINES` vector with a machine entry for each host
+/// provided in `host_list`. The machines take the metadata of the inventory (name, tags,
+/// role, owner, MAC address) and are initialized with default values for `last_update`
+/// and `status`. The machine `id` is generated based on the index in the list, and is also
+/// the name of the machines the inventory doesn't name.
+///
+/// # Arguments
+///
+/// * `host_list` - The hosts of the inventory.
+/// * `mac` - The MAC address shown for the machines whose MAC address is not in the inventory.
+pub fn from_list(host_list: Vec<Host>, mac: &str)
+{
+    let mut machines = MACHINES.lock().unwrap();
+    *machines = host_list
         .into_iter()
         .enumerate()
-        .map(|(index, ip)| Machine {
-            id: (index + 1).to_string(),
-            name: (index + 1).to_string(),
-            ip: ip.to_string(),
-            mac: "AA:BB:CC:DD:EE:FF".to_string(),
-            last_update: "N/A".to_string(),
-            status: "connected".to_string(),
-        })
+        .map(|(index, host)| Machine::from_host(index + 1, host, mac))
         .collect();
 }
 
+/// Updates the `MACHINES` list from a new list of hosts of the inventory.
+///
+/// Unlike `from_list`, the machines already known keep their identifier and status, only
+/// their metadata being updated. New machines are added after them, and the machines
+/// missing from `host_list` are retired rather than removed, so that their history is kept.
+/// A retired machine listed again is restored, and counted as added.
+///
+/// # Arguments
+///
+/// * `host_list` - The hosts of the inventory.
+/// * `mac` - The MAC address shown for the machines whose MAC address is not in the inventory.
+///
+/// # Returns
+///
+/// * `(usize, usize)` - The number of machines added and retired.
+pub fn update_from_list(host_list: Vec<Host>, mac: &str) -> (usize, usize)
+{
+    let mut machines = MACHINES.lock().unwrap();
+    let ips: Vec<String> = host_list.iter().map(|host| host.ip.to_string()).collect();
+
+    let mut retired = 0;
+    for machine in machines.iter_mut().filter(|m| !m.retired && !m.discovered && !ips.contains(&m.ip)) {
+        machine.retired = true;
+        retired += 1;
+    }
+
+    let mut next_id = machines.iter().filter_map(|m| m.id.parse::<usize>().ok()).max().unwrap_or(0) + 1;
+    let mut added = 0;
+    for host in host_list {
+        if let Some(machine) = machines.iter_mut().find(|m| m.ip == host.ip.to_string()) {
+            if machine.retired {
+                machine.retired = false;
+                added += 1;
+            }
+            machine.discovered = false;
+            machine.pending = false;
+            machine.apply_host(host, mac);
+            continue;
+        }
+        machines.push(Machine::from_host(next_id, host, mac));
+        next_id += 1;
+        added += 1;
+    }
+
+    (added, retired)
+}
+
+/// A vote of a peer for the isolation of a machine, or for its release.
+///
+/// Peer-requested isolations and releases take effect once enough distinct nodes voted for
+/// them (see `response::Quorum`).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct IsolationVote {
+    /// The IP address of the machine to isolate.
+    pub target: IpAddr,
+    /// The key of the node that voted.
+    pub node: String,
+    /// The IP address the vote came from.
+    pub src: IpAddr,
+    /// The severity of the incident behind the vote, if the peer sent it.
+    pub severity: Option<Severity>,
+    /// The time of the vote, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+}
+
+/// The isolation votes of the peers, kept for the quorum window, the ones older being
+/// dropped as new votes come in.
+pub static VOTES: Lazy<Arc<Mutex<Vec<IsolationVote>>>> = Lazy::new(|| {Arc::new(Mutex::new(Vec::new()))});
+
+/// The release votes of the peers, counted like the isolation votes: a release requested by
+/// the peers takes effect once enough distinct nodes asked for it.
+static RELEASE_VOTES: Lazy<Mutex<Vec<IsolationVote>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Records a vote in a store of votes, dropping the ones out of the window.
+fn record_in(votes: &mut Vec<IsolationVote>, vote: IsolationVote, window: u64) -> Option<usize>
+{
+    votes.retain(|v| v.timestamp + window > vote.timestamp);
+
+    if let Some(previous) = votes.iter_mut().find(|v| v.target == vote.target && v.node == vote.node) {
+        *previous = vote;
+        return None;
+    }
+
+    let target = vote.target;
+    votes.push(vote);
+    Some(votes.iter().filter(|v| v.target == target).count())
+}
+
+/// Records the vote of a peer for the isolation of a machine.
+///
+/// A node has at most one vote per machine: voting again renews its vote.
+///
+/// # Arguments
+///
+/// * `vote` - The vote.
+/// * `window` - How long, in seconds, the votes count.
+///
+/// # Returns
+///
+/// * `Some(usize)` - The number of distinct nodes voting for the isolation of the machine,
+///   if the vote is the first of its node within the window.
+/// * `None` if the node already voted for the isolation of the machine.
+pub fn record_vote(vote: IsolationVote, window: u64) -> Option<usize>
+{
+    record_in(&mut VOTES.lock().unwrap(), vote, window)
+}
+
+/// Records the vote of a peer for the release of a machine, like `record_vote`.
+///
+/// # Arguments
+///
+/// * `vote` - The vote, `target` being the machine to release.
+/// * `window` - How long, in seconds, the votes count.
+///
+/// # Returns
+///
+/// * `Some(usize)` - The number of distinct nodes voting for the release of the machine,
+///   if the vote is the first of its node within the window.
+/// * `None` if the node already voted for the release of the machine.
+pub fn record_release_vote(vote: IsolationVote, window: u64) -> Option<usize>
+{
+    record_in(&mut RELEASE_VOTES.lock().unwrap(), vote, window)
+}
+
+/// Retrieves the isolation votes, optionally only the ones for a given machine.
+///
+/// # Arguments
+///
+/// * `target` - The IP address of the machine, or `None` for all the votes.
+pub fn get_votes(target: Option<IpAddr>) -> Vec<IsolationVote>
+{
+    let votes = VOTES.lock().unwrap();
+    votes.iter().filter(|v| target.is_none_or(|ip| v.target == ip)).cloned().collect()
+}
+
+/// Forgets the isolation and release votes for a machine, e.g. once it is released.
+///
+/// # Arguments
+///
+/// * `target` - The IP address of the machine.
+pub fn clear_votes(target: IpAddr)
+{
+    VOTES.lock().unwrap().retain(|v| v.target != target);
+    RELEASE_VOTES.lock().unwrap().retain(|v| v.target != target);
+}
+
+/// A release of an isolated machine broadcast to the peers, and the peers that confirmed it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Release {
+    /// The identifier of the release, sent back in the acknowledgements.
+    pub id: String,
+    /// The IP address of the machine released.
+    pub ip: IpAddr,
+    /// Who released the machine.
+    pub actor: String,
+    /// The time of the release, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// The peers the release was sent to.
+    pub sent_to: Vec<IpAddr>,
+    /// The peers that confirmed they released the machine.
+    pub confirmed_by: Vec<IpAddr>,
+}
+
+impl Release {
+    /// Checks whether every peer the release was sent to confirmed it.
+    pub fn is_confirmed(&self) -> bool
+    {
+        self.sent_to.iter().all(|ip| self.confirmed_by.contains(ip))
+    }
+}
+
+/// The number of releases kept in `RELEASES`.
+const MAX_RELEASES: usize = 100;
+
+/// A globally accessible, thread-safe vector holding the latest releases broadcast by the agent.
+pub static RELEASES: Lazy<Arc<Mutex<Vec<Release>>>> = Lazy::new(|| {Arc::new(Mutex::new(Vec::new()))});
+
+/// Records a release broadcast to the peers, keeping the latest `MAX_RELEASES`.
+pub fn record_release(release: Release)
+{
+    let mut releases = RELEASES.lock().unwrap();
+    releases.push(release);
+    if releases.len() > MAX_RELEASES {
+        releases.remove(0);
+    }
+}
+
+/// Records the confirmation of a release by a peer.
+///
+/// # Arguments
+///
+/// * `id` - The identifier of the release.
+/// * `peer` - The IP address of the peer.
+///
+/// # Returns
+///
+/// * `Some(Release)` - The release, if the confirmation is the first of the peer.
+/// * `None` if the release is unknown, was not sent to the peer, or was already confirmed by it.
+pub fn confirm_release(id: &str, peer: IpAddr) -> Option<Release>
+{
+    let mut releases = RELEASES.lock().unwrap();
+    let release = releases.iter_mut().find(|r| r.id == id)?;
+    if !release.sent_to.contains(&peer) || release.confirmed_by.contains(&peer) {
+        return None;
+    }
+    release.confirmed_by.push(peer);
+    Some(release.clone())
+}
+
+/// Retrieves a release broadcast by the agent.
+///
+/// # Arguments
+///
+/// * `id` - The identifier of the release.
+pub fn get_release(id: &str) -> Option<Release>
+{
+    let releases = RELEASES.lock().unwrap();
+    releases.iter().find(|r| r.id == id).cloned()
+}
+
+/// Retrieves the latest releases broadcast by the agent, oldest first.
+pub fn get_releases() -> Vec<Release>
+{
+    RELEASES.lock().unwrap().clone()
+}
+
+/// Serializes the tests of the modules sharing `MACHINES`.
+#[cfg(test)]
+pub static TEST_LOCK: Mutex<()> = Mutex::new(());
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::net::{Ipv4Addr, IpAddr};
 
-    fn reset_machines() {
-        let mut machines = MACHINES.lock().unwrap();
-        machines.clear();
+    fn hosts(ip_list: Vec<IpAddr>) -> Vec<Host> {
+        ip_list.into_iter().map(Host::from_ip).collect()
+    }
+
+    fn reset_machines() -> std::sync::MutexGuard<'static, ()> {
+        let guard = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        MACHINES.lock().unwrap().clear();
+        guard
     }
 
     #[test]
     fn test_get_machines_empty() {
-        reset_machines();
+        let _guard = reset_machines();
 
         let machines = get_machines();
         assert!(machines.is_empty());
@@ -107,13 +813,13 @@ mod tests {
 
     #[test]
     fn test_get_machines_non_empty() {
-        reset_machines();
+        let _guard = reset_machines();
 
         let ip_list = vec![
             Ipv4Addr::new(192, 168, 1, 1).into(),
             Ipv4Addr::new(127, 0, 0, 1).into(),
         ];
-        from_list(ip_list);
+        from_list(hosts(ip_list), "AA:BB:CC:DD:EE:FF");
 
         let machines = get_machines();
         assert_eq!(machines.len(), 2);
@@ -123,10 +829,10 @@ mod tests {
 
     #[test]
     fn test_change_machine_state() {
-        reset_machines();
+        let _guard = reset_machines();
 
         let ip_list = vec![Ipv4Addr::new(192, 168, 1, 1).into()];
-        from_list(ip_list);
+        from_list(hosts(ip_list), "AA:BB:CC:DD:EE:FF");
 
         change_machine_state("192.168.1.1", "isolated");
 
@@ -136,10 +842,10 @@ mod tests {
 
     #[test]
     fn test_change_machine_state_non_existent_ip() {
-        reset_machines();
+        let _guard = reset_machines();
 
         let ip_list = vec![Ipv4Addr::new(192, 168, 1, 1).into()];
-        from_list(ip_list);
+        from_list(hosts(ip_list), "AA:BB:CC:DD:EE:FF");
 
         change_machine_state("10.0.0.1", "isolated");
 
@@ -147,16 +853,59 @@ mod tests {
         assert_eq!(machines[0].status, "connected");
     }
 
+    #[test]
+    fn test_find_machine() {
+        let _guard = reset_machines();
+
+        let ip_list = vec![Ipv4Addr::new(192, 168, 1, 1).into(), Ipv4Addr::new(192, 168, 1, 2).into()];
+        from_list(hosts(ip_list), "AA:BB:CC:DD:EE:FF");
+
+        assert_eq!(find_machine("192.168.1.2").map(|m| m.id), Some("2".to_string()));
+        assert_eq!(find_machine("1").map(|m| m.ip), Some("192.168.1.1".to_string()));
+        assert!(find_machine("10.0.0.1").is_none());
+    }
+
+    #[test]
+    fn test_machine_addresses() {
+        let _guard = reset_machines();
+
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let mut host = Host::from_ip(ip("10.20.0.1"));
+        host.addresses = vec![ip("2001:db8::1")];
+        host.mac = Some("02:00:00:00:00:01".to_string());
+        let mut other = Host::from_ip(ip("10.20.0.2"));
+        other.mac = Some("02:00:00:00:00:02".to_string());
+        from_list(vec![host, other], "AA:BB:CC:DD:EE:FF");
+
+        // The machine is found, and isolated, by any of its addresses.
+        change_machine_state("2001:db8::1", "isolated");
+        assert_eq!(find_machine("10.20.0.1").map(|m| m.status), Some("isolated".to_string()));
+        assert!(is_trusted("2001:db8::1"));
+
+        assert!(add_address("02:00:00:00:00:01", ip("fe80::1"), AddressSource::Local));
+        assert!(!add_address("02:00:00:00:00:02", ip("fe80::1"), AddressSource::Local));
+        assert!(!add_address("02:00:00:00:00:03", ip("fe80::3"), AddressSource::Local));
+        assert_eq!(machine_addresses(ip("fe80::1")), vec![ip("10.20.0.1"), ip("2001:db8::1"), ip("fe80::1")]);
+        assert_eq!(machine_addresses(ip("10.20.0.3")), vec![ip("10.20.0.3")]);
+
+        // The addresses of the neighbour tables are neither known nor locked with the machine.
+        assert!(add_address("02:00:00:00:00:02", ip("fe80::2"), AddressSource::Neighbour));
+        assert!(!add_address("02:00:00:00:00:01", ip("fe80::2"), AddressSource::Neighbour));
+        assert_eq!(machine_addresses(ip("10.20.0.2")), vec![ip("10.20.0.2")]);
+        assert_eq!(machine_addresses(ip("fe80::2")), vec![ip("fe80::2")]);
+        assert!(!is_trusted("fe80::2"));
+    }
+
     #[test]
     fn test_from_list() {
-        reset_machines();
+        let _guard = reset_machines();
 
         let ip_list = vec![
             Ipv4Addr::new(192, 168, 1, 1).into(),
             Ipv4Addr::new(127, 0, 0, 1).into(),
             Ipv4Addr::new(8, 8, 8, 8).into(),
         ];
-        from_list(ip_list);
+        from_list(hosts(ip_list), "AA:BB:CC:DD:EE:FF");
 
         let machines = get_machines();
         assert_eq!(machines.len(), 3);
@@ -167,12 +916,165 @@ mod tests {
 
     #[test]
     fn test_from_list_empty_ip_list() {
-        reset_machines();
+        let _guard = reset_machines();
 
         let ip_list: Vec<IpAddr> = vec![];
-        from_list(ip_list);
+        from_list(hosts(ip_list), "AA:BB:CC:DD:EE:FF");
 
         let machines = get_machines();
         assert!(machines.is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_update_from_list() {
+        let _guard = reset_machines();
+
+        from_list(hosts(vec![Ipv4Addr::new(192, 168, 1, 1).into(), Ipv4Addr::new(192, 168, 1, 2).into()]), "AA:BB:CC:DD:EE:FF");
+        change_machine_state("192.168.1.2", "isolated");
+
+        let (added, retired) = update_from_list(
+            hosts(vec![Ipv4Addr::new(192, 168, 1, 2).into(), Ipv4Addr::new(192, 168, 1, 3).into()]),
+            "AA:BB:CC:DD:EE:FF",
+        );
+        assert_eq!((added, retired), (1, 1));
+
+        let machines = get_machines();
+        assert_eq!(machines.len(), 3);
+        assert_eq!((machines[0].ip.as_str(), machines[0].retired), ("192.168.1.1", true));
+        assert_eq!((machines[1].id.as_str(), machines[1].status.as_str()), ("2", "isolated"));
+        assert_eq!((machines[2].id.as_str(), machines[2].ip.as_str()), ("3", "192.168.1.3"));
+
+        let (added, retired) = update_from_list(
+            hosts(vec![Ipv4Addr::new(192, 168, 1, 1).into(), Ipv4Addr::new(192, 168, 1, 2).into(), Ipv4Addr::new(192, 168, 1, 3).into()]),
+            "AA:BB:CC:DD:EE:FF",
+        );
+        assert_eq!((added, retired), (1, 0));
+        assert!(!get_machines()[0].retired);
+        assert_eq!(get_machines()[1].status, "isolated");
+    }
+
+    #[test]
+    fn test_discovered_peers() {
+        let _guard = reset_machines();
+
+        from_list(hosts(vec![Ipv4Addr::new(192, 168, 1, 1).into()]), "AA:BB:CC:DD:EE:FF");
+        assert!(add_discovered("192.168.1.7", "node-7", "key-7", false));
+        assert!(!add_discovered("192.168.1.1", "node-1", "key-1", true));
+
+        assert!(is_trusted("192.168.1.1"));
+        assert!(!is_trusted("192.168.1.7"));
+        assert!(!is_trusted("192.168.1.8"));
+
+        // Discovered peers are not retired by an inventory reload.
+        update_from_list(hosts(vec![Ipv4Addr::new(192, 168, 1, 1).into()]), "AA:BB:CC:DD:EE:FF");
+        assert!(!get_machines()[1].retired);
+
+        assert_eq!(approve_peer("192.168.1.7").and_then(|m| m.key).as_deref(), Some("key-7"));
+        assert!(approve_peer("192.168.1.7").is_none());
+        assert!(is_trusted("192.168.1.7"));
+        assert_eq!(get_machines()[1].name, "node-7");
+
+        assert!(add_discovered("192.168.1.8", "", "key-8", false));
+        assert!(reject_peer("192.168.1.8"));
+        assert_eq!(get_machines().len(), 2);
+    }
+
+    #[test]
+    fn test_record_vote() {
+        let target: IpAddr = "10.70.0.1".parse().unwrap();
+        let vote = |node: &str, timestamp| IsolationVote {
+            target,
+            node: node.to_string(),
+            src: "10.70.0.2".parse().unwrap(),
+            severity: None,
+            timestamp,
+        };
+
+        assert_eq!(record_vote(vote("a", 1000), 60), Some(1));
+        assert_eq!(record_vote(vote("a", 1010), 60), None);
+        assert_eq!(record_vote(vote("b", 1020), 60), Some(2));
+        assert_eq!(get_votes(Some(target)).len(), 2);
+
+        // The votes out of the window no longer count.
+        assert_eq!(record_vote(vote("c", 1075), 60), Some(2));
+        assert_eq!(get_votes(Some(target)).iter().map(|v| v.node.as_str()).collect::<Vec<_>>(), vec!["b", "c"]);
+
+        // The release votes are counted apart, and cleared with the isolation votes.
+        assert_eq!(record_release_vote(vote("a", 1080), 60), Some(1));
+        assert_eq!(record_release_vote(vote("a", 1085), 60), None);
+        clear_votes(target);
+        assert!(get_votes(Some(target)).is_empty());
+        assert_eq!(record_release_vote(vote("a", 1090), 60), Some(1));
+    }
+
+    #[test]
+    fn test_confirm_release() {
+        let peer: IpAddr = "10.70.1.2".parse().unwrap();
+        record_release(Release {
+            id: "r1".to_string(),
+            ip: "10.70.1.1".parse().unwrap(),
+            actor: "test".to_string(),
+            timestamp: 1000,
+            sent_to: vec![peer, "10.70.1.3".parse().unwrap()],
+            confirmed_by: Vec::new(),
+        });
+
+        assert!(confirm_release("r1", "10.70.1.9".parse().unwrap()).is_none());
+        assert!(!confirm_release("r1", peer).unwrap().is_confirmed());
+        assert!(confirm_release("r1", peer).is_none());
+        assert!(confirm_release("r1", "10.70.1.3".parse().unwrap()).unwrap().is_confirmed());
+        assert!(confirm_release("unknown", peer).is_none());
+    }
+
+    #[test]
+    fn test_merge_machine_statuses() {
+        let _guard = reset_machines();
+        from_list(hosts(vec![Ipv4Addr::new(10, 70, 2, 1).into(), Ipv4Addr::new(10, 70, 2, 2).into()]), "AA:BB:CC:DD:EE:FF");
+
+        change_machine_state("10.70.2.1", "isolated");
+        let local = machine_statuses();
+        assert_eq!(local.len(), 1);
+
+        // An older change of a peer loses, a newer one wins, and unknown machines are ignored.
+        let status = |ip: &str, status: &str, counter| MachineStatus {
+            ip: ip.to_string(),
+            status: status.to_string(),
+            version: Version { counter, node: "peer".to_string() },
+        };
+        let counter = local[0].version.counter;
+        set_locked("10.70.2.1", true);
+        assert_eq!(merge_machine_statuses(vec![status("10.70.2.1", "connected", counter - 1)]), 0);
+        assert_eq!(merge_machine_statuses(vec![status("10.70.2.1", "connected", counter + 1), status("10.70.2.2", "isolated", 1)]), 2);
+        assert_eq!(merge_machine_statuses(vec![status("10.70.2.9", "isolated", 1)]), 0);
+        assert_eq!(get_machines()[0].status, "connected");
+
+        // The peers change the statuses, not what the local firewall locks out.
+        assert!(get_machines()[0].locked);
+        assert!(!get_machines()[1].locked);
+
+        // The local clock moved past the versions received: a local change wins again.
+        change_machine_state("10.70.2.1", "isolated");
+        assert!(get_machines()[0].version.as_ref().unwrap().counter > counter + 1);
+    }
+
+    #[test]
+    fn test_open_clock() {
+        let _guard = reset_machines();
+        let path = std::env::temp_dir().join("test_wormsec_clock");
+        let path = path.to_str().unwrap();
+        fs::write(path, "1000000\n").unwrap();
+        from_list(hosts(vec![Ipv4Addr::new(10, 70, 4, 1).into()]), "AA:BB:CC:DD:EE:FF");
+
+        // The clock restarts past the value saved, and saves every local change.
+        assert!(open_clock(path).unwrap() >= 1000000);
+        change_machine_state("10.70.4.1", "isolated");
+        let counter = get_machines()[0].version.as_ref().unwrap().counter;
+        assert!(counter > 1000000);
+        assert_eq!(fs::read_to_string(path).unwrap(), counter.to_string());
+
+        *CLOCK_FILE.lock().unwrap() = None;
+        fs::write(path, "not a clock").unwrap();
+        assert!(open_clock(path).is_err());
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Serialize, Deserialize};
+use std::{thread, time::Duration};
+
+use crate::incident::{self, Incident};
+use crate::network::broadcast_state;
+use crate::state::{self, MachineStatus};
+
+/// The number of incidents, the latest updated, sent to the peers on every synchronization.
+///
+/// Older incidents were already exchanged while they were updated.
+const SYNC_INCIDENTS: usize = 100;
+
+/// The number of machine statuses sent in a single message, to keep it within a datagram.
+const MACHINES_PER_MESSAGE: usize = 100;
+
+/// The number of incidents sent in a single message, to keep it within a datagram.
+const INCIDENTS_PER_MESSAGE: usize = 25;
+
+/// The state an agent shares with its peers, so that they all show the same view of the cluster.
+///
+/// Both parts are merged in a way that does not depend on the order the messages are
+/// received in (see `state::merge_machine_statuses` and `incident::merge_incidents`): the
+/// agents converge as long as they keep exchanging their state, messages lost or reordered
+/// on the way.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+pub struct StateSync {
+    /// The statuses of the machines changed since the agents started.
+    pub machines: Vec<MachineStatus>,
+    /// The incidents known to the agent, its own and those of its peers.
+    pub incidents: Vec<Incident>,
+}
+
+/// Returns the state of the agent to send to the peers, split in messages that fit in a datagram.
+pub fn snapshot() -> Vec<StateSync>
+{
+    let machines = state::machine_statuses();
+    let incidents = incident::latest_incidents(SYNC_INCIDENTS);
+
+    let mut messages: Vec<StateSync> = machines
+        .chunks(MACHINES_PER_MESSAGE)
+        .map(|machines| StateSync { machines: machines.to_vec(), incidents: Vec::new() })
+        .collect();
+    messages.extend(incidents.chunks(INCIDENTS_PER_MESSAGE).map(|incidents| StateSync { machines: Vec::new(), incidents: incidents.to_vec() }));
+    messages
+}
+
+/// Merges the state received from a peer with the state of the agent.
+///
+/// # Arguments
+///
+/// * `sync` - The state received, from a trusted node.
+///
+/// # Returns
+///
+/// * `(usize, usize)` - The number of machines whose status changed, and the number of
+///   incidents added or updated.
+pub fn apply(sync: StateSync) -> (usize, usize)
+{
+    (state::merge_machine_statuses(sync.machines), incident::merge_incidents(sync.incidents))
+}
+
+/// Starts sending the state of the agent to its peers.
+///
+/// The state is sent in a separate thread, every `interval`, sealed for each peer like the
+/// other messages.
+///
+/// # Arguments
+///
+/// * `interval` - The time between two synchronizations.
+/// * `port` - The port the agents listen on.
+pub fn start_state_sync(interval: Duration, port: u16)
+{
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        for message in snapshot() {
+            if let Err(e) = broadcast_state(message, port) {
+                println!("Failed to send the state to the peers: {e}");
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alert::{Alert, Policy, Severity};
+    use crate::network::{PeerMessage, Payload};
+
+    #[test]
+    fn test_snapshot_fits_in_a_datagram() {
+        let _guard = state::TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        for i in 0..=200 {
+            let alert = Alert::new("canary", Severity::Critical, format!("10.80.{}.{}", i / 100, i % 100).parse().unwrap(), "x".repeat(200));
+            incident::correlate(&alert, 300, &Policy::default());
+        }
+
+        let messages = snapshot();
+        assert_eq!(messages.iter().map(|m| m.incidents.len()).sum::<usize>(), SYNC_INCIDENTS);
+        for message in messages {
+            let datagram = serde_json::to_vec(&PeerMessage::new(Payload::Sync(message))).unwrap();
+            assert!(datagram.len() < crate::channel::MAX_DATAGRAM / 2);
+        }
+    }
+}
@@ -0,0 +1,548 @@
+use std::{fs::File, io::{self, BufRead, BufReader, Read}, net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream}, path::Path, sync::{atomic::{AtomicUsize, Ordering}, Arc}, thread};
+
+use crate::alert::{Alert, AlertCallback, Severity};
+use crate::state;
+use crate::transport::{bind_tcp, bind_udp, canonical};
+
+/// The default port of the syslog receiver.
+pub const DEFAULT_SYSLOG_PORT: u16 = 514;
+
+/// The maximum size of a syslog message, larger TCP frames being dropped.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// The maximum number of TCP connections served at the same time, each in its own thread.
+const MAX_TCP_CONNECTIONS: usize = 64;
+
+/// The field of the messages holding the offending address, for the rules that don't name one.
+const DEFAULT_ADDRESS_FIELD: &str = "src";
+
+/// A syslog message received from another host (RFC 5424 or RFC 3164).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogMessage {
+    /// The address the message was received from.
+    pub source: IpAddr,
+    /// The facility of the message (e.g. 4 for `auth`).
+    pub facility: u8,
+    /// The syslog severity of the message, from 0 (emergency) to 7 (debug).
+    pub severity: u8,
+    /// The timestamp written by the sender, as is.
+    pub timestamp: Option<String>,
+    /// The host name written by the sender.
+    pub hostname: Option<String>,
+    /// The application that sent the message.
+    pub app_name: Option<String>,
+    /// The free-form message.
+    pub message: String,
+}
+
+/// A rule matching syslog messages that should raise an alert.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogRule {
+    /// The severity of the alerts raised by the rule.
+    pub severity: Severity,
+    /// The text the message must contain, compared case-insensitively.
+    pub pattern: String,
+    /// The field of the message holding the offending address (e.g. `src` for `SRC=10.0.0.9`,
+    /// `from` for `from 10.0.0.9`), compared case-insensitively.
+    pub field: String,
+}
+
+/// The settings of the syslog receiver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogSettings {
+    /// The UDP and TCP port to listen on.
+    pub port: u16,
+    /// The rules to match the messages against.
+    pub rules: Vec<SyslogRule>,
+    /// The addresses of the appliances allowed to send messages, the others being dropped.
+    pub allowed_senders: Vec<IpAddr>,
+}
+
+/// Reads the syslog receiver settings from a file.
+///
+/// A `port <n>` line sets the port to listen on (514 by default), and each `allow <ip>` line
+/// adds an appliance allowed to send messages. Every other line holds a rule, written as a
+/// severity, optionally the field holding the offending address (`address=<field>`, `src` by
+/// default), then the text to look for in the messages (e.g. `high address=from Port scan
+/// detected`). Empty lines and lines starting with `#` are ignored.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the settings file.
+///
+/// # Returns
+///
+/// * `Ok(SyslogSettings)` - The settings read from the file.
+/// * `Err(io::Error)` - An error if the file cannot be read or contains an invalid line.
+pub fn read_syslog_settings_from_file(filename: &str) -> io::Result<SyslogSettings>
+{
+    let file = File::open(Path::new(filename))?;
+    let reader = io::BufReader::new(file);
+    let mut settings = SyslogSettings { port: DEFAULT_SYSLOG_PORT, rules: Vec::new(), allowed_senders: Vec::new() };
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (first, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        if first == "port" {
+            settings.port = rest.trim().parse().map_err(|_| io::Error::other(format!("invalid port \"{}\"", rest.trim())))?;
+            continue;
+        }
+        if first == "allow" {
+            settings.allowed_senders.push(rest.trim().parse().map_err(|_| io::Error::other(format!("invalid sender \"{}\"", rest.trim())))?);
+            continue;
+        }
+
+        let severity = first.parse().map_err(io::Error::other)?;
+        let mut rest = rest.trim();
+        let mut field = DEFAULT_ADDRESS_FIELD;
+        if let Some(after) = rest.strip_prefix("address=") {
+            (field, rest) = after.split_once(char::is_whitespace).unwrap_or((after, ""));
+            rest = rest.trim();
+        }
+        if field.is_empty() || rest.is_empty() {
+            return Err(io::Error::other(format!("missing pattern in rule \"{line}\"")));
+        }
+
+        settings.rules.push(SyslogRule { severity, pattern: rest.to_string(), field: field.to_lowercase() });
+    }
+
+    Ok(settings)
+}
+
+/// Returns `None` for the `-` RFC 5424 uses for missing values.
+fn nil(value: &str) -> Option<String>
+{
+    (value != "-").then(|| value.to_string())
+}
+
+/// Skips the structured data of an RFC 5424 message, returning what follows it.
+fn skip_structured_data(s: &str) -> &str
+{
+    if let Some(rest) = s.strip_prefix('-') {
+        return rest;
+    }
+
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ if depth == 0 => return &s[i..],
+            _ => {},
+        }
+    }
+
+    ""
+}
+
+/// Parses a syslog message, in the RFC 5424 or the RFC 3164 (BSD) format.
+///
+/// # Arguments
+///
+/// * `data` - The message, without its transport framing.
+/// * `source` - The address the message was received from.
+///
+/// # Returns
+///
+/// * `Some(SyslogMessage)` if the message starts with a valid priority.
+/// * `None` otherwise.
+pub fn parse_message(data: &str, source: IpAddr) -> Option<SyslogMessage>
+{
+    let data = data.trim_end_matches(['\r', '\n', '\0']);
+    let rest = data.strip_prefix('<')?;
+    let (pri, rest) = rest.split_once('>')?;
+    let pri: u8 = pri.parse().ok().filter(|pri| *pri <= 191)?;
+
+    let mut message = SyslogMessage {
+        source,
+        facility: pri / 8,
+        severity: pri % 8,
+        timestamp: None,
+        hostname: None,
+        app_name: None,
+        message: String::new(),
+    };
+
+    if let Some(rest) = rest.strip_prefix("1 ") {
+        // RFC 5424: TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]
+        let mut fields = rest.splitn(6, ' ');
+        message.timestamp = nil(fields.next()?);
+        message.hostname = nil(fields.next()?);
+        message.app_name = nil(fields.next()?);
+        fields.next()?;
+        fields.next()?;
+
+        let msg = skip_structured_data(fields.next().unwrap_or("-"));
+        message.message = msg.trim_start().trim_start_matches('\u{feff}').to_string();
+    } else {
+        // RFC 3164: "Mmm dd hh:mm:ss HOSTNAME TAG: MSG", every part being optional in practice.
+        let mut rest = rest;
+        let months = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+        if rest.len() >= 16 && months.iter().any(|m| rest.starts_with(m)) && rest.as_bytes()[15] == b' ' {
+            message.timestamp = Some(rest[..15].to_string());
+            rest = &rest[16..];
+
+            if let Some((hostname, after)) = rest.split_once(' ') {
+                if !hostname.ends_with(':') && !hostname.contains('[') {
+                    message.hostname = Some(hostname.to_string());
+                    rest = after;
+                }
+            }
+        }
+
+        let tag_end = rest.find([':', '[', ' ']).unwrap_or(0);
+        if tag_end > 0 && rest[tag_end..].contains(':') {
+            message.app_name = Some(rest[..tag_end].to_string());
+            rest = rest.split_once(':').map(|(_, msg)| msg).unwrap_or(rest);
+        }
+
+        message.message = rest.trim_start().to_string();
+    }
+
+    Some(message)
+}
+
+/// Reads the next syslog frame from a TCP stream (RFC 6587).
+///
+/// Both framings are supported: octet counting (`<length> <message>`), used when the frame
+/// starts with a digit, and newline-terminated messages otherwise.
+///
+/// # Arguments
+///
+/// * `reader` - The stream to read from.
+///
+/// # Returns
+///
+/// * `Ok(Some(String))` - The next message.
+/// * `Ok(None)` - If the stream was closed.
+/// * `Err(io::Error)` - If the stream could not be read or the frame is invalid.
+pub fn read_frame(reader: &mut impl BufRead) -> io::Result<Option<String>>
+{
+    let starts_with_digit = match reader.fill_buf()?.first() {
+        None => return Ok(None),
+        Some(byte) => byte.is_ascii_digit(),
+    };
+
+    if starts_with_digit {
+        let mut length = Vec::new();
+        reader.read_until(b' ', &mut length)?;
+
+        let length: usize = String::from_utf8_lossy(&length)
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::other("invalid frame length"))?;
+        if length > MAX_MESSAGE_SIZE {
+            return Err(io::Error::other("frame too large"));
+        }
+
+        let mut message = vec![0; length];
+        reader.read_exact(&mut message)?;
+        return Ok(Some(String::from_utf8_lossy(&message).to_string()));
+    }
+
+    let mut message = Vec::new();
+    reader.take(MAX_MESSAGE_SIZE as u64).read_until(b'\n', &mut message)?;
+    Ok(Some(String::from_utf8_lossy(&message).to_string()))
+}
+
+/// Parses an address written in a log, possibly with a port (e.g. `10.0.0.9:443`, `[2001:db8::9]:443`).
+fn parse_address(value: &str) -> Option<IpAddr>
+{
+    let value = value.trim_matches(|c: char| matches!(c, ',' | ';' | '(' | ')' | '"' | '\''));
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// Finds the address held by a field of a log message.
+///
+/// The field is followed by its value after `=`, `:` or a space, whatever the case of its
+/// name: `SRC=10.0.0.9`, `src: 10.0.0.9` and `from 10.0.0.9` are all found.
+fn field_address(text: &str, field: &str) -> Option<IpAddr>
+{
+    let words: Vec<&str> = text.split_whitespace().collect();
+    words.iter().enumerate().find_map(|(i, word)| {
+        let rest = word.get(..field.len()).filter(|name| name.eq_ignore_ascii_case(field)).map(|_| &word[field.len()..])?;
+        let value = rest.strip_prefix(['=', ':']).unwrap_or(rest);
+        match value {
+            "" if rest.len() <= 1 => words.get(i + 1).and_then(|next| parse_address(next)),
+            _ if rest.len() == value.len() => None,
+            value => parse_address(value),
+        }
+    })
+}
+
+/// Finds the machine a syslog message is about: the offending address, held by the field
+/// the rule names.
+///
+/// The sender is never the target, as it is the appliance reporting, nor is any other
+/// address mentioned (e.g. the destination of a denied connection, which is the victim).
+///
+/// # Returns
+///
+/// * `Some(IpAddr)` - The main IP address of the machine having the offending address, or
+///   the offending address itself if it is not a known machine.
+/// * `None` if the message holds no offending address.
+fn target(message: &SyslogMessage, rule: &SyslogRule) -> Option<IpAddr>
+{
+    let address = field_address(&message.message, &rule.field)?;
+    Some(
+        state::find_machine(&address.to_string())
+            .and_then(|machine| machine.ip.parse().ok())
+            .unwrap_or(address),
+    )
+}
+
+/// Matches a syslog message against the rules, building the alert for the first match.
+///
+/// # Arguments
+///
+/// * `message` - The message received.
+/// * `rules` - The rules to match the message against.
+///
+/// # Returns
+///
+/// * `Some(Alert)` if one of the rules matches, and the message holds the offending address.
+/// * `None` otherwise.
+pub fn match_rules(message: &SyslogMessage, rules: &[SyslogRule]) -> Option<Alert>
+{
+    let text = message.message.to_lowercase();
+    let rule = rules.iter().find(|rule| text.contains(&rule.pattern.to_lowercase()))?;
+    let Some(target) = target(message, rule) else {
+        println!("Ignored a syslog message from {} matching \"{}\": no {} address in it", message.source, rule.pattern, rule.field);
+        return None;
+    };
+
+    let mut alert = Alert::new(
+        "syslog",
+        rule.severity,
+        target,
+        format!("{} reported: {}", message.hostname.as_deref().unwrap_or(&message.source.to_string()), message.message),
+    );
+
+    alert.details.insert("source".to_string(), message.source.to_string());
+    alert.details.insert("facility".to_string(), message.facility.to_string());
+    alert.details.insert("syslog_severity".to_string(), message.severity.to_string());
+    alert.details.insert("pattern".to_string(), rule.pattern.clone());
+    if let Some(app_name) = &message.app_name {
+        alert.details.insert("app_name".to_string(), app_name.clone());
+    }
+
+    Some(alert)
+}
+
+/// Parses a received message and reports it if it matches one of the rules.
+fn handle(data: &str, source: IpAddr, rules: &[SyslogRule], callback: &AlertCallback)
+{
+    let Some(message) = parse_message(data, source) else {
+        return;
+    };
+
+    if let Some(alert) = match_rules(&message, rules) {
+        let cb = callback.lock().unwrap();
+        cb(alert);
+    }
+}
+
+/// Reads the messages of a TCP connection from `src` until it is closed.
+fn serve_tcp_connection(stream: TcpStream, src: SocketAddr, rules: Vec<SyslogRule>, callback: AlertCallback)
+{
+    let mut reader = BufReader::new(stream);
+
+    while let Ok(Some(data)) = read_frame(&mut reader) {
+        handle(&data, src.ip(), &rules, &callback);
+    }
+}
+
+/// Starts receiving syslog messages over UDP and TCP.
+///
+/// This lets firewalls, switches and other appliances forward their logs to the agent.
+/// Messages matching one of the rules raise an alert about the machine they concern (see
+/// `match_rules`), so they go through the same response as the local detections.
+///
+/// Syslog messages are easily forged, so only the messages of the allowed senders are
+/// handled, the receiver being disabled if there are none. The receiver listens on IPv4
+/// and IPv6 both, unless IPv6 is disabled (see `transport::bind_tcp`). Each listener, and each TCP
+/// connection, up to `MAX_TCP_CONNECTIONS`, runs in its own thread.
+///
+/// # Arguments
+///
+/// * `settings` - The port to listen on, the allowed senders and the rules to match the messages against.
+/// * `callback` - The callback to run with the alerts raised.
+pub fn start_syslog_receiver(settings: SyslogSettings, callback: AlertCallback)
+{
+    if settings.allowed_senders.is_empty() {
+        println!("Syslog receiver disabled: no sender is allowed");
+        return;
+    }
+    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, settings.port));
+    let allowed = Arc::new(settings.allowed_senders.clone());
+
+    match bind_udp(addr) {
+        Ok(socket) => {
+            let rules = settings.rules.clone();
+            let (allowed, callback) = (allowed.clone(), callback.clone());
+
+            thread::spawn(move || {
+                let mut buf = vec![0; MAX_MESSAGE_SIZE];
+                loop {
+                    if let Ok((amt, src)) = socket.recv_from(&mut buf).map(|(amt, src)| (amt, canonical(src))) {
+                        if allowed.contains(&src.ip()) {
+                            handle(&String::from_utf8_lossy(&buf[..amt]), src.ip(), &rules, &callback);
+                        }
+                    }
+                }
+            });
+        },
+        Err(e) => println!("Failed to open syslog receiver on udp/{}: {e}", settings.port),
+    }
+
+    match bind_tcp(addr) {
+        Ok(listener) => {
+            let connections = Arc::new(AtomicUsize::new(0));
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let Ok(src) = stream.peer_addr().map(canonical) else {
+                        continue;
+                    };
+                    if !allowed.contains(&src.ip()) || connections.load(Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+                        continue;
+                    }
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    let (rules, callback, connections) = (settings.rules.clone(), callback.clone(), connections.clone());
+                    thread::spawn(move || {
+                        serve_tcp_connection(stream, src, rules, callback);
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            });
+        },
+        Err(e) => println!("Failed to open syslog receiver on tcp/{}: {e}", settings.port),
+    }
+
+    println!("Syslog receiver listening on port {}", addr.port());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn source() -> IpAddr {
+        "10.0.0.254".parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_rfc5424() {
+        let data = "<34>1 2025-03-10T22:14:15.003Z fw01 filterlog 1234 ID47 [exampleSDID@32473 iut=\"3\" eventID=\"1011\"] Port scan detected from 10.0.0.9";
+        let message = parse_message(data, source()).unwrap();
+
+        assert_eq!(message.facility, 4);
+        assert_eq!(message.severity, 2);
+        assert_eq!(message.timestamp.as_deref(), Some("2025-03-10T22:14:15.003Z"));
+        assert_eq!(message.hostname.as_deref(), Some("fw01"));
+        assert_eq!(message.app_name.as_deref(), Some("filterlog"));
+        assert_eq!(message.message, "Port scan detected from 10.0.0.9");
+    }
+
+    #[test]
+    fn test_parse_rfc5424_without_structured_data() {
+        let message = parse_message("<165>1 2025-03-10T22:14:15Z - - - - - link down\n", source()).unwrap();
+
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.app_name, None);
+        assert_eq!(message.message, "link down");
+    }
+
+    #[test]
+    fn test_parse_rfc3164() {
+        let message = parse_message("<13>Mar 10 22:14:15 switch01 kernel[12]: ARP spoofing detected", source()).unwrap();
+
+        assert_eq!(message.facility, 1);
+        assert_eq!(message.severity, 5);
+        assert_eq!(message.timestamp.as_deref(), Some("Mar 10 22:14:15"));
+        assert_eq!(message.hostname.as_deref(), Some("switch01"));
+        assert_eq!(message.app_name.as_deref(), Some("kernel"));
+        assert_eq!(message.message, "ARP spoofing detected");
+    }
+
+    #[test]
+    fn test_parse_invalid_message() {
+        assert!(parse_message("no priority", source()).is_none());
+        assert!(parse_message("<999>1 - - - - - -", source()).is_none());
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let mut reader = Cursor::new(b"9 <13>hello<13>world\n".to_vec());
+
+        assert_eq!(read_frame(&mut reader).unwrap().as_deref(), Some("<13>hello"));
+        assert_eq!(read_frame(&mut reader).unwrap().as_deref(), Some("<13>world\n"));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_syslog_settings_from_file() {
+        let path = std::env::temp_dir().join("test_syslog.txt");
+        std::fs::write(&path, "# appliances\nport 5514\nallow 10.0.0.254\nhigh address=from Port scan detected\nmedium DENY\n").unwrap();
+
+        let settings = read_syslog_settings_from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(settings, SyslogSettings {
+            port: 5514,
+            rules: vec![
+                SyslogRule { severity: Severity::High, pattern: "Port scan detected".to_string(), field: "from".to_string() },
+                SyslogRule { severity: Severity::Medium, pattern: "DENY".to_string(), field: "src".to_string() },
+            ],
+            allowed_senders: vec![source()],
+        });
+
+        std::fs::write(&path, "urgent Port scan\n").unwrap();
+        assert!(read_syslog_settings_from_file(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_field_address() {
+        let line = "DENY IN=eth0 SRC=10.99.0.9 DST=10.99.0.2 PROTO=TCP";
+        assert_eq!(field_address(line, "src"), Some("10.99.0.9".parse().unwrap()));
+        assert_eq!(field_address(line, "dst"), Some("10.99.0.2".parse().unwrap()));
+        assert_eq!(field_address("scan from [2001:db8::9]:4444, blocked", "from"), Some("2001:db8::9".parse().unwrap()));
+        assert_eq!(field_address("src: 10.99.0.9", "src"), Some("10.99.0.9".parse().unwrap()));
+        assert_eq!(field_address("srcport=10.99.0.9 src=nothing", "src"), None);
+    }
+
+    #[test]
+    fn test_match_rules() {
+        let rules = vec![
+            SyslogRule { severity: Severity::High, pattern: "port scan".to_string(), field: "from".to_string() },
+            SyslogRule { severity: Severity::Medium, pattern: "deny".to_string(), field: "src".to_string() },
+        ];
+        let message = parse_message("<34>1 - fw01 - - - - Port scan detected from 10.99.0.9", source()).unwrap();
+
+        let alert = match_rules(&message, &rules).unwrap();
+        assert_eq!(alert.severity, Severity::High);
+        assert_eq!(alert.target, "10.99.0.9".parse::<IpAddr>().unwrap());
+        assert_eq!(alert.details["source"], "10.0.0.254");
+
+        // The scanner is the target, not the host being scanned.
+        let message = parse_message("<34>1 - fw01 - - - - DENY SRC=10.99.0.9 DST=10.99.0.2", source()).unwrap();
+        assert_eq!(match_rules(&message, &rules).unwrap().target, "10.99.0.9".parse::<IpAddr>().unwrap());
+
+        // Without an offending address, nothing is raised, and certainly not about the sender.
+        let message = parse_message("<34>1 - fw01 - - - - Port scan detected", source()).unwrap();
+        assert!(match_rules(&message, &rules).is_none());
+
+        let message = parse_message("<34>1 - fw01 - - - - link up", source()).unwrap();
+        assert!(match_rules(&message, &rules).is_none());
+    }
+}
@@ -0,0 +1,491 @@
+use serde::{Serialize, Deserialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{collections::HashMap, fmt, io::{self, Read, Write}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket}, sync::{mpsc::{self, Receiver, SyncSender}, Arc, Mutex}, thread, time::Duration};
+use once_cell::sync::OnceCell;
+
+use crate::channel::MAX_DATAGRAM;
+
+/// The time allowed to connect to a peer, or to send it a frame, over TCP.
+const TCP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The time after which a TCP connection of a peer that sends nothing is closed.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The time a new TCP connection has to send its first frame before it is closed.
+const TCP_FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum number of TCP connections of the peers open at the same time.
+const MAX_TCP_CONNECTIONS: usize = 256;
+
+/// The maximum number of TCP connections open at the same time from the same IP address.
+const MAX_TCP_CONNECTIONS_PER_IP: usize = 4;
+
+/// The number of frames received over TCP waiting for `recv`. Once it is reached, the
+/// connections are not read anymore until `recv` catches up, slowing their senders down.
+const TCP_QUEUE_SIZE: usize = 1024;
+
+/// The number of connections of the peers waiting to be accepted over TCP.
+const TCP_BACKLOG: i32 = 128;
+
+/// A message received, with the address it was sent from.
+type Frame = (Vec<u8>, SocketAddr);
+
+/// Opens a socket bound to `bind`.
+///
+/// A socket bound to the unspecified IPv6 address (`::`) is dual-stack: it also receives from
+/// the IPv4 peers, whose addresses it sees as IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`).
+/// If IPv6 is not available on the machine, the socket is bound to `0.0.0.0` instead.
+fn bind_socket(bind: SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket>
+{
+    let open = |bind: SocketAddr| -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(bind), kind, Some(protocol))?;
+        if bind.is_ipv6() {
+            socket.set_only_v6(false)?;
+        }
+        if kind == Type::STREAM {
+            socket.set_reuse_address(true)?;
+        }
+        socket.bind(&bind.into())?;
+        Ok(socket)
+    };
+
+    match open(bind) {
+        Err(e) if bind.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) && e.kind() != io::ErrorKind::AddrInUse => {
+            open(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), bind.port()))
+        },
+        result => result,
+    }
+}
+
+/// Opens a UDP socket bound to `bind`, dual-stack for `::` (see `bind_socket`).
+pub fn bind_udp(bind: SocketAddr) -> io::Result<UdpSocket>
+{
+    Ok(bind_socket(bind, Type::DGRAM, Protocol::UDP)?.into())
+}
+
+/// Starts listening for TCP connections on `bind`, dual-stack for `::` (see `bind_socket`).
+pub fn bind_tcp(bind: SocketAddr) -> io::Result<TcpListener>
+{
+    let socket = bind_socket(bind, Type::STREAM, Protocol::TCP)?;
+    socket.listen(TCP_BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// Returns `addr` with its IPv4-mapped IPv6 address, as seen by a dual-stack socket, turned
+/// back into an IPv4 address, so that the peers are known by the same address whatever the
+/// socket they are heard from.
+pub fn canonical(addr: SocketAddr) -> SocketAddr
+{
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// How the messages are exchanged with the peers.
+///
+/// The messages are datagrams, sealed and signed by the agents (see `channel`), so the
+/// transport only has to carry them: it is not trusted with their confidentiality nor
+/// their authenticity.
+pub trait Transport: Send + Sync {
+    /// Sends a datagram to the peer listening on `addr`.
+    fn send(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<()>;
+
+    /// Waits for the next datagram, copied in `buf`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok((usize, SocketAddr))` - The size of the datagram, and the address it was sent from.
+    /// * `Err(io::Error)` - An error if the datagram could not be received.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Returns the address the transport listens on.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// The transports the agents can be configured with.
+///
+/// All the agents of a cluster must use the same one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// Each message is a UDP datagram.
+    #[default]
+    Udp,
+    /// Each message is a frame on a TCP connection, for the networks that block UDP.
+    Tcp,
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self {
+            TransportKind::Udp => write!(f, "udp"),
+            TransportKind::Tcp => write!(f, "tcp"),
+        }
+    }
+}
+
+/// Opens the transport of the given kind, listening on `bind`.
+///
+/// # Arguments
+///
+/// * `kind` - The kind of transport.
+/// * `bind` - The address the transport listens on.
+pub fn bind(kind: TransportKind, bind: SocketAddr) -> io::Result<Arc<dyn Transport>>
+{
+    Ok(match kind {
+        TransportKind::Udp => Arc::new(UdpTransport::bind(bind)?),
+        TransportKind::Tcp => Arc::new(TcpTransport::bind(bind)?),
+    })
+}
+
+/// The transport the messages to the peers are sent with, set once at startup.
+static TRANSPORT: OnceCell<Arc<dyn Transport>> = OnceCell::new();
+
+/// Sets the transport the messages to the peers are sent with.
+pub fn set_transport(transport: Arc<dyn Transport>)
+{
+    TRANSPORT.set(transport).ok();
+}
+
+/// Returns the transport the messages to the peers are sent with.
+///
+/// If none was set (e.g. in the tests, or in the commands of the CLI), the messages are sent
+/// over UDP from an ephemeral port, to IPv4 and IPv6 peers alike.
+pub fn transport() -> Arc<dyn Transport>
+{
+    TRANSPORT
+        .get_or_init(|| Arc::new(UdpTransport::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))).expect("Failed to open a UDP socket")))
+        .clone()
+}
+
+/// Exchanges the messages as UDP datagrams, sent from the socket the agent listens on.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    /// Whether the socket is an IPv6 one, sending to the IPv4 peers at their IPv4-mapped address.
+    ipv6: bool,
+}
+
+impl UdpTransport {
+    /// Opens a UDP socket listening on `bind`, dual-stack for `::` (see `bind_socket`).
+    pub fn bind(bind: SocketAddr) -> io::Result<Self>
+    {
+        let socket = bind_udp(bind)?;
+        let ipv6 = socket.local_addr()?.is_ipv6();
+        Ok(UdpTransport { socket, ipv6 })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<()>
+    {
+        let addr = match addr.ip() {
+            IpAddr::V4(ip) if self.ipv6 => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+            _ => addr,
+        };
+        self.socket.send_to(datagram, addr)?;
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>
+    {
+        let (len, src) = self.socket.recv_from(buf)?;
+        Ok((len, canonical(src)))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr>
+    {
+        self.socket.local_addr()
+    }
+}
+
+/// Exchanges the messages as frames on TCP connections: the size of the message, as a
+/// 4-byte big-endian integer, then the message.
+///
+/// The connections opened to the peers are kept and reused for the next messages. The
+/// connections of the peers are read in their own thread each, their frames being queued
+/// for `recv`. Only `MAX_TCP_CONNECTIONS` connections are accepted at the same time, and
+/// `MAX_TCP_CONNECTIONS_PER_IP` from each address. The ones that send no frame within
+/// `TCP_FIRST_FRAME_TIMEOUT`, then within `TCP_IDLE_TIMEOUT`, are closed.
+pub struct TcpTransport {
+    /// The address the transport listens on.
+    addr: SocketAddr,
+    /// The frames received, with the address of the peer that sent them.
+    frames: Mutex<Receiver<Frame>>,
+    /// The connections opened to the peers, by the address they listen on. Each one has its
+    /// own lock, so that a slow peer only holds up the messages sent to it.
+    connections: Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>,
+}
+
+impl TcpTransport {
+    /// Starts listening for the connections of the peers on `bind`, dual-stack for `::` (see `bind_socket`).
+    pub fn bind(bind: SocketAddr) -> io::Result<Self>
+    {
+        let listener = bind_tcp(bind)?;
+        let addr = listener.local_addr()?;
+        let (sender, frames) = mpsc::sync_channel(TCP_QUEUE_SIZE);
+        // The connections open, by the address of the peer.
+        let connections: Arc<Mutex<HashMap<IpAddr, usize>>> = Arc::new(Mutex::new(HashMap::new()));
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let Ok(ip) = stream.peer_addr().map(|addr| canonical(addr).ip()) else {
+                    continue;
+                };
+                {
+                    let mut open = connections.lock().unwrap();
+                    let from_ip = open.get(&ip).copied().unwrap_or(0);
+                    if open.values().sum::<usize>() >= MAX_TCP_CONNECTIONS || from_ip >= MAX_TCP_CONNECTIONS_PER_IP {
+                        continue;
+                    }
+                    if stream.set_read_timeout(Some(TCP_FIRST_FRAME_TIMEOUT)).is_err() {
+                        continue;
+                    }
+                    open.insert(ip, from_ip + 1);
+                }
+
+                let (sender, connections) = (sender.clone(), connections.clone());
+                thread::spawn(move || {
+                    read_frames(stream, sender);
+                    let mut open = connections.lock().unwrap();
+                    if let Some(count) = open.get_mut(&ip) {
+                        *count -= 1;
+                        if *count == 0 {
+                            open.remove(&ip);
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(TcpTransport { addr, frames: Mutex::new(frames), connections: Mutex::new(HashMap::new()) })
+    }
+
+    /// Writes a frame on the connection kept to `addr`, if any.
+    ///
+    /// # Returns
+    ///
+    /// * `true` if the frame was written, `false` if there is no connection to `addr` or
+    ///   it was closed since, in which case it is forgotten.
+    fn write_kept(&self, frame: &[u8], addr: SocketAddr) -> bool
+    {
+        let Some(stream) = self.connections.lock().unwrap().get(&addr).cloned() else {
+            return false;
+        };
+        if stream.lock().unwrap().write_all(frame).is_ok() {
+            return true;
+        }
+
+        let mut connections = self.connections.lock().unwrap();
+        if connections.get(&addr).is_some_and(|kept| Arc::ptr_eq(kept, &stream)) {
+            connections.remove(&addr);
+        }
+        false
+    }
+
+    /// Opens a connection to `addr`, writes a frame on it and keeps it for the next ones.
+    fn connect(&self, frame: &[u8], addr: SocketAddr) -> io::Result<()>
+    {
+        let mut stream = TcpStream::connect_timeout(&addr, TCP_TIMEOUT)?;
+        stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+        stream.write_all(frame)?;
+
+        self.connections.lock().unwrap().insert(addr, Arc::new(Mutex::new(stream)));
+        Ok(())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<()>
+    {
+        let len = u32::try_from(datagram.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+        let mut frame = len.to_be_bytes().to_vec();
+        frame.extend_from_slice(datagram);
+
+        // A connection kept from an earlier message may have been closed by the peer since,
+        // and is then opened again. A peer that cannot be connected to is not retried.
+        if self.write_kept(&frame, addr) {
+            return Ok(());
+        }
+        self.connect(&frame, addr)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>
+    {
+        let (frame, src) = self.frames.lock().unwrap().recv().map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        buf.get_mut(..frame.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "message too large"))?
+            .copy_from_slice(&frame);
+        Ok((frame.len(), src))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr>
+    {
+        Ok(self.addr)
+    }
+}
+
+/// Reads the frames of a connection until it is closed, or sends a frame too large.
+///
+/// Once the first frame is read, the connection may stay idle up to `TCP_IDLE_TIMEOUT`.
+fn read_frames(mut stream: TcpStream, frames: SyncSender<Frame>)
+{
+    let Ok(src) = stream.peer_addr().map(canonical) else {
+        return;
+    };
+
+    let mut len = [0; 4];
+    while stream.read_exact(&mut len).is_ok() {
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_DATAGRAM {
+            return;
+        }
+        let mut frame = vec![0; len];
+        if stream.read_exact(&mut frame).is_err() || frames.send((frame, src)).is_err() {
+            return;
+        }
+        if stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT)).is_err() {
+            return;
+        }
+    }
+}
+
+/// A network of in-memory transports, delivering the messages between them without sockets.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    /// The inboxes of the transports, by the address they listen on.
+    inboxes: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Frame>>>>,
+}
+
+#[cfg(test)]
+impl MemoryNetwork {
+    /// Adds a transport listening on `addr` to the network.
+    pub fn bind(&self, addr: SocketAddr) -> MemoryTransport
+    {
+        let (sender, inbox) = mpsc::channel();
+        self.inboxes.lock().unwrap().insert(addr, sender);
+        MemoryTransport { addr, network: self.clone(), inbox: Mutex::new(inbox) }
+    }
+}
+
+/// A transport of a `MemoryNetwork`.
+///
+/// Like UDP, the messages sent to an address nobody listens on are lost without error.
+#[cfg(test)]
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    inbox: Mutex<Receiver<Frame>>,
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+    fn send(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<()>
+    {
+        if let Some(inbox) = self.network.inboxes.lock().unwrap().get(&addr) {
+            inbox.send((datagram.to_vec(), self.addr)).ok();
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>
+    {
+        let (datagram, src) = self.inbox.lock().unwrap().recv().map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        buf[..datagram.len()].copy_from_slice(&datagram);
+        Ok((datagram.len(), src))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr>
+    {
+        Ok(self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(a: &dyn Transport, b: &dyn Transport, b_addr: SocketAddr) -> Frame {
+        a.send(b"first", b_addr).unwrap();
+        a.send(&vec![7; 40000], b_addr).unwrap();
+
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let (len, _) = b.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"first");
+        let (len, src) = b.recv(&mut buf).unwrap();
+        (buf[..len].to_vec(), src)
+    }
+
+    #[test]
+    fn test_udp_transport() {
+        let a = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let b = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let (datagram, src) = exchange(&a, &b, b.local_addr().unwrap());
+        assert_eq!(datagram, vec![7; 40000]);
+        assert_eq!(src, a.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_tcp_transport() {
+        let a = TcpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let b = TcpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let (frame, src) = exchange(&a, &b, b.local_addr().unwrap());
+        assert_eq!(frame, vec![7; 40000]);
+        assert_eq!(src.ip(), a.local_addr().unwrap().ip());
+
+        // The connection is kept for the next frames.
+        let (_, second) = exchange(&a, &b, b.local_addr().unwrap());
+        assert_eq!(second, src);
+        assert_eq!(a.connections.lock().unwrap().len(), 1);
+
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        assert!(a.send(b"hello", closed).is_err());
+        assert!(!a.connections.lock().unwrap().contains_key(&closed));
+    }
+
+    #[test]
+    fn test_tcp_connections_per_ip() {
+        let transport = TcpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = transport.local_addr().unwrap();
+
+        let open: Vec<TcpStream> = (0..MAX_TCP_CONNECTIONS_PER_IP).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let mut refused = TcpStream::connect(addr).unwrap();
+        refused.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(refused.read(&mut [0; 1]).unwrap(), 0);
+        drop(open);
+    }
+
+    #[test]
+    fn test_dual_stack_transports() {
+        // The IPv4 peers of a dual-stack transport are known by their IPv4 address, both ways.
+        let a = UdpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let b = UdpTransport::bind("[::]:0".parse().unwrap()).unwrap();
+        let b_addr = SocketAddr::from(([127, 0, 0, 1], b.local_addr().unwrap().port()));
+
+        let (datagram, src) = exchange(&a, &b, b_addr);
+        assert_eq!(datagram, vec![7; 40000]);
+        assert_eq!(src, a.local_addr().unwrap());
+        let (_, src) = exchange(&b, &a, src);
+        assert_eq!(src, b_addr);
+
+        let a = TcpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let b = TcpTransport::bind("[::]:0".parse().unwrap()).unwrap();
+        let (_, src) = exchange(&a, &b, SocketAddr::from(([127, 0, 0, 1], b.local_addr().unwrap().port())));
+        assert_eq!(src.ip(), a.local_addr().unwrap().ip());
+    }
+
+    #[test]
+    fn test_memory_transport() {
+        let network = MemoryNetwork::default();
+        let a_addr: SocketAddr = "10.0.0.1:21335".parse().unwrap();
+        let b_addr: SocketAddr = "10.0.0.2:21335".parse().unwrap();
+        let a = network.bind(a_addr);
+        let b = network.bind(b_addr);
+
+        let (datagram, src) = exchange(&a, &b, b_addr);
+        assert_eq!(datagram, vec![7; 40000]);
+        assert_eq!(src, a_addr);
+        assert!(a.send(b"lost", "10.0.0.3:21335".parse().unwrap()).is_ok());
+    }
+}
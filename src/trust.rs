@@ -0,0 +1,336 @@
+use serde::{Serialize, Deserialize};
+use std::{fs::{self, OpenOptions}, io::{self, Write}, net::IpAddr, os::unix::fs::OpenOptionsExt, sync::{Arc, Mutex}};
+use once_cell::sync::Lazy;
+
+use crate::events::{record_event, Event, EventKind};
+use crate::identity::parse_node_key;
+use crate::state;
+use crate::utils::timestamp;
+
+/// The default path of the trust store, relative to the working directory.
+pub const DEFAULT_TRUST_STORE: &str = "./trust.json";
+
+/// Who pinned the nodes trusted on first use, until an operator confirms them.
+pub const FIRST_USE: &str = "trust on first use";
+
+/// A node pinned in the trust store: the key its messages must be signed with.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TrustedNode {
+    /// The public key of the node, in hexadecimal.
+    pub key: String,
+    /// The IP address the node was enrolled from.
+    pub ip: IpAddr,
+    /// The hostname of the node, if known.
+    #[serde(default)]
+    pub hostname: String,
+    /// The time the node was trusted, in seconds since the UNIX epoch.
+    pub added: u64,
+    /// How the node was trusted (an operator, the enrolment key, trust on first use).
+    pub added_by: String,
+    /// The time the node was revoked, if it was. Revoked nodes are never trusted again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked: Option<u64>,
+}
+
+/// The nodes of the trust store, trusted or revoked, as saved in its file.
+pub static TRUST_STORE: Lazy<Arc<Mutex<Vec<TrustedNode>>>> = Lazy::new(|| {Arc::new(Mutex::new(Vec::new()))});
+
+/// The file the trust store is saved to, once opened with `open_trust_store`.
+static TRUST_STORE_FILE: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// Reads the nodes stored in a trust store file.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the trust store.
+///
+/// # Returns
+///
+/// * `Ok(Vec<TrustedNode>)` - The nodes of the file, none if it doesn't exist yet.
+/// * `Err(io::Error)` - An error if the file cannot be read or parsed.
+pub fn read_trust_store(filename: &str) -> io::Result<Vec<TrustedNode>>
+{
+    match fs::read_to_string(filename) {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Opens the trust store, loading the nodes it already holds.
+///
+/// Every change made afterwards is saved to the file.
+///
+/// # Arguments
+///
+/// * `filename` - The path to the trust store.
+///
+/// # Returns
+///
+/// * `Ok(usize)` - The number of nodes loaded, `0` if the file doesn't exist yet.
+/// * `Err(io::Error)` - An error if the file cannot be read or parsed.
+pub fn open_trust_store(filename: &str) -> io::Result<usize>
+{
+    let nodes = read_trust_store(filename)?;
+    let count = nodes.len();
+
+    *TRUST_STORE.lock().unwrap() = nodes;
+    *TRUST_STORE_FILE.lock().unwrap() = Some(filename.to_string());
+
+    Ok(count)
+}
+
+/// Saves the trust store to its file, if one is open.
+///
+/// The file is written next to the previous one and renamed over it, so that a crash never
+/// leaves a truncated trust store.
+fn save(nodes: &[TrustedNode])
+{
+    let Some(filename) = TRUST_STORE_FILE.lock().unwrap().clone() else {
+        return;
+    };
+
+    let tmp = format!("{filename}.tmp");
+    let result = serde_json::to_string_pretty(nodes)
+        .map_err(io::Error::from)
+        .and_then(|json| {
+            let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+            writeln!(file, "{json}")
+        })
+        .and_then(|_| fs::rename(&tmp, &filename));
+
+    if let Err(e) = result {
+        println!("Failed to save the trust store {filename}: {e}");
+    }
+}
+
+/// Pins a node in the trust store.
+///
+/// Pinning again a node trusted on first use confirms it: the actor replaces `FIRST_USE`.
+///
+/// # Arguments
+///
+/// * `key` - The public key of the node, in hexadecimal.
+/// * `ip` - The IP address of the node.
+/// * `hostname` - The hostname of the node, if known.
+/// * `actor` - Who or what trusted the node, for the event log.
+///
+/// # Returns
+///
+/// * `Ok(bool)` - `true` if the node was added or confirmed, `false` if it was already trusted.
+/// * `Err(String)` - Why the node cannot be trusted (invalid or revoked key).
+pub fn trust_node(key: &str, ip: IpAddr, hostname: &str, actor: &str) -> Result<bool, String>
+{
+    if parse_node_key(key).is_none() {
+        return Err(format!("\"{key}\" is not a node key"));
+    }
+
+    let mut nodes = TRUST_STORE.lock().unwrap();
+    if let Some(node) = nodes.iter_mut().find(|n| n.key == key) {
+        if node.revoked.is_some() {
+            return Err(format!("node {} was revoked", short_key(key)));
+        }
+        if node.added_by != FIRST_USE || actor == FIRST_USE {
+            return Ok(false);
+        }
+        node.added_by = actor.to_string();
+        let ip = node.ip;
+        save(&nodes);
+
+        record_event(
+            Event::new(EventKind::Trust, Some(ip), actor, format!("Confirmed node {} at {ip}, trusted on first use", short_key(key)))
+                .with_detail("key", key),
+        );
+        return Ok(true);
+    }
+
+    nodes.push(TrustedNode {
+        key: key.to_string(),
+        ip,
+        hostname: hostname.to_string(),
+        added: timestamp(),
+        added_by: actor.to_string(),
+        revoked: None,
+    });
+    save(&nodes);
+
+    let event = match actor {
+        FIRST_USE => Event::new(
+            EventKind::Trust,
+            Some(ip),
+            actor,
+            format!("Trusted node {} at {ip} on first use, to be confirmed with `wormsec-poc trust {ip} {key}`", short_key(key)),
+        )
+        .with_detail("confirmed", false),
+        _ => Event::new(EventKind::Trust, Some(ip), actor, format!("Trusted node {} at {ip}", short_key(key))),
+    };
+    record_event(event.with_detail("key", key));
+    Ok(true)
+}
+
+/// Revokes the nodes of the trust store with a given key or IP address.
+///
+/// Their messages are ignored from then on, and their keys cannot be trusted again.
+///
+/// # Arguments
+///
+/// * `key_or_ip` - The public key of the node, or its IP address.
+/// * `actor` - Who revoked the nodes, for the event log.
+///
+/// # Returns
+///
+/// * `Vec<TrustedNode>` - The nodes revoked.
+pub fn revoke_node(key_or_ip: &str, actor: &str) -> Vec<TrustedNode>
+{
+    let mut nodes = TRUST_STORE.lock().unwrap();
+    let now = timestamp();
+    let mut revoked = Vec::new();
+
+    for node in nodes.iter_mut().filter(|n| n.revoked.is_none() && (n.key == key_or_ip || n.ip.to_string() == key_or_ip)) {
+        node.revoked = Some(now);
+        revoked.push(node.clone());
+    }
+    if revoked.is_empty() {
+        return revoked;
+    }
+    save(&nodes);
+
+    for node in &revoked {
+        record_event(
+            Event::new(EventKind::Trust, Some(node.ip), actor, format!("Revoked node {} at {}", short_key(&node.key), node.ip))
+                .with_detail("key", &node.key),
+        );
+    }
+    revoked
+}
+
+/// Checks whether a node key is trusted: pinned in the trust store and not revoked.
+pub fn is_trusted_key(key: &str) -> bool
+{
+    let nodes = TRUST_STORE.lock().unwrap();
+    nodes.iter().any(|n| n.key == key && n.revoked.is_none())
+}
+
+/// Checks whether a node is, or was, pinned for an IP address.
+///
+/// Trust on first use only applies to the addresses without any node, so that a revoked
+/// node cannot come back with a new key.
+pub fn has_node_for_ip(ip: IpAddr) -> bool
+{
+    let nodes = TRUST_STORE.lock().unwrap();
+    nodes.iter().any(|n| n.ip == ip)
+}
+
+/// Checks whether the node pinned for an IP address was revoked.
+pub fn is_revoked_ip(ip: IpAddr) -> bool
+{
+    let nodes = TRUST_STORE.lock().unwrap();
+    nodes.iter().any(|n| n.ip == ip && n.revoked.is_some()) && !nodes.iter().any(|n| n.ip == ip && n.revoked.is_none())
+}
+
+/// Returns the key of the node trusted for an IP address, the latest one if there are several.
+///
+/// The messages sent to the machine are encrypted to this key.
+pub fn node_key_for_ip(ip: IpAddr) -> Option<String>
+{
+    let nodes = TRUST_STORE.lock().unwrap();
+    nodes.iter().rev().find(|n| n.ip == ip && n.revoked.is_none()).map(|n| n.key.clone())
+}
+
+/// Returns the IP address a trusted node was pinned for.
+pub fn node_ip(key: &str) -> Option<IpAddr>
+{
+    let nodes = TRUST_STORE.lock().unwrap();
+    nodes.iter().find(|n| n.key == key && n.revoked.is_none()).map(|n| n.ip)
+}
+
+/// Pins the key of a node the first time a machine of the inventory sends a message.
+///
+/// Only the trusted machines (see `state::is_trusted`) without any node, trusted or revoked,
+/// get their key pinned this way, and an operator must confirm it (see `trust_node`).
+///
+/// # Arguments
+///
+/// * `key` - The key of the node that signed the message.
+/// * `ip` - The IP address the message came from.
+///
+/// # Returns
+///
+/// * `true` if the key was pinned.
+pub fn trust_on_first_use(key: &str, ip: IpAddr) -> bool
+{
+    if !state::is_trusted(&ip.to_string()) || has_node_for_ip(ip) {
+        return false;
+    }
+    let hostname = state::find_machine(&ip.to_string()).and_then(|m| m.hostname).unwrap_or_default();
+    trust_node(key, ip, &hostname, FIRST_USE) == Ok(true)
+}
+
+/// Returns the nodes of the trust store.
+pub fn get_trusted_nodes() -> Vec<TrustedNode>
+{
+    TRUST_STORE.lock().unwrap().clone()
+}
+
+/// Shortens a node key for display: its first 16 hexadecimal digits.
+pub fn short_key(key: &str) -> &str
+{
+    key.get(..16).unwrap_or(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+
+    #[test]
+    fn test_trust_and_revoke() {
+        let key = Identity::generate().unwrap().node_key();
+        let ip: IpAddr = "10.60.0.1".parse().unwrap();
+
+        assert!(trust_node("nope", ip, "", "test").is_err());
+        assert_eq!(trust_node(&key, ip, "node-1", "test"), Ok(true));
+        assert_eq!(trust_node(&key, ip, "node-1", "test"), Ok(false));
+        assert!(is_trusted_key(&key));
+        assert!(has_node_for_ip(ip));
+
+        let revoked = revoke_node("10.60.0.1", "test");
+        assert_eq!(revoked.len(), 1);
+        assert!(!is_trusted_key(&key));
+        assert!(is_revoked_ip(ip));
+        assert!(trust_node(&key, ip, "node-1", "test").is_err());
+        assert!(revoke_node(&key, "test").is_empty());
+    }
+
+    #[test]
+    fn test_confirm_first_use() {
+        let key = Identity::generate().unwrap().node_key();
+        let ip: IpAddr = "10.60.0.3".parse().unwrap();
+
+        assert_eq!(trust_node(&key, ip, "", FIRST_USE), Ok(true));
+        assert_eq!(trust_node(&key, ip, "", FIRST_USE), Ok(false));
+        assert_eq!(trust_node(&key, ip, "", "operator"), Ok(true));
+        assert_eq!(trust_node(&key, ip, "", "operator"), Ok(false));
+        assert!(get_trusted_nodes().iter().any(|n| n.key == key && n.added_by == "operator"));
+    }
+
+    #[test]
+    fn test_read_trust_store() {
+        let path = std::env::temp_dir().join("test_wormsec_trust.json");
+        let node = TrustedNode {
+            key: Identity::generate().unwrap().node_key(),
+            ip: "10.60.0.2".parse().unwrap(),
+            hostname: "node-2".to_string(),
+            added: 1000,
+            added_by: "test".to_string(),
+            revoked: None,
+        };
+        fs::write(&path, serde_json::to_string(&vec![node.clone()]).unwrap()).unwrap();
+
+        assert_eq!(read_trust_store(path.to_str().unwrap()).unwrap(), vec![node]);
+
+        fs::write(&path, "not json").unwrap();
+        assert!(read_trust_store(path.to_str().unwrap()).is_err());
+        assert!(read_trust_store("/nonexistent/trust.json").unwrap().is_empty());
+    }
+}
@@ -1,4 +1,7 @@
-use inotify::{Inotify, WatchMask};
+use inotify::{EventMask, Inotify, WatchMask};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -10,9 +13,15 @@ use std::time::Duration;
 /// and no return value (`()`), which is triggered when a watched event occurs.
 pub type Callback = Arc<Mutex<Box<dyn Fn() + Send + 'static>>>;
 
+/// A type alias for a callback function that is executed when an event is detected on a watched path.
+///
+/// The callback receives the path the event happened on (for a watched directory, the path of
+/// the entry inside it) and the inotify event mask describing what happened.
+pub type PathCallback = Arc<Mutex<Box<dyn Fn(&Path, EventMask) + Send + 'static>>>;
+
 /// Starts a file watcher on the specified log file and invokes the callback function when an event is detected.
 ///
-/// This function uses the `inotify` crate to monitor the `/var/log/auth.log` file for specific events such as:
+/// This function uses the `inotify` crate to monitor the given file (`/var/log/auth.log` by default) for specific events such as:
 /// `ACCESS`, `OPEN`, and `ATTRIB` (modification of file attributes). When such an event is detected,
 /// the provided callback function is called.
 ///
@@ -20,28 +29,131 @@ pub type Callback = Arc<Mutex<Box<dyn Fn() + Send + 'static>>>;
 ///
 /// # Arguments
 ///
+/// * `path` - The path of the file to watch.
 /// * `callback` - A callback function wrapped in an `Arc<Mutex<Box<dyn Fn() + Send + 'static>>>`. This function
 ///   will be executed when a file event is detected on the watched file.
-pub fn start_watcher(callback: Callback)
+pub fn start_watcher(path: String, callback: Callback)
 {
     thread::spawn(move || {
         let mut inotify = Inotify::init().expect("Failed to initialize inotify");
         inotify
             .watches()
-            .add("/var/log/auth.log", WatchMask::ACCESS | WatchMask::OPEN | WatchMask::ATTRIB)
-            .expect("Failed to watch /var/log/auth.log");
+            .add(&path, WatchMask::ACCESS | WatchMask::OPEN | WatchMask::ATTRIB)
+            .unwrap_or_else(|e| panic!("Failed to watch {path}: {e}"));
 
         let mut buffer = [0; 1024];
 
         loop {
-            match inotify.read_events(&mut buffer) {
-                Ok(_) => {
-                    let cb = callback.lock().unwrap();
-                    cb();
+            if inotify.read_events(&mut buffer).is_ok() {
+                let cb = callback.lock().unwrap();
+                cb();
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+}
+
+/// Collects a directory and its subdirectories, recursively, without following symbolic links.
+pub fn collect_dirs(path: &Path, dirs: &mut Vec<PathBuf>)
+{
+    if !fs::symlink_metadata(path).map(|m| m.is_dir()).unwrap_or(false) {
+        return;
+    }
+
+    dirs.push(path.to_path_buf());
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            collect_dirs(&entry.path(), dirs);
+        }
+    }
+}
+
+/// Starts a file watcher on a list of paths and invokes the callback for every event.
+///
+/// Unlike `start_watcher`, which only reports that something happened on a single file,
+/// this watcher reports each event with the path it happened on and its mask, so that callers
+/// can tell what was touched and how. Paths that can't be watched (e.g. missing files) are
+/// reported and skipped.
+///
+/// A path deleted or moved away loses its watch: once the callback is run with the
+/// `IGNORED` event that ends it, the path is watched again if it exists (e.g. because the
+/// callback recreated it, or an editor replaced the file).
+///
+/// When `recursive` is set, the directories created or moved into a watched directory are
+/// watched too, with their subdirectories, before the callback is run with their event.
+///
+/// The watcher runs in a separate thread to avoid blocking the main execution.
+///
+/// # Arguments
+///
+/// * `paths` - The files and directories to watch.
+/// * `mask` - The inotify events to watch for on each path.
+/// * `recursive` - Whether the new subdirectories of the watched directories are watched.
+/// * `callback` - A callback function wrapped in an `Arc<Mutex<Box<dyn Fn(&Path, EventMask) + Send + 'static>>>`.
+///   This function will be executed for every event on one of the watched paths.
+pub fn start_path_watcher(paths: Vec<PathBuf>, mask: WatchMask, recursive: bool, callback: PathCallback)
+{
+    thread::spawn(move || {
+        let mut inotify = Inotify::init().expect("Failed to initialize inotify");
+        let mut watched = HashMap::new();
+
+        for path in paths {
+            match inotify.watches().add(&path, mask) {
+                Ok(wd) => {
+                    watched.insert(wd, path);
                 },
-                Err(_) => {}
+                Err(e) => println!("Failed to watch {}: {e}", path.display()),
+            }
+        }
+
+        let mut buffer = [0; 4096];
+
+        loop {
+            if let Ok(events) = inotify.read_events(&mut buffer) {
+                for event in events {
+                    let Some(path) = watched.get(&event.wd).cloned() else {
+                        continue;
+                    };
+                    let entry = match event.name {
+                        Some(name) => path.join(name),
+                        None => path.clone(),
+                    };
+
+                    if recursive && event.mask.contains(EventMask::ISDIR) && event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                        let mut dirs = Vec::new();
+                        collect_dirs(&entry, &mut dirs);
+                        for dir in dirs {
+                            match inotify.watches().add(&dir, mask) {
+                                Ok(wd) => {
+                                    watched.insert(wd, dir);
+                                },
+                                Err(e) => println!("Failed to watch {}: {e}", dir.display()),
+                            }
+                        }
+                    }
+
+                    let cb = callback.lock().unwrap();
+                    cb(&entry, event.mask);
+                    drop(cb);
+
+                    if event.name.is_some() {
+                        continue;
+                    }
+                    if event.mask.contains(EventMask::MOVE_SELF) {
+                        // The watch follows the file to its new path: drop it, to watch the path again.
+                        inotify.watches().remove(event.wd.clone()).ok();
+                    } else if event.mask.contains(EventMask::IGNORED) {
+                        watched.remove(&event.wd);
+                        match inotify.watches().add(&path, mask) {
+                            Ok(wd) => {
+                                watched.insert(wd, path);
+                            },
+                            Err(e) => println!("Stopped watching {}: {e}", path.display()),
+                        }
+                    }
+                }
             }
             thread::sleep(Duration::from_millis(100));
         }
     });
-}
\ No newline at end of file
+}
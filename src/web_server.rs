@@ -1,33 +1,98 @@
 use axum::{
+    extract::{Query, State},
     routing::get,
     response::Json,
     Router,
 };
+use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use tower_http::services::ServeDir;
 
-use crate::state;
+use crate::{alert, events, flood, identity, incident, state, trust};
+use crate::flood::PeerStats;
+
+/// The state shared by the handlers of the web server.
+#[derive(Debug, Clone, Copy)]
+pub struct AppState {
+    /// The IP address of the machine the agent runs on.
+    pub my_ip: IpAddr,
+    /// The time the agent started, in seconds since the UNIX epoch.
+    pub started: u64,
+}
+
+/// A summary of the state of the agent, returned by the `/api/status` route.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Status {
+    /// The IP address of the machine the agent runs on.
+    pub ip: IpAddr,
+    /// The node key of the agent, which its peers pin in their trust stores.
+    #[serde(default)]
+    pub node: String,
+    /// The time the agent started, in seconds since the UNIX epoch.
+    pub started: u64,
+    /// The number of machines in the inventory.
+    pub machines: usize,
+    /// The number of machines removed from the inventory since the agent started.
+    #[serde(default)]
+    pub retired: usize,
+    /// The number of discovered peers waiting for approval.
+    #[serde(default)]
+    pub pending: usize,
+    /// The number of machines currently isolated.
+    pub isolated: usize,
+    /// The number of alerts kept in memory.
+    pub alerts: usize,
+    /// The number of incidents kept in memory.
+    pub incidents: usize,
+    /// The number of isolation votes of the peers within the quorum window.
+    #[serde(default)]
+    pub votes: usize,
+    /// The counters of the messages received from the peers.
+    #[serde(default)]
+    pub peers: PeerStats,
+}
 
 /// Starts a web server that serves an API and static files.
 ///
-/// This function sets up a web server using the `axum` framework. It defines two routes:
+/// This function sets up a web server using the `axum` framework. It defines the following routes:
 /// - `/api/machines`: A GET endpoint that returns the list of machines in JSON format.
-/// - A fallback service that serves static files from the `./ui/build` directory.
+/// - `/api/alerts`: A GET endpoint that returns the latest alerts in JSON format.
+/// - `/api/incidents`: A GET endpoint that returns the latest incidents in JSON format.
+/// - `/api/events`: A GET endpoint that returns a page of the event log in JSON format.
+/// - `/api/status`: A GET endpoint that returns a summary of the state of the agent.
+/// - `/api/nodes`: A GET endpoint that returns the nodes of the trust store in JSON format.
+/// - `/api/votes`: A GET endpoint that returns the isolation votes of the peers in JSON format.
+/// - `/api/releases`: A GET endpoint that returns the releases broadcast by the agent and their confirmations.
+/// - A fallback service that serves static files from the dashboard build directory (`./ui/build` by default).
+///
+/// The server listens on the given address (all available network interfaces at port `21335`
+/// by default) and will respond to incoming requests according to the defined routes. The
+/// API is read-only, administrative actions go through the control socket (see `control`).
 ///
-/// The server listens on all available network interfaces at port `21335` and will respond
-/// to incoming requests according to the defined routes.
+/// # Arguments
+///
+/// * `addr` - The address to listen on.
+/// * `ui_path` - The directory holding the build of the dashboard.
+/// * `app_state` - The state shared by the handlers.
 ///
 /// # Example Usage:
 ///
-/// To start the server, simply call `run_web_server()` in an async context.
-pub async fn run_web_server()
+/// To start the server, simply call `run_web_server(addr, ui_path, app_state)` in an async context.
+pub async fn run_web_server(addr: SocketAddr, ui_path: String, app_state: AppState)
 {
     let app = Router::new()
         .route("/api/machines", get(get_machines))
-        .fallback_service(ServeDir::new("./ui/build"));
+        .route("/api/alerts", get(get_alerts))
+        .route("/api/incidents", get(get_incidents))
+        .route("/api/events", get(get_events))
+        .route("/api/status", get(get_status))
+        .route("/api/nodes", get(get_nodes))
+        .route("/api/votes", get(get_votes))
+        .route("/api/releases", get(get_releases))
+        .fallback_service(ServeDir::new(ui_path))
+        .with_state(app_state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 21335));
     println!("Web server running at http://{}", addr);
     let listener = TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, app.into_make_service())
@@ -47,4 +112,131 @@ async fn get_machines() -> Json<Vec<state::Machine>>
 {
     let machines = state::get_machines();
     Json(machines)
-}
\ No newline at end of file
+}
+
+/// The query parameters of the `/api/alerts`, `/api/incidents` and `/api/votes` routes.
+#[derive(Debug, Deserialize)]
+struct TargetQuery {
+    /// If set, only the alerts, incidents or votes about this IP address are returned.
+    ip: Option<IpAddr>,
+}
+
+/// Retrieves the latest alerts and returns them as JSON.
+///
+/// This is the handler for the `/api/alerts` route. Alerts carry the forensic context of
+/// the process responsible for them when one could be identified.
+///
+/// # Returns
+///
+/// A `Json<Vec<alert::Alert>>` containing the alerts, oldest first.
+async fn get_alerts(Query(query): Query<TargetQuery>) -> Json<Vec<alert::Alert>>
+{
+    Json(alert::get_alerts(query.ip))
+}
+
+
+/// Retrieves the latest incidents and returns them as JSON.
+///
+/// This is the handler for the `/api/incidents` route. Each incident groups the alerts
+/// about one machine, with their first and last times, count and highest severity.
+///
+/// # Returns
+///
+/// A `Json<Vec<incident::Incident>>` containing the incidents, oldest first.
+async fn get_incidents(Query(query): Query<TargetQuery>) -> Json<Vec<incident::Incident>>
+{
+    Json(incident::get_incidents(query.ip))
+}
+
+/// Retrieves the isolation votes of the peers and returns them as JSON.
+///
+/// This is the handler for the `/api/votes` route. The votes are kept for the quorum window.
+///
+/// # Returns
+///
+/// A `Json<Vec<state::IsolationVote>>` containing the votes, oldest first.
+async fn get_votes(Query(query): Query<TargetQuery>) -> Json<Vec<state::IsolationVote>>
+{
+    Json(state::get_votes(query.ip))
+}
+
+/// Retrieves the latest releases broadcast by the agent and returns them as JSON.
+///
+/// This is the handler for the `/api/releases` route. Each release lists the peers it was
+/// sent to and the ones that confirmed it.
+///
+/// # Returns
+///
+/// A `Json<Vec<state::Release>>` containing the releases, oldest first.
+async fn get_releases() -> Json<Vec<state::Release>>
+{
+    Json(state::get_releases())
+}
+
+/// Retrieves a page of the event log and returns it as JSON.
+///
+/// This is the handler for the `/api/events` route. The events can be filtered by machine
+/// (`machine`), kind (`type`) and time range (`since`, `until`), and paginated with
+/// `offset` and `limit`.
+///
+/// # Returns
+///
+/// A `Json<events::EventPage>` containing the matching events, oldest first.
+async fn get_events(Query(filter): Query<events::EventFilter>) -> Json<events::EventPage>
+{
+    Json(events::query_events(&filter))
+}
+
+/// Builds a summary of the state of the agent.
+///
+/// # Arguments
+///
+/// * `my_ip` - The IP address of the machine the agent runs on.
+/// * `started` - The time the agent started, in seconds since the UNIX epoch.
+///
+/// # Returns
+///
+/// * `Status` - The counts of machines, alerts and incidents.
+pub fn agent_status(my_ip: IpAddr, started: u64) -> Status
+{
+    let machines = state::get_machines();
+
+    Status {
+        ip: my_ip,
+        node: identity::identity().node_key(),
+        started,
+        machines: machines.iter().filter(|m| !m.retired && !m.pending).count(),
+        retired: machines.iter().filter(|m| m.retired).count(),
+        pending: machines.iter().filter(|m| m.pending).count(),
+        isolated: machines.iter().filter(|m| m.status == "isolated").count(),
+        alerts: alert::get_alerts(None).len(),
+        incidents: incident::get_incidents(None).len(),
+        votes: state::get_votes(None).len(),
+        peers: flood::get_peer_stats(),
+    }
+}
+
+/// Retrieves a summary of the state of the agent and returns it as JSON.
+///
+/// This is the handler for the `/api/status` route.
+///
+/// # Returns
+///
+/// A `Json<Status>` containing the counts of machines, alerts and incidents.
+async fn get_status(State(app_state): State<AppState>) -> Json<Status>
+{
+    Json(agent_status(app_state.my_ip, app_state.started))
+}
+
+/// Retrieves the nodes of the trust store and returns them as JSON.
+///
+/// This is the handler for the `/api/nodes` route. Revoked nodes are included, with the
+/// time they were revoked.
+///
+/// # Returns
+///
+/// A `Json<Vec<trust::TrustedNode>>` containing the nodes of the trust store.
+async fn get_nodes() -> Json<Vec<trust::TrustedNode>>
+{
+    Json(trust::get_trusted_nodes())
+}
@@ -3,7 +3,7 @@ import Header from './components/Header';
 import Visualizer from './components/Visualizer';
 import Information from './components/Information';
 import Footer from './components/Footer';
-import { Machine, Link } from './types';
+import { Machine, Link, Alert, IsolationVote } from './types';
 import './App.css';
 
 interface Position {
@@ -15,6 +15,8 @@ const App: React.FC = () => {
   const [selectedMachine, setSelectedMachine] = useState<Machine | null>(null);
   const [machinePositions, setMachinePositions] = useState<Record<string, Position>>({});
   const [machines, setMachines] = useState<Machine[]>([]);
+  const [alerts, setAlerts] = useState<Alert[]>([]);
+  const [votes, setVotes] = useState<IsolationVote[]>([]);
   const [previousMachines, setPreviousMachines] = useState<Machine[]>([]);
   const [links, setLinks] = useState<Link[]>([]);
   const [isLoading, setIsLoading] = useState(true);
@@ -87,6 +89,16 @@ const App: React.FC = () => {
       const newLinks = generateLinks(data);
       setLinks(newLinks);
       
+      const alertsResponse = await fetch('/api/alerts');
+      if (alertsResponse.ok) {
+        setAlerts(await alertsResponse.json());
+      }
+      
+      const votesResponse = await fetch('/api/votes');
+      if (votesResponse.ok) {
+        setVotes(await votesResponse.json());
+      }
+      
       if (selectedMachine) {
         const updatedSelectedMachine = data.find(m => m.id === selectedMachine.id) || null;
         setSelectedMachine(updatedSelectedMachine);
@@ -157,7 +169,11 @@ const App: React.FC = () => {
       <Header />
       <div className="main-content">
         {selectedMachine && (
-          <Information machine={selectedMachine} />
+          <Information 
+            machine={selectedMachine} 
+            alerts={alerts.filter(alert => alert.target === selectedMachine.ip)}
+            votes={votes.filter(vote => vote.target === selectedMachine.ip)}
+          />
         )}
         <div 
           ref={visualizerRef} 
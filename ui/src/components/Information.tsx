@@ -1,12 +1,14 @@
 import React, { useState, useEffect } from 'react';
-import { Machine } from '../types';
+import { Machine, Alert, IsolationVote } from '../types';
 import './Information.css';
 
 interface InformationProps {
     machine: Machine;
+    alerts: Alert[];
+    votes: IsolationVote[];
 }
 
-const Information: React.FC<InformationProps> = ({ machine }) => {
+const Information: React.FC<InformationProps> = ({ machine, alerts, votes }) => {
     const [previousStatus, setPreviousStatus] = useState<string>(machine.status);
     const [isTransitioning, setIsTransitioning] = useState<boolean>(false);
     
@@ -65,6 +67,18 @@ const Information: React.FC<InformationProps> = ({ machine }) => {
                 <div className="info-label">IP :</div> 
                 <div className="info-value">{machine.ip}</div>
             </div>
+            {machine.addresses.length > 0 && (
+                <div className="info-item">
+                    <div className="info-label">Other IPs :</div> 
+                    <div className="info-value">{machine.addresses.join(', ')}</div>
+                </div>
+            )}
+            {machine.neighbour_addresses.length > 0 && (
+                <div className="info-item">
+                    <div className="info-label">Neighbour IPs :</div> 
+                    <div className="info-value">{machine.neighbour_addresses.join(', ')}</div>
+                </div>
+            )}
             {machine.mac && (
                 <div className="info-item">
                     <div className="info-label">MAC :</div> 
@@ -81,6 +95,56 @@ const Information: React.FC<InformationProps> = ({ machine }) => {
                     {machine.status}
                 </div>
             </div>
+            <div className="info-item">
+                <div className="info-label">Firewall :</div> 
+                <div className={`info-value ${machine.locked ? "status-isolated" : "status-connected"}`}>
+                    {machine.locked ? "locked here" : "not locked here"}
+                </div>
+            </div>
+            {votes.length > 0 && (
+                <div className="info-alerts">
+                    <div className="info-label">Isolation votes :</div>
+                    {votes.map((vote, index) => (
+                        <div key={index} className={`info-alert severity-${vote.severity ?? 'low'}`}>
+                            {new Date(vote.timestamp * 1000).toLocaleString()} {vote.src} (node {vote.node.slice(0, 16)})
+                            {vote.severity && ` [${vote.severity}]`}
+                        </div>
+                    ))}
+                </div>
+            )}
+            {alerts.length > 0 && (
+                <div className="info-alerts">
+                    <div className="info-label">Alerts :</div>
+                    {alerts.slice().reverse().map((alert, index) => (
+                        <details key={index} className={`info-alert severity-${alert.severity}`}>
+                            <summary>
+                                {new Date(alert.timestamp * 1000).toLocaleString()} [{alert.source}] {alert.message}
+                            </summary>
+                            {Object.entries(alert.details).map(([key, value]) => (
+                                <div key={key} className="info-alert-detail">{key} : {value}</div>
+                            ))}
+                            {alert.process && (
+                                <div className="info-alert-process">
+                                    <div>PID : {alert.process.pid}</div>
+                                    <div>Command : {alert.process.cmdline}</div>
+                                    <div>User : {alert.process.user ?? alert.process.uid ?? '?'}</div>
+                                    {alert.process.exe && <div>Executable : {alert.process.exe}</div>}
+                                    {alert.process.exe_sha256 && <div>SHA-256 : {alert.process.exe_sha256}</div>}
+                                    {alert.process.cwd && <div>Directory : {alert.process.cwd}</div>}
+                                    {alert.process.ancestors.length > 0 && (
+                                        <div>
+                                            Ancestors : {alert.process.ancestors.map(p => `${p.name} (${p.pid})`).join(' < ')}
+                                        </div>
+                                    )}
+                                    {alert.process.sockets.map((socket, i) => (
+                                        <div key={i}>Socket : {socket}</div>
+                                    ))}
+                                </div>
+                            )}
+                        </details>
+                    ))}
+                </div>
+            )}
         </div>
     );
 };
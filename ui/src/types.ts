@@ -5,10 +5,68 @@ export interface Machine {
     mac: string;
     lastUpdate: string;
     status: "connected" | "isolated";
+    locked: boolean;
+    hostname: string | null;
+    tags: string[];
+    role: string | null;
+    owner: string | null;
+    retired: boolean;
+    discovered: boolean;
+    pending: boolean;
+    key: string | null;
+    version: { counter: number; node: string } | null;
+    addresses: string[];
+    neighbour_addresses: string[];
 }
 
 export interface Link {
     source: string;
     target: string;
     type: "connected" | "isolated";
-}
\ No newline at end of file
+}
+
+export interface ProcessInfo {
+    pid: number;
+    name: string;
+    cmdline: string;
+}
+
+export interface ProcessContext {
+    pid: number;
+    name: string;
+    cmdline: string;
+    exe: string | null;
+    exe_sha256: string | null;
+    uid: number | null;
+    user: string | null;
+    cwd: string | null;
+    ancestors: ProcessInfo[];
+    sockets: string[];
+}
+
+export interface Alert {
+    source: string;
+    severity: "low" | "medium" | "high" | "critical";
+    target: string;
+    message: string;
+    timestamp: number;
+    process: ProcessContext | null;
+    details: Record<string, string>;
+}
+
+export interface IsolationVote {
+    target: string;
+    node: string;
+    src: string;
+    severity: "low" | "medium" | "high" | "critical" | null;
+    timestamp: number;
+}
+
+export interface Release {
+    id: string;
+    ip: string;
+    actor: string;
+    timestamp: number;
+    sent_to: string[];
+    confirmed_by: string[];
+}